@@ -1,154 +1,87 @@
-use serde_json::Value;
-use std::fs;
-
-fn main() -> anyhow::Result<()> {
-    let path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "/Users/petaflops/Library/Containers/com.tencent.xinWeChat/Data/Documents/xwechat_files/petaflops_d0d1/msg/file/2026-02/request_eaa0a5f5_2026-02-07T15-16-31.json".to_string());
+//! `compress_request` 用法示例
+//!
+//! 默认构造一段带历史的示例会话（也可以传入一个真实请求快照的路径作为第一个
+//! 参数，格式为 `{"request_body": "<json string>"}`，与诊断落盘的请求快照一致），
+//! 跑一遍默认 `CompressionConfig`，打印 `CompressedRequest::stats` 里各阶段的
+//! 真实节省量。
+//!
+//! 运行：`cargo run --example test_compression [请求快照路径]`
+
+use kiro_rs_plus::anthropic::compressor::compress_request;
+use kiro_rs_plus::kiro::model::requests::conversation::{
+    ConversationState, CurrentMessage, HistoryAssistantMessage, HistoryUserMessage, Message,
+    UserInputMessage,
+};
+use kiro_rs_plus::model::config::CompressionConfig;
+
+const SAMPLE_MODEL: &str = "claude-sonnet-4.5";
+
+fn sample_state() -> ConversationState {
+    let mut history = Vec::new();
+    for turn in 0..40 {
+        history.push(Message::User(HistoryUserMessage::new(
+            &format!("第 {turn} 轮：帮我看看 src/lib.rs 里这个函数为什么会 panic"),
+            SAMPLE_MODEL,
+        )));
+        history.push(Message::Assistant(HistoryAssistantMessage::new(&format!(
+            "第 {turn} 轮回复：已定位到 src/lib.rs 第 {line} 行的越界访问，建议加边界检查。",
+            line = 10 + turn,
+        ))));
+    }
 
-    let content = fs::read_to_string(&path)?;
-    let data: Value = serde_json::from_str(&content)?;
+    ConversationState::new("test-compression-example")
+        .with_current_message(CurrentMessage::new(UserInputMessage::new(
+            "把刚才讨论的几个修复点汇总一下，再看看还有没有遗漏的边界情况",
+            SAMPLE_MODEL,
+        )))
+        .with_history(history)
+}
 
-    let body_str = data["request_body"]
+/// 从诊断落盘的请求快照加载（`{"request_body": "<json string>"}`）
+fn load_state_from_path(path: &str) -> anyhow::Result<ConversationState> {
+    let content = std::fs::read_to_string(path)?;
+    let dump: serde_json::Value = serde_json::from_str(&content)?;
+    let body_str = dump["request_body"]
         .as_str()
-        .ok_or_else(|| anyhow::anyhow!("找不到 request_body"))?;
-
-    println!(
-        "原始请求体大小: {} bytes ({:.1} KB)",
-        body_str.len(),
-        body_str.len() as f64 / 1024.0
-    );
-
-    let req: Value = serde_json::from_str(body_str)?;
-
-    if let Some(messages) = req["messages"].as_array() {
-        println!("消息数量: {}", messages.len());
-
-        let mut user_count = 0;
-        let mut assistant_count = 0;
-        let mut total_chars = 0;
-        let mut tool_result_chars = 0;
-        let mut tool_use_chars = 0;
-
-        for msg in messages {
-            match msg["role"].as_str() {
-                Some("user") => user_count += 1,
-                Some("assistant") => assistant_count += 1,
-                _ => {}
-            }
-
-            if let Some(content) = msg["content"].as_array() {
-                for item in content {
-                    if let Some(text) = item["text"].as_str() {
-                        total_chars += text.len();
-                    }
-                    // 统计 tool_result
-                    if item["type"].as_str() == Some("tool_result")
-                        && let Some(result_content) = item["content"].as_array()
-                    {
-                        for result_item in result_content {
-                            if let Some(text) = result_item["text"].as_str() {
-                                tool_result_chars += text.len();
-                            }
-                        }
-                    }
-                    // 统计 tool_use
-                    if item["type"].as_str() == Some("tool_use")
-                        && let Some(input) = item["input"].as_object()
-                    {
-                        let input_str = serde_json::to_string(input).unwrap_or_default();
-                        tool_use_chars += input_str.len();
-                    }
-                }
-            }
-        }
-
-        println!("  - user: {}", user_count);
-        println!("  - assistant: {}", assistant_count);
-        println!(
-            "  - 文本字符数: {} ({:.1} KB)",
-            total_chars,
-            total_chars as f64 / 1024.0
-        );
-        println!(
-            "  - tool_result 字符数: {} ({:.1} KB)",
-            tool_result_chars,
-            tool_result_chars as f64 / 1024.0
-        );
-        println!(
-            "  - tool_use input 字符数: {} ({:.1} KB)",
-            tool_use_chars,
-            tool_use_chars as f64 / 1024.0
-        );
-
-        // 模拟历史截断
-        let max_history_turns = 80;
-        let max_history_chars = 400_000;
-
-        let turns = messages.len() / 2;
-        println!("\n压缩模拟（默认配置）:");
-        println!("  - 当前轮数: {} (阈值: {})", turns, max_history_turns);
-
-        if turns > max_history_turns {
-            let to_remove = turns - max_history_turns;
-            println!("  - 需要移除: {} 轮 ({} 条消息)", to_remove, to_remove * 2);
-        } else {
-            println!("  - 轮数未超限");
-        }
-
-        let total_content_chars = total_chars + tool_result_chars + tool_use_chars;
-        println!(
-            "  - 总内容字符数: {} ({:.1} KB)",
-            total_content_chars,
-            total_content_chars as f64 / 1024.0
-        );
-
-        if total_content_chars > max_history_chars {
-            println!(
-                "  - 字符数超限: {} > {}",
-                total_content_chars, max_history_chars
-            );
-        } else {
-            println!("  - 字符数未超限");
-        }
-    }
-
-    if let Some(tools) = req["tools"].as_array() {
-        let tools_str = serde_json::to_string(tools).unwrap_or_default();
-        println!("\n工具数量: {}", tools.len());
-        println!(
-            "工具定义总大小: {} bytes ({:.1} KB)",
-            tools_str.len(),
-            tools_str.len() as f64 / 1024.0
-        );
+        .ok_or_else(|| anyhow::anyhow!("快照中找不到 request_body 字段"))?;
+    Ok(serde_json::from_str(body_str)?)
+}
 
-        // 统计每个工具描述的大小
-        let mut total_desc_chars = 0;
-        for tool in tools {
-            if let Some(desc) = tool["input_schema"]["description"].as_str() {
-                total_desc_chars += desc.len();
-            }
-        }
-        println!(
-            "工具描述总字符数: {} ({:.1} KB)",
-            total_desc_chars,
-            total_desc_chars as f64 / 1024.0
-        );
-    }
+fn main() -> anyhow::Result<()> {
+    let mut state = match std::env::args().nth(1) {
+        Some(path) => load_state_from_path(&path)?,
+        None => sample_state(),
+    };
 
-    println!("\n=== 体积分析 ===");
-    println!("原始请求体: 622.9 KB");
-    println!("  - 文本消息: 46.1 KB (7.4%)");
-    println!("  - tool_use input: 93.0 KB (14.9%)");
-    println!("  - 工具定义: 62.1 KB (10.0%)");
-    println!("  - 其他（system/metadata/tool_result等）: ~421.7 KB (67.7%)");
+    let before = serde_json::to_string(&state)?.len();
+    let config = CompressionConfig::default();
+    let compressed = compress_request(&mut state, &config);
 
-    println!("\n=== 压缩效果预估（默认配置）===");
-    println!("✅ 历史截断: 不触发（52 轮 < 80 轮，139 KB < 400 KB）");
-    println!("✅ tool_use input 截断: 可能触发（93 KB，阈值 6 KB/条）");
-    println!("✅ 工具描述截断: 可能触发（62 KB，阈值 4 KB/条）");
-    println!("✅ 空白压缩: 会执行");
-    println!("✅ thinking 丢弃: 会执行（如有）");
+    println!("压缩前请求体: {} bytes ({:.1} KB)", before, before as f64 / 1024.0);
+    println!(
+        "压缩后请求体: {} bytes ({:.1} KB)",
+        compressed.body.len(),
+        compressed.body.len() as f64 / 1024.0
+    );
+    println!("\n=== CompressionStats ===");
+    println!("redacted_saved: {}", compressed.stats.redacted_saved);
+    println!("whitespace_saved: {}", compressed.stats.whitespace_saved);
+    println!("json_minified_saved: {}", compressed.stats.json_minified_saved);
+    println!("thinking_saved: {}", compressed.stats.thinking_saved);
+    println!("tool_result_saved: {}", compressed.stats.tool_result_saved);
+    println!("tool_use_input_saved: {}", compressed.stats.tool_use_input_saved);
+    println!("history_turns_removed: {}", compressed.stats.history_turns_removed);
+    println!("history_bytes_saved: {}", compressed.stats.history_bytes_saved);
+    println!(
+        "history_turns_summarized: {}",
+        compressed.stats.history_turns_summarized
+    );
+    println!("summary_chars: {}", compressed.stats.summary_chars);
+    println!(
+        "empty_messages_removed: {}",
+        compressed.stats.empty_messages_removed
+    );
+    println!("total_saved: {}", compressed.stats.total_saved());
 
     Ok(())
 }
@@ -0,0 +1,93 @@
+//! Admin API 按能力鉴权
+//!
+//! 将"密钥 -> 能力集合"的解析（`Config::resolve_admin_capabilities`）与
+//! "请求是否被允许"的判定分离，便于各 Admin 路由在调用 `AdminService` 前
+//! 统一做一次权限检查。
+
+use crate::model::config::{AdminCapability, Config};
+
+/// 鉴权结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// 密钥有效且具备所需能力
+    Allowed,
+    /// 未提供密钥或密钥不匹配任何条目
+    Unauthorized,
+    /// 密钥有效，但缺少所需能力
+    Forbidden,
+}
+
+/// 校验某个 Admin API 密钥是否具备指定能力
+///
+/// `presented_key` 为 `None` 时（未携带密钥）直接判定为 `Unauthorized`。
+pub fn authorize(
+    config: &Config,
+    presented_key: Option<&str>,
+    required: AdminCapability,
+) -> AuthDecision {
+    let Some(key) = presented_key else {
+        return AuthDecision::Unauthorized;
+    };
+
+    match config.resolve_admin_capabilities(key) {
+        None => AuthDecision::Unauthorized,
+        Some(capabilities) if capabilities.contains(&required) => AuthDecision::Allowed,
+        Some(_) => AuthDecision::Forbidden,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::config::AdminKeyConfig;
+
+    fn base_config() -> Config {
+        let mut config = Config::default();
+        config.admin_api_key = Some("legacy-key".to_string());
+        config.admin_keys = vec![AdminKeyConfig {
+            key: "readonly-key".to_string(),
+            capabilities: vec![AdminCapability::ReadStatus, AdminCapability::ReadBalance],
+        }];
+        config
+    }
+
+    #[test]
+    fn test_legacy_key_has_full_access() {
+        let config = base_config();
+        assert_eq!(
+            authorize(&config, Some("legacy-key"), AdminCapability::ReloadConfig),
+            AuthDecision::Allowed
+        );
+    }
+
+    #[test]
+    fn test_scoped_key_forbidden_for_unlisted_capability() {
+        let config = base_config();
+        assert_eq!(
+            authorize(
+                &config,
+                Some("readonly-key"),
+                AdminCapability::ToggleCredential
+            ),
+            AuthDecision::Forbidden
+        );
+    }
+
+    #[test]
+    fn test_unknown_key_unauthorized() {
+        let config = base_config();
+        assert_eq!(
+            authorize(&config, Some("nope"), AdminCapability::ReadStatus),
+            AuthDecision::Unauthorized
+        );
+    }
+
+    #[test]
+    fn test_missing_key_unauthorized() {
+        let config = base_config();
+        assert_eq!(
+            authorize(&config, None, AdminCapability::ReadStatus),
+            AuthDecision::Unauthorized
+        );
+    }
+}
@@ -3,19 +3,76 @@
 use std::sync::Arc;
 
 use crate::kiro::token_manager::MultiTokenManager;
+use crate::model::config::ConfigUpdateResult;
+use crate::model::watcher::SharedConfig;
 
 use super::types::{BalanceResponse, CredentialStatusItem, CredentialsStatusResponse};
+use super::webhook::{CredentialEventPayload, CredentialEventType, WebhookNotifier};
 
 /// Admin 服务
 ///
 /// 封装所有 Admin API 的业务逻辑
 pub struct AdminService {
     token_manager: Arc<MultiTokenManager>,
+    shared_config: SharedConfig,
+    /// 实际生效的监听端口（`port: 0` 模式下为操作系统分配的端口），
+    /// 由启动阶段的 `Config::reserve_listen_port` 结果填入
+    bound_port: u16,
 }
 
 impl AdminService {
-    pub fn new(token_manager: Arc<MultiTokenManager>) -> Self {
-        Self { token_manager }
+    pub fn new(
+        token_manager: Arc<MultiTokenManager>,
+        shared_config: SharedConfig,
+        bound_port: u16,
+    ) -> Self {
+        Self {
+            token_manager,
+            shared_config,
+            bound_port,
+        }
+    }
+
+    /// 获取实际生效的监听端口（用于 Admin 状态接口回报 `port: 0` 模式下的真实端口）
+    pub fn get_bound_port(&self) -> u16 {
+        self.bound_port
+    }
+
+    /// 获取当前生效的配置（用于 Admin API 的只读展示）
+    pub fn get_effective_config(&self) -> serde_json::Value {
+        let config = self.shared_config.read();
+        serde_json::to_value(&*config).unwrap_or(serde_json::Value::Null)
+    }
+
+    /// 应用一次部分配置更新（运维通过 Admin API 调整策略）
+    ///
+    /// 安全可热更新的字段立即生效并持久化；影响监听 socket/TLS 等的字段
+    /// 会在返回结果的 `pending_restart` 中报告，等待下次重启。
+    pub fn update_config(&self, patch: serde_json::Value) -> anyhow::Result<ConfigUpdateResult> {
+        let mut config = self.shared_config.write();
+        config.apply_runtime_update(&patch)
+    }
+
+    /// 按当前生效配置构造一个 Webhook 通知发送器
+    ///
+    /// 未配置 `notifications` 时返回 `None`，调用方应跳过通知而不是报错。
+    /// 每次按需重新构造（而不是在 `AdminService` 构造时缓存一份），使凭据
+    /// 事件通知能跟随 `notifications` 的热更新立即生效。
+    fn notifier(&self) -> Option<WebhookNotifier> {
+        self.shared_config
+            .read()
+            .notifications
+            .clone()
+            .map(WebhookNotifier::new)
+    }
+
+    /// 当前等待重启生效的字段（文件热重载路径搁置的 `restart_required_fields`）
+    ///
+    /// 与 `update_config` 返回值里的 `pending_restart` 互补：后者只反映单次
+    /// Admin API 调用的结果，这里反映的是运维直接改 `config.json` 时被搁置、
+    /// 持续到下次重启前都有效的待办状态。
+    pub fn get_pending_restart_fields(&self) -> Vec<String> {
+        self.shared_config.pending_restart()
     }
 
     /// 获取所有凭据状态
@@ -47,8 +104,15 @@ impl AdminService {
 
     /// 设置凭据禁用状态
     pub fn set_disabled(&self, index: usize, disabled: bool) -> anyhow::Result<()> {
-        // 先获取当前凭据索引，用于判断是否需要切换
-        let current_index = self.token_manager.snapshot().current_index;
+        // 先获取当前凭据索引/失败计数，用于判断是否需要切换、以及通知负载
+        let snapshot = self.token_manager.snapshot();
+        let current_index = snapshot.current_index;
+        let failure_count = snapshot
+            .entries
+            .iter()
+            .find(|entry| entry.index == index)
+            .map(|entry| entry.failure_count)
+            .unwrap_or(0);
 
         self.token_manager.set_disabled(index, disabled)?;
 
@@ -56,6 +120,26 @@ impl AdminService {
         if disabled && index == current_index {
             let _ = self.token_manager.switch_to_next();
         }
+
+        if let Some(notifier) = self.notifier() {
+            let event_type = if disabled {
+                CredentialEventType::AutoDisabled
+            } else {
+                CredentialEventType::ReEnabled
+            };
+            tokio::spawn(async move {
+                notifier
+                    .notify(CredentialEventPayload {
+                        event_type,
+                        index,
+                        failure_count,
+                        usage_percentage: 0.0,
+                        account_hint: None,
+                    })
+                    .await;
+            });
+        }
+
         Ok(())
     }
 
@@ -66,7 +150,23 @@ impl AdminService {
 
     /// 重置失败计数并重新启用
     pub fn reset_and_enable(&self, index: usize) -> anyhow::Result<()> {
-        self.token_manager.reset_and_enable(index)
+        self.token_manager.reset_and_enable(index)?;
+
+        if let Some(notifier) = self.notifier() {
+            tokio::spawn(async move {
+                notifier
+                    .notify(CredentialEventPayload {
+                        event_type: CredentialEventType::ReEnabled,
+                        index,
+                        failure_count: 0,
+                        usage_percentage: 0.0,
+                        account_hint: None,
+                    })
+                    .await;
+            });
+        }
+
+        Ok(())
     }
 
     /// 获取凭据余额
@@ -82,6 +182,20 @@ impl AdminService {
             0.0
         };
 
+        if let Some(notifier) = self.notifier() {
+            if notifier.should_alert_low_balance(usage_percentage) {
+                notifier
+                    .notify(CredentialEventPayload {
+                        event_type: CredentialEventType::LowBalance,
+                        index,
+                        failure_count: 0,
+                        usage_percentage,
+                        account_hint: None,
+                    })
+                    .await;
+            }
+        }
+
         Ok(BalanceResponse {
             index,
             subscription_title: usage.subscription_title().map(|s| s.to_string()),
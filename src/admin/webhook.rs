@@ -0,0 +1,125 @@
+//! 凭据状态变更 Webhook 通知
+//!
+//! 在凭据被自动禁用/重新启用、token 刷新失败、或余额低于阈值时，向运维配置的
+//! 地址推送一条签名事件，使无人值守运行的实例也能接入 Slack/PagerDuty 等告警。
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::common::redact::mask_email;
+use crate::model::config::NotificationConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 凭据状态变更事件类型
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialEventType {
+    /// 因连续失败被自动禁用
+    AutoDisabled,
+    /// 被重新启用（手动或失败计数重置）
+    ReEnabled,
+    /// Token 刷新失败
+    TokenRefreshFailed,
+    /// 余额低于配置阈值
+    LowBalance,
+}
+
+/// 推送给 Webhook 接收方的事件负载
+#[derive(Debug, Clone, Serialize)]
+pub struct CredentialEventPayload {
+    pub event_type: CredentialEventType,
+    pub index: usize,
+    pub failure_count: u32,
+    /// 使用百分比（仅 `LowBalance` 等事件会填充有意义的值）
+    pub usage_percentage: f64,
+    /// 脱敏后的关联邮箱（如有），避免原始凭据标识外泄
+    pub account_hint: Option<String>,
+}
+
+/// Webhook 通知发送器
+pub struct WebhookNotifier {
+    config: NotificationConfig,
+    client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: NotificationConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// 发送一次凭据状态变更通知
+    ///
+    /// 网络失败仅记录告警日志，不会影响调用方（凭据管理）的主流程。
+    pub async fn notify(&self, mut payload: CredentialEventPayload) {
+        if let Some(email) = payload.account_hint.as_deref() {
+            payload.account_hint = Some(mask_email(email));
+        }
+
+        let body = match serde_json::to_vec(&payload) {
+            Ok(b) => b,
+            Err(e) => {
+                tracing::warn!(error = %e, "序列化 Webhook 通知负载失败");
+                return;
+            }
+        };
+
+        let mut request = self.client.post(&self.config.url).body(body.clone());
+
+        if let (Some(name), Some(value)) = (
+            self.config.auth_header_name.as_deref(),
+            self.config.auth_header_value.as_deref(),
+        ) {
+            request = request.header(name, value);
+        }
+
+        if let Some(secret) = self.config.hmac_secret.as_deref() {
+            match sign(secret, &body) {
+                Ok(signature) => {
+                    request = request.header("X-Signature-SHA256", signature);
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "计算 Webhook 签名失败，已跳过签名头");
+                }
+            }
+        }
+
+        if let Err(e) = request.send().await {
+            tracing::warn!(error = %e, url = %self.config.url, "发送 Webhook 通知失败");
+        }
+    }
+
+    /// 判断当前使用百分比是否需要触发低余额告警
+    pub fn should_alert_low_balance(&self, usage_percentage: f64) -> bool {
+        (100.0 - usage_percentage) <= self.config.balance_alert_threshold_percent
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> anyhow::Result<String> {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).map_err(|e| anyhow::anyhow!("{e}"))?;
+    mac.update(body);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_alert_low_balance() {
+        let notifier = WebhookNotifier::new(NotificationConfig {
+            url: "http://localhost/webhook".to_string(),
+            auth_header_name: None,
+            auth_header_value: None,
+            hmac_secret: None,
+            balance_alert_threshold_percent: 10.0,
+        });
+        assert!(notifier.should_alert_low_balance(92.0));
+        assert!(!notifier.should_alert_low_balance(50.0));
+    }
+}
@@ -10,24 +10,50 @@
 //! 4. tool_use input 截断
 //! 5. 历史截断
 
-use crate::kiro::model::requests::conversation::{ConversationState, Message};
-use crate::model::config::CompressionConfig;
+use regex::Regex;
+
+use crate::common::embedding::{cosine_similarity, TurnEmbedder};
+use crate::common::tokenizer::BpeTokenizer;
+use crate::kiro::model::requests::conversation::{
+    ConversationState, HistoryAssistantMessage, HistoryUserMessage, Message,
+};
+use crate::model::config::{Budget, CompressionConfig};
+
+/// `compress`/`compress_with_summarizer`（不接收模型名）使用的默认模型
+///
+/// 仅影响 `budget = Tokens` 模式下 BPE 编码表的选择，压缩预算只需要
+/// "足够接近"的 token 计数，不要求逐字节精确。
+const DEFAULT_TOKENIZER_MODEL: &str = "claude-sonnet-4.5";
 
 /// 压缩统计信息
+///
+/// `tool_result_saved`、`tool_use_input_saved`、`history_bytes_saved` 的单位
+/// 取决于调用时的 `CompressionConfig.budget`：`Chars` 模式下为字节数，`Tokens`
+/// 模式下为 BPE token 数；其余 `*_saved` 字段始终为字节数。
 #[derive(Debug, Default)]
 pub struct CompressionStats {
+    pub redacted_saved: usize,
     pub whitespace_saved: usize,
+    pub json_minified_saved: usize,
     pub thinking_saved: usize,
     pub tool_result_saved: usize,
     pub tool_use_input_saved: usize,
     pub history_turns_removed: usize,
     pub history_bytes_saved: usize,
+    /// `history_strategy = "summarize"` 下被汇总（而非直接丢弃）的轮数
+    pub history_turns_summarized: usize,
+    /// 插入的汇总消息的字符数
+    pub summary_chars: usize,
+    /// `prune_empty_pass` 剪除的空白/占位符历史消息数
+    pub empty_messages_removed: usize,
 }
 
 impl CompressionStats {
     /// 总节省字节数
     pub fn total_saved(&self) -> usize {
-        self.whitespace_saved
+        self.redacted_saved
+            + self.whitespace_saved
+            + self.json_minified_saved
             + self.thinking_saved
             + self.tool_result_saved
             + self.tool_use_input_saved
@@ -35,48 +61,312 @@ impl CompressionStats {
     }
 }
 
+/// 历史摘要生成器
+///
+/// `history_strategy = "summarize"` 时，被移除的轮次不会直接丢弃，而是交给
+/// 调用方提供的 `Summarizer` 生成一段摘要文本，拼回历史中，避免上下文被
+/// 完全丢失。未提供 summarizer 时退化为直接丢弃（等同 "oldest" 行为）。
+pub trait Summarizer {
+    fn summarize(&self, removed: &[Message]) -> String;
+}
+
+/// 零网络调用的启发式 `Summarizer` 实现
+///
+/// 对每个被移除的轮次提取首尾句、调用过的工具名与提及的文件路径，拼接为一段
+/// "较早对话要点回顾"。用于 `history_strategy = "summarize"` 或
+/// `adaptive_shrink_request_body` 最后手段层在没有接入上游摘要模型时兜底；
+/// 需要更高质量摘要时应改为注入一个调用大模型的 `Summarizer` 实现。
+pub struct HeuristicSummarizer;
+
+impl Summarizer for HeuristicSummarizer {
+    fn summarize(&self, removed: &[Message]) -> String {
+        let mut bullets = Vec::new();
+        let mut tool_names: Vec<String> = Vec::new();
+        let mut file_paths: Vec<String> = Vec::new();
+
+        for msg in removed {
+            match msg {
+                Message::User(u) => {
+                    if let Some(bullet) = summarize_turn_text(&u.user_input_message.content) {
+                        bullets.push(format!("- {bullet}"));
+                    }
+                    collect_file_paths(&u.user_input_message.content, &mut file_paths);
+                }
+                Message::Assistant(a) => {
+                    if let Some(bullet) = summarize_turn_text(&a.assistant_response_message.content)
+                    {
+                        bullets.push(format!("- {bullet}"));
+                    }
+                    collect_file_paths(&a.assistant_response_message.content, &mut file_paths);
+                    if let Some(ref tool_uses) = a.assistant_response_message.tool_uses {
+                        for tu in tool_uses {
+                            if !tool_names.contains(&tu.name) {
+                                tool_names.push(tu.name.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let turns = removed.len() / 2;
+        let mut summary = format!("Earlier in this conversation ({turns} turn(s) summarized):");
+        for bullet in &bullets {
+            summary.push('\n');
+            summary.push_str(bullet);
+        }
+        if !tool_names.is_empty() {
+            summary.push_str("\n- Tools invoked: ");
+            summary.push_str(&tool_names.join(", "));
+        }
+        if !file_paths.is_empty() {
+            summary.push_str("\n- Files referenced: ");
+            summary.push_str(&file_paths.join(", "));
+        }
+        summary
+    }
+}
+
+/// 提取一段文本的首句与尾句，拼成启发式摘要里的一行要点
+///
+/// 按 `.`/`!`/`?`/换行粗略切分句子；空白/占位符内容返回 `None`。
+fn summarize_turn_text(content: &str) -> Option<String> {
+    let trimmed = content.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    let sentences: Vec<&str> = trimmed
+        .split(['.', '!', '?', '\n'])
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    match sentences.as_slice() {
+        [] => None,
+        [only] => Some(only.to_string()),
+        [first, .., last] if first == last => Some(first.to_string()),
+        [first, .., last] => Some(format!("{first} ... {last}")),
+    }
+}
+
+/// 从文本中粗略提取形似文件路径的 token（含 `/` 与 `.`），用于摘要中的
+/// "涉及文件"提示
+///
+/// 纯启发式扫描，不做语法校验；漏检/误检都是可接受的——摘要只是帮助恢复
+/// 上下文的线索，不是精确记录。
+fn collect_file_paths(content: &str, out: &mut Vec<String>) {
+    for token in content.split_whitespace() {
+        let cleaned = token.trim_matches(|c: char| {
+            !c.is_alphanumeric() && c != '/' && c != '.' && c != '_' && c != '-'
+        });
+        if cleaned.contains('/') && cleaned.contains('.') && !out.iter().any(|p| p == cleaned) {
+            out.push(cleaned.to_string());
+        }
+    }
+}
+
+/// 环境上下文提供者
+///
+/// 用于在压缩前向 `current_message` 注入项目信息、当前打开文件等环境上下文，
+/// 通过 `inject_ambient_context` 显式调用（`ConversationState` 本身不持有
+/// provider 注册表，与 `Summarizer` 一样由调用方显式传入）。
+pub trait AmbientContextProvider {
+    /// 返回本次要注入的上下文文本；`None` 或 trim 后为空表示本次无上下文
+    fn provide(&self) -> Option<String>;
+}
+
+/// 依次调用所有 provider，将非空输出拼接后注入 `current_message` 内容前部
+///
+/// 空输出（`None` 或 trim 后为空）会被过滤，不会产生空白注入，与
+/// `prune_empty_pass` 剪除空白占位符消息的不变量保持一致。
+///
+/// 返回实际注入的字符数。
+pub fn inject_ambient_context(
+    state: &mut ConversationState,
+    providers: &[&dyn AmbientContextProvider],
+) -> usize {
+    let blocks: Vec<String> = providers
+        .iter()
+        .filter_map(|p| p.provide())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    if blocks.is_empty() {
+        return 0;
+    }
+
+    let injected = blocks.join("\n\n");
+    let injected_chars = injected.chars().count();
+
+    let content = &mut state.current_message.user_input_message.content;
+    if content.trim().is_empty() {
+        *content = injected;
+    } else {
+        *content = format!("{injected}\n\n{content}");
+    }
+
+    injected_chars
+}
+
 /// 压缩管道入口
 ///
-/// 按顺序执行各层压缩，返回统计信息。
+/// 按顺序执行各层压缩，返回统计信息。`budget = Tokens` 模式下使用
+/// `DEFAULT_TOKENIZER_MODEL` 近似选择 BPE 编码表；已知目标模型名时应改用
+/// `compress_with_model` 以获得更准确的 token 计数。
 pub fn compress(state: &mut ConversationState, config: &CompressionConfig) -> CompressionStats {
+    compress_with_model(state, config, DEFAULT_TOKENIZER_MODEL)
+}
+
+/// 压缩管道入口，附加一个 `Summarizer`，供 `history_strategy = "summarize"` 使用
+pub fn compress_with_summarizer(
+    state: &mut ConversationState,
+    config: &CompressionConfig,
+    summarizer: &dyn Summarizer,
+) -> CompressionStats {
+    compress_inner(state, config, DEFAULT_TOKENIZER_MODEL, Some(summarizer))
+}
+
+/// 压缩管道入口，显式指定 `budget = Tokens` 模式下用于选择 BPE 编码表的模型名
+///
+/// 按顺序执行各层压缩，返回统计信息。
+pub fn compress_with_model(
+    state: &mut ConversationState,
+    config: &CompressionConfig,
+    model_name: &str,
+) -> CompressionStats {
+    compress_inner(state, config, model_name, None)
+}
+
+/// [`compress_request`] 的返回值：压缩后重新序列化得到的请求体 JSON，
+/// 以及本次调用各层压缩的分阶段统计
+pub struct CompressedRequest {
+    /// 压缩后的 `ConversationState` 重新序列化得到的 JSON 字符串
+    pub body: String,
+    /// 本次压缩各层的分阶段统计（字节数或 token 数，取决于 `config.budget`）
+    pub stats: CompressionStats,
+}
+
+/// 一站式压缩入口：对 `state` 执行完整压缩管道并立即重新序列化为 JSON，
+/// 返回压缩后的请求体与分阶段统计
+///
+/// `compress`/`compress_with_model` 已经是可直接调用的压缩管道——本函数只是
+/// 在其上加一层"压缩 + 重新序列化"的便利封装，让只关心最终请求体字节数、
+/// 不想自己持有 `ConversationState` 的调用方（例如一次性诊断/分析脚本）
+/// 一步拿到压缩后的 JSON 和每个阶段节省的大小，而不必分别调用
+/// `compress` 和 `serde_json::to_string`。
+pub fn compress_request(state: &mut ConversationState, config: &CompressionConfig) -> CompressedRequest {
+    let stats = compress(state, config);
+    let body = serde_json::to_string(state).unwrap_or_default();
+    CompressedRequest { body, stats }
+}
+
+fn compress_inner(
+    state: &mut ConversationState,
+    config: &CompressionConfig,
+    model_name: &str,
+    summarizer: Option<&dyn Summarizer>,
+) -> CompressionStats {
     let mut stats = CompressionStats::default();
 
     if !config.enabled {
         return stats;
     }
 
+    // token 预算模式下构造一次分词器，供本次调用的所有 pass 复用
+    let tokenizer = match config.budget {
+        Budget::Tokens => match BpeTokenizer::for_model(model_name) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                tracing::warn!(error = %e, model = model_name, "构造 BPE 分词器失败，回退为字符预算");
+                None
+            }
+        },
+        Budget::Chars => None,
+    };
+
+    // 固定（pin）保护的 tool_use_id 集合，贯穿本次调用的所有会截断/移除内容的 pass
+    let pinned: std::collections::HashSet<&str> =
+        config.pinned_ids.iter().map(|s| s.as_str()).collect();
+
+    // 0. 敏感信息脱敏（先于空白压缩执行，使脱敏标记自身也会被正常处理）
+    if !config.redaction_patterns.is_empty() {
+        stats.redacted_saved = compress_redaction_pass(state, config);
+    }
+
     // 1. 空白压缩
     if config.whitespace_compression {
         stats.whitespace_saved = compress_whitespace_pass(state);
     }
 
+    // 1.5 JSON 压缩：无损，对 pretty-print 的 JSON 重新紧凑序列化
+    stats.json_minified_saved = compress_json_minify_pass(state);
+
+    // 1.7 剪除空白/占位符且无工具负载的历史消息（转换产物噪声，无损）
+    stats.empty_messages_removed = prune_empty_pass(state);
+
     // 2. thinking 丢弃/截断
     if config.thinking_strategy != "keep" {
         stats.thinking_saved = compress_thinking_pass(state, &config.thinking_strategy);
     }
 
     // 3. tool_result 智能截断
-    if config.tool_result_max_chars > 0 {
+    if let Some(tokenizer) = &tokenizer {
+        if config.tool_result_max_tokens > 0 {
+            stats.tool_result_saved = compress_tool_results_pass_tokens(
+                state,
+                tokenizer,
+                config.tool_result_max_tokens,
+                config.tool_result_head_lines,
+                config.tool_result_tail_lines,
+                &pinned,
+            );
+        }
+    } else if config.tool_result_max_chars > 0 {
         stats.tool_result_saved = compress_tool_results_pass(
             state,
             config.tool_result_max_chars,
             config.tool_result_head_lines,
             config.tool_result_tail_lines,
+            &pinned,
         );
     }
 
     // 4. tool_use input 截断
-    if config.tool_use_input_max_chars > 0 {
+    if let Some(tokenizer) = &tokenizer {
+        if config.tool_use_input_max_tokens > 0 {
+            stats.tool_use_input_saved = compress_tool_use_inputs_pass_tokens(
+                state,
+                tokenizer,
+                config.tool_use_input_max_tokens,
+                &pinned,
+            );
+        }
+    } else if config.tool_use_input_max_chars > 0 {
         stats.tool_use_input_saved =
-            compress_tool_use_inputs_pass(state, config.tool_use_input_max_chars);
+            compress_tool_use_inputs_pass(state, config.tool_use_input_max_chars, &pinned);
     }
 
     // 5. 历史截断（最后手段）
-    if config.max_history_turns > 0 || config.max_history_chars > 0 {
-        let (turns, bytes) =
-            compress_history_pass(state, config.max_history_turns, config.max_history_chars);
-        stats.history_turns_removed = turns;
-        stats.history_bytes_saved = bytes;
+    if let Some(tokenizer) = &tokenizer {
+        if config.max_history_turns > 0 || config.max_history_tokens > 0 {
+            let (turns, tokens_saved) = compress_history_pass_tokens(
+                state,
+                tokenizer,
+                config.max_history_turns,
+                config.max_history_tokens,
+                &pinned,
+            );
+            stats.history_turns_removed = turns;
+            stats.history_bytes_saved = tokens_saved;
+        }
+    } else if config.max_history_turns > 0 || config.max_history_chars > 0 {
+        let outcome = run_history_pass(state, config, summarizer, &pinned);
+        stats.history_turns_removed = outcome.turns_removed;
+        stats.history_bytes_saved = outcome.bytes_saved;
+        stats.history_turns_summarized = outcome.turns_summarized;
+        stats.summary_chars = outcome.summary_chars;
     }
 
     // 历史截断会破坏 tool_use(tool_uses) 与 tool_result(tool_results) 的跨消息配对：
@@ -94,6 +384,179 @@ pub fn compress(state: &mut ConversationState, config: &CompressionConfig) -> Co
     stats
 }
 
+// ============ 敏感信息脱敏 ============
+
+/// 按 `config.redaction_patterns` 对 ConversationState 中所有文本字段执行脱敏
+///
+/// 覆盖范围与 `compress_whitespace_pass`/`truncate_json_value_strings` 一致：
+/// history 中 user/assistant 的 `content`、tool_result 的 `text`，以及 tool_use
+/// `input` JSON 中递归出现的所有字符串，还有 `current_message`。
+/// 无法编译的正则会被跳过并记录告警日志，不影响其余模式生效。
+fn compress_redaction_pass(state: &mut ConversationState, config: &CompressionConfig) -> usize {
+    let patterns: Vec<Regex> = config
+        .redaction_patterns
+        .iter()
+        .filter_map(|p| match Regex::new(p) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                tracing::warn!(pattern = %p, error = %e, "脱敏正则编译失败，已跳过");
+                None
+            }
+        })
+        .collect();
+
+    if patterns.is_empty() {
+        return 0;
+    }
+
+    let exclude: std::collections::HashSet<&str> = config
+        .redaction_exclude_literals
+        .iter()
+        .map(|s| s.as_str())
+        .collect();
+
+    let mut saved = 0usize;
+
+    for msg in &mut state.history {
+        match msg {
+            Message::User(user_msg) => {
+                saved += redact_string_field(
+                    &mut user_msg.user_input_message.content,
+                    &patterns,
+                    &config.redaction_marker,
+                    &exclude,
+                );
+                for result in &mut user_msg
+                    .user_input_message
+                    .user_input_message_context
+                    .tool_results
+                {
+                    saved +=
+                        redact_tool_result_content(result, &patterns, &config.redaction_marker, &exclude);
+                }
+            }
+            Message::Assistant(assistant_msg) => {
+                saved += redact_string_field(
+                    &mut assistant_msg.assistant_response_message.content,
+                    &patterns,
+                    &config.redaction_marker,
+                    &exclude,
+                );
+                if let Some(ref mut tool_uses) = assistant_msg.assistant_response_message.tool_uses
+                {
+                    for tool_use in tool_uses.iter_mut() {
+                        saved += redact_json_value_strings(
+                            &mut tool_use.input,
+                            &patterns,
+                            &config.redaction_marker,
+                            &exclude,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    saved += redact_string_field(
+        &mut state.current_message.user_input_message.content,
+        &patterns,
+        &config.redaction_marker,
+        &exclude,
+    );
+    for result in &mut state
+        .current_message
+        .user_input_message
+        .user_input_message_context
+        .tool_results
+    {
+        saved += redact_tool_result_content(result, &patterns, &config.redaction_marker, &exclude);
+    }
+
+    saved
+}
+
+/// 对单个字符串字段执行脱敏，返回节省的字节数（标记比原文长时按 0 计）
+fn redact_string_field(
+    field: &mut String,
+    patterns: &[Regex],
+    marker: &str,
+    exclude: &std::collections::HashSet<&str>,
+) -> usize {
+    if field == " " {
+        return 0;
+    }
+
+    let original_len = field.len();
+    let mut result = std::borrow::Cow::Borrowed(field.as_str());
+    for re in patterns {
+        result = std::borrow::Cow::Owned(
+            re.replace_all(&result, |caps: &regex::Captures| {
+                let matched = &caps[0];
+                if exclude.contains(matched) {
+                    matched.to_string()
+                } else {
+                    marker.to_string()
+                }
+            })
+            .into_owned(),
+        );
+    }
+
+    if result.as_ref() != field.as_str() {
+        let new_value = result.into_owned();
+        let saved = original_len.saturating_sub(new_value.len());
+        *field = new_value;
+        saved
+    } else {
+        0
+    }
+}
+
+/// 对单个 tool_result 的 content 数组中的 text 字段执行脱敏
+fn redact_tool_result_content(
+    result: &mut crate::kiro::model::requests::tool::ToolResult,
+    patterns: &[Regex],
+    marker: &str,
+    exclude: &std::collections::HashSet<&str>,
+) -> usize {
+    let mut saved = 0usize;
+    for map in result.content.iter_mut() {
+        if let Some(serde_json::Value::String(text)) = map.get_mut("text") {
+            saved += redact_string_field(text, patterns, marker, exclude);
+        }
+    }
+    saved
+}
+
+/// 递归对 JSON 值中的字符串执行脱敏（与 `truncate_json_value_strings` 结构一致）
+fn redact_json_value_strings(
+    value: &mut serde_json::Value,
+    patterns: &[Regex],
+    marker: &str,
+    exclude: &std::collections::HashSet<&str>,
+) -> usize {
+    let mut saved = 0usize;
+
+    match value {
+        serde_json::Value::String(s) => {
+            saved += redact_string_field(s, patterns, marker, exclude);
+        }
+        serde_json::Value::Object(map) => {
+            for (_, v) in map.iter_mut() {
+                saved += redact_json_value_strings(v, patterns, marker, exclude);
+            }
+        }
+        serde_json::Value::Array(arr) => {
+            for v in arr.iter_mut() {
+                saved += redact_json_value_strings(v, patterns, marker, exclude);
+            }
+        }
+        _ => {}
+    }
+
+    saved
+}
+
 // ============ 空白压缩 ============
 
 /// 空白压缩：连续空行(3+)→单空行，行尾空格移除，保留行首缩进
@@ -234,6 +697,146 @@ fn truncate_thinking_blocks(text: &str, max_chars: usize) -> String {
     result
 }
 
+// ============ JSON 压缩（无损） ============
+
+/// 对 tool_result `text` 与 tool_use `input` 中可解析为 JSON 的字符串重新做
+/// 紧凑序列化（去除 pretty-print 缩进/换行），仅在压缩后严格变短时才采用
+///
+/// 与截断类 pass 不同，这是无损操作：结果反序列化后与原始内容完全等价，
+/// 因此排在空白压缩之后、thinking 处理之前执行，尽量减少后续有损截断层
+/// 需要触发的频率。
+fn compress_json_minify_pass(state: &mut ConversationState) -> usize {
+    let mut saved = 0usize;
+
+    for msg in &mut state.history {
+        match msg {
+            Message::User(user_msg) => {
+                for result in &mut user_msg
+                    .user_input_message
+                    .user_input_message_context
+                    .tool_results
+                {
+                    saved += minify_tool_result_content(result);
+                }
+            }
+            Message::Assistant(assistant_msg) => {
+                if let Some(ref mut tool_uses) = assistant_msg.assistant_response_message.tool_uses
+                {
+                    for tool_use in tool_uses.iter_mut() {
+                        saved += minify_json_value_strings(&mut tool_use.input);
+                    }
+                }
+            }
+        }
+    }
+
+    for result in &mut state
+        .current_message
+        .user_input_message
+        .user_input_message_context
+        .tool_results
+    {
+        saved += minify_tool_result_content(result);
+    }
+
+    saved
+}
+
+/// 对单个 tool_result 的 content 数组中的 text 字段尝试 JSON 紧凑化
+fn minify_tool_result_content(
+    result: &mut crate::kiro::model::requests::tool::ToolResult,
+) -> usize {
+    let mut saved = 0usize;
+    for map in result.content.iter_mut() {
+        if let Some(serde_json::Value::String(text)) = map.get_mut("text") {
+            saved += minify_json_string(text);
+        }
+    }
+    saved
+}
+
+/// 递归对 JSON 值中"内嵌为字符串的 JSON"执行紧凑化（与 `truncate_json_value_strings`
+/// 结构一致），覆盖 tool_use input 中任意深度的字符串字段
+fn minify_json_value_strings(value: &mut serde_json::Value) -> usize {
+    let mut saved = 0usize;
+
+    match value {
+        serde_json::Value::String(s) => {
+            saved += minify_json_string(s);
+        }
+        serde_json::Value::Object(map) => {
+            for (_, v) in map.iter_mut() {
+                saved += minify_json_value_strings(v);
+            }
+        }
+        serde_json::Value::Array(arr) => {
+            for v in arr.iter_mut() {
+                saved += minify_json_value_strings(v);
+            }
+        }
+        _ => {}
+    }
+
+    saved
+}
+
+/// 若字符串可解析为 JSON，重新紧凑序列化；仅当结果严格更短时才采用
+fn minify_json_string(text: &mut String) -> usize {
+    let Ok(parsed) = serde_json::from_str::<serde_json::Value>(text) else {
+        return 0;
+    };
+    let Ok(compact) = serde_json::to_string(&parsed) else {
+        return 0;
+    };
+
+    if compact.len() < text.len() {
+        let saved = text.len() - compact.len();
+        *text = compact;
+        saved
+    } else {
+        0
+    }
+}
+
+// ============ 空消息剪除 ============
+
+/// 剪除 history 中内容为空白/占位符且不携带 tool_results/tool_uses 的消息
+///
+/// 这类消息是协议转换产物（例如仅剩 " " 占位符、且没有工具负载的残留轮次），
+/// 对上游没有信息量；只要不携带 tool_results/tool_uses，移除它们就不会破坏
+/// `repair_tool_pairing_pass` 依赖的 tool_use/tool_result 跨消息配对。
+///
+/// 返回移除的消息数。
+fn prune_empty_pass(state: &mut ConversationState) -> usize {
+    let mut removed = 0usize;
+
+    state.history.retain(|msg| {
+        let keep = match msg {
+            Message::User(u) => {
+                !u.user_input_message.content.trim().is_empty()
+                    || !u
+                        .user_input_message
+                        .user_input_message_context
+                        .tool_results
+                        .is_empty()
+            }
+            Message::Assistant(a) => {
+                !a.assistant_response_message.content.trim().is_empty()
+                    || a.assistant_response_message
+                        .tool_uses
+                        .as_ref()
+                        .is_some_and(|tool_uses| !tool_uses.is_empty())
+            }
+        };
+        if !keep {
+            removed += 1;
+        }
+        keep
+    });
+
+    removed
+}
+
 // ============ tool_result 智能截断 ============
 
 /// 按行智能截断，保留头尾行
@@ -290,11 +893,14 @@ fn smart_truncate_by_lines(
 }
 
 /// 遍历所有 tool_result 的 text 字段，执行智能截断
+///
+/// `pinned` 中的 tool_use_id 对应的 tool_result 会被跳过，原样保留。
 fn compress_tool_results_pass(
     state: &mut ConversationState,
     max_chars: usize,
     head_lines: usize,
     tail_lines: usize,
+    pinned: &std::collections::HashSet<&str>,
 ) -> usize {
     let mut saved = 0usize;
 
@@ -305,6 +911,9 @@ fn compress_tool_results_pass(
                 .user_input_message_context
                 .tool_results
             {
+                if pinned.contains(result.tool_use_id.as_str()) {
+                    continue;
+                }
                 saved += truncate_tool_result_content(
                     &mut result.content,
                     max_chars,
@@ -321,6 +930,9 @@ fn compress_tool_results_pass(
         .user_input_message_context
         .tool_results
     {
+        if pinned.contains(result.tool_use_id.as_str()) {
+            continue;
+        }
         saved +=
             truncate_tool_result_content(&mut result.content, max_chars, head_lines, tail_lines);
     }
@@ -350,10 +962,134 @@ fn truncate_tool_result_content(
     saved
 }
 
+/// 按 token 预算智能截断，保留头尾行（`smart_truncate_by_lines` 的 token 版本）
+fn smart_truncate_by_lines_tokens(
+    text: &str,
+    tokenizer: &BpeTokenizer,
+    max_tokens: usize,
+    head_lines: usize,
+    tail_lines: usize,
+) -> (String, usize) {
+    let token_count = tokenizer.count(text);
+    if token_count <= max_tokens {
+        return (text.to_string(), 0);
+    }
+
+    let lines: Vec<&str> = text.lines().collect();
+    let total_lines = lines.len();
+
+    if total_lines <= head_lines + tail_lines {
+        let half = max_tokens / 2;
+        let (head, tail, omitted) = tokenizer.truncate_head_tail(text, half, max_tokens - half);
+        let result = format!("{head}\n... [{omitted} tokens omitted] ...\n{tail}");
+        let saved = token_count.saturating_sub(tokenizer.count(&result));
+        return (result, saved);
+    }
+
+    let head_part: String = lines[..head_lines].join("\n");
+    let tail_part: String = lines[total_lines - tail_lines..].join("\n");
+    let omitted_lines = total_lines - head_lines - tail_lines;
+    let mut result = format!("{head_part}\n... [{omitted_lines} lines omitted] ...\n{tail_part}");
+
+    // 硬截断兜底：确保结果不超过 max_tokens
+    if tokenizer.count(&result) > max_tokens {
+        let (truncated, _) = tokenizer.truncate_to_tokens(&result, max_tokens);
+        result = truncated;
+    }
+
+    let saved = token_count.saturating_sub(tokenizer.count(&result));
+    (result, saved)
+}
+
+/// 遍历所有 tool_result 的 text 字段，按 token 预算执行智能截断
+///
+/// `pinned` 中的 tool_use_id 对应的 tool_result 会被跳过，原样保留。
+fn compress_tool_results_pass_tokens(
+    state: &mut ConversationState,
+    tokenizer: &BpeTokenizer,
+    max_tokens: usize,
+    head_lines: usize,
+    tail_lines: usize,
+    pinned: &std::collections::HashSet<&str>,
+) -> usize {
+    let mut saved = 0usize;
+
+    for msg in &mut state.history {
+        if let Message::User(user_msg) = msg {
+            for result in &mut user_msg
+                .user_input_message
+                .user_input_message_context
+                .tool_results
+            {
+                if pinned.contains(result.tool_use_id.as_str()) {
+                    continue;
+                }
+                saved += truncate_tool_result_content_tokens(
+                    &mut result.content,
+                    tokenizer,
+                    max_tokens,
+                    head_lines,
+                    tail_lines,
+                );
+            }
+        }
+    }
+
+    for result in &mut state
+        .current_message
+        .user_input_message
+        .user_input_message_context
+        .tool_results
+    {
+        if pinned.contains(result.tool_use_id.as_str()) {
+            continue;
+        }
+        saved += truncate_tool_result_content_tokens(
+            &mut result.content,
+            tokenizer,
+            max_tokens,
+            head_lines,
+            tail_lines,
+        );
+    }
+
+    saved
+}
+
+/// 截断单个 tool_result 的 content 数组中的 text 字段（token 预算版本）
+fn truncate_tool_result_content_tokens(
+    content: &mut [serde_json::Map<String, serde_json::Value>],
+    tokenizer: &BpeTokenizer,
+    max_tokens: usize,
+    head_lines: usize,
+    tail_lines: usize,
+) -> usize {
+    let mut saved = 0usize;
+
+    for map in content.iter_mut() {
+        if let Some(serde_json::Value::String(text)) = map.get_mut("text")
+            && tokenizer.count(text) > max_tokens
+        {
+            let (truncated, s) =
+                smart_truncate_by_lines_tokens(text, tokenizer, max_tokens, head_lines, tail_lines);
+            saved += s;
+            *text = truncated;
+        }
+    }
+
+    saved
+}
+
 // ============ tool_use input 截断 ============
 
 /// 遍历 history 中 assistant 消息的 tool_use input，截断大字符串字段
-fn compress_tool_use_inputs_pass(state: &mut ConversationState, max_chars: usize) -> usize {
+///
+/// `pinned` 中的 tool_use_id 会被跳过，原样保留。
+fn compress_tool_use_inputs_pass(
+    state: &mut ConversationState,
+    max_chars: usize,
+    pinned: &std::collections::HashSet<&str>,
+) -> usize {
     let mut saved = 0usize;
 
     for msg in &mut state.history {
@@ -361,6 +1097,9 @@ fn compress_tool_use_inputs_pass(state: &mut ConversationState, max_chars: usize
             && let Some(ref mut tool_uses) = assistant_msg.assistant_response_message.tool_uses
         {
             for tool_use in tool_uses.iter_mut() {
+                if pinned.contains(tool_use.tool_use_id.as_str()) {
+                    continue;
+                }
                 let serialized = serde_json::to_string(&tool_use.input).unwrap_or_default();
                 if serialized.chars().count() > max_chars {
                     saved += truncate_json_value_strings(&mut tool_use.input, max_chars);
@@ -411,31 +1150,414 @@ fn truncate_json_value_strings(value: &mut serde_json::Value, max_chars: usize)
                 saved += truncate_json_value_strings(v, max_chars);
             }
         }
-        _ => {}
+        _ => {}
+    }
+
+    saved
+}
+
+/// 遍历 history 中 assistant 消息的 tool_use input，按 token 预算截断大字符串字段
+///
+/// `pinned` 中的 tool_use_id 会被跳过，原样保留。
+fn compress_tool_use_inputs_pass_tokens(
+    state: &mut ConversationState,
+    tokenizer: &BpeTokenizer,
+    max_tokens: usize,
+    pinned: &std::collections::HashSet<&str>,
+) -> usize {
+    let mut saved = 0usize;
+
+    for msg in &mut state.history {
+        if let Message::Assistant(assistant_msg) = msg
+            && let Some(ref mut tool_uses) = assistant_msg.assistant_response_message.tool_uses
+        {
+            for tool_use in tool_uses.iter_mut() {
+                if pinned.contains(tool_use.tool_use_id.as_str()) {
+                    continue;
+                }
+                let serialized = serde_json::to_string(&tool_use.input).unwrap_or_default();
+                if tokenizer.count(&serialized) > max_tokens {
+                    saved +=
+                        truncate_json_value_strings_tokens(&mut tool_use.input, tokenizer, max_tokens);
+                }
+            }
+        }
+    }
+
+    saved
+}
+
+/// 递归截断 JSON 值中的大字符串（token 预算版本）
+fn truncate_json_value_strings_tokens(
+    value: &mut serde_json::Value,
+    tokenizer: &BpeTokenizer,
+    max_tokens: usize,
+) -> usize {
+    let mut saved = 0usize;
+
+    match value {
+        serde_json::Value::String(s) => {
+            let original_token_count = tokenizer.count(s);
+            if original_token_count > max_tokens {
+                let original_len = s.len();
+                let (truncated, omitted_tokens) = tokenizer.truncate_to_tokens(s, max_tokens);
+
+                // 仅当“带标记版本”确实更短时才附加标记，避免在边界场景反而把字符串变长
+                let with_marker = format!("{truncated}...[truncated {omitted_tokens} tokens]");
+                let new_value = if with_marker.len() < original_len {
+                    with_marker
+                } else {
+                    truncated
+                };
+
+                saved += original_token_count.saturating_sub(tokenizer.count(&new_value));
+                *s = new_value;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (_, v) in map.iter_mut() {
+                saved += truncate_json_value_strings_tokens(v, tokenizer, max_tokens);
+            }
+        }
+        serde_json::Value::Array(arr) => {
+            for v in arr.iter_mut() {
+                saved += truncate_json_value_strings_tokens(v, tokenizer, max_tokens);
+            }
+        }
+        _ => {}
+    }
+
+    saved
+}
+
+// ============ 历史截断 ============
+
+/// 历史截断的结果
+struct HistoryPassOutcome {
+    turns_removed: usize,
+    bytes_saved: usize,
+    turns_summarized: usize,
+    summary_chars: usize,
+}
+
+/// 按 `config.history_strategy` 选择历史截断策略
+///
+/// `"summarize"` 仅在提供了 `summarizer` 时生效，否则与其他未识别的取值一样
+/// 按 `"oldest"` 处理。
+fn run_history_pass(
+    state: &mut ConversationState,
+    config: &CompressionConfig,
+    summarizer: Option<&dyn Summarizer>,
+    pinned: &std::collections::HashSet<&str>,
+) -> HistoryPassOutcome {
+    match (config.history_strategy.as_str(), summarizer) {
+        ("summarize", Some(summarizer)) => {
+            let (turns_removed, bytes_saved, turns_summarized, summary_chars) =
+                compress_history_pass_summarize(
+                    state,
+                    config.max_history_turns,
+                    config.max_history_chars,
+                    summarizer,
+                    pinned,
+                );
+            HistoryPassOutcome {
+                turns_removed,
+                bytes_saved,
+                turns_summarized,
+                summary_chars,
+            }
+        }
+        ("relevance", _) => {
+            let (turns_removed, bytes_saved) = compress_history_pass_relevance(
+                state,
+                config.max_history_turns,
+                config.max_history_chars,
+                pinned,
+            );
+            HistoryPassOutcome {
+                turns_removed,
+                bytes_saved,
+                turns_summarized: 0,
+                summary_chars: 0,
+            }
+        }
+        _ => {
+            let (turns_removed, bytes_saved) = compress_history_pass(
+                state,
+                config.max_history_turns,
+                config.max_history_chars,
+                pinned,
+            );
+            HistoryPassOutcome {
+                turns_removed,
+                bytes_saved,
+                turns_summarized: 0,
+                summary_chars: 0,
+            }
+        }
+    }
+}
+
+/// 判断一对 (user, assistant) 历史轮次是否被 `pinned` 保护
+///
+/// 命中条件：该轮次内任意 tool_result 或 tool_use 的 `tool_use_id` 出现在
+/// `pinned` 中。纯文本（无工具调用）轮次当前无法被 pin（没有可匹配的 id）。
+pub(crate) fn pair_is_pinned(
+    user_msg: &Message,
+    assistant_msg: &Message,
+    pinned: &std::collections::HashSet<&str>,
+) -> bool {
+    if pinned.is_empty() {
+        return false;
+    }
+    if let Message::User(u) = user_msg {
+        for tr in &u.user_input_message.user_input_message_context.tool_results {
+            if pinned.contains(tr.tool_use_id.as_str()) {
+                return true;
+            }
+        }
+    }
+    if let Message::Assistant(a) = assistant_msg
+        && let Some(ref tool_uses) = a.assistant_response_message.tool_uses
+    {
+        for tu in tool_uses {
+            if pinned.contains(tu.tool_use_id.as_str()) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// 在可移除区间 `[preserve_count, history.len() - 2)` 内找到最早的未被 pin 的
+/// (user, assistant) 轮次起始下标；最近一轮（末尾 2 条）始终保留，不参与搜索
+fn find_removable_pair_index(
+    state: &ConversationState,
+    preserve_count: usize,
+    pinned: &std::collections::HashSet<&str>,
+) -> Option<usize> {
+    let end = state.history.len().saturating_sub(2);
+    let mut idx = preserve_count;
+    while idx < end {
+        if !pair_is_pinned(&state.history[idx], &state.history[idx + 1], pinned) {
+            return Some(idx);
+        }
+        idx += 2;
+    }
+    None
+}
+
+/// 在可移除区间 `[preserve_count, history.len() - 2)` 内，按与 `current_vector`
+/// 的语义相关性挑选最不相关的 (user, assistant) 轮次起始下标
+///
+/// 轮次得分取 user/assistant 两条消息各自向量与 `current_vector` 余弦相似度的
+/// 最大值（轮次中只要有一条消息仍与当前问题相关，就不优先丢弃它）。被
+/// `pinned` 保护的轮次不参与打分；最近一轮（末尾 2 条）始终保留，不参与搜索。
+/// 可移除区间全部被 pin 保护时返回 `None`，调用方应退回按时间顺序移除。
+pub(crate) fn find_least_relevant_pair_index(
+    history: &[Message],
+    preserve_count: usize,
+    pinned: &std::collections::HashSet<&str>,
+    embedder: &dyn TurnEmbedder,
+    current_vector: &[f32],
+) -> Option<usize> {
+    let end = history.len().saturating_sub(2);
+    let mut best_idx = None;
+    let mut best_score = f32::INFINITY;
+
+    let mut idx = preserve_count;
+    while idx < end {
+        if !pair_is_pinned(&history[idx], &history[idx + 1], pinned) {
+            let text_of = |msg: &Message| -> &str {
+                match msg {
+                    Message::User(u) => u.user_input_message.content.as_str(),
+                    Message::Assistant(a) => a.assistant_response_message.content.as_str(),
+                }
+            };
+
+            let user_score = cosine_similarity(current_vector, &embedder.embed(text_of(&history[idx])));
+            let assistant_score =
+                cosine_similarity(current_vector, &embedder.embed(text_of(&history[idx + 1])));
+            let score = user_score.max(assistant_score);
+
+            if score < best_score {
+                best_score = score;
+                best_idx = Some(idx);
+            }
+        }
+        idx += 2;
+    }
+
+    best_idx
+}
+
+/// 历史截断：保留前 2 条（系统消息对），从前往后成对移除最早的*未被 pin*的轮次
+///
+/// 若剩余可移除轮次全部被 `pinned` 保护，则提前停止（即使仍超出预算）。
+///
+/// 返回 (移除的轮数, 移除的字节数)
+fn compress_history_pass(
+    state: &mut ConversationState,
+    max_turns: usize,
+    max_chars: usize,
+    pinned: &std::collections::HashSet<&str>,
+) -> (usize, usize) {
+    let mut removed = 0usize;
+    let mut bytes_saved = 0usize;
+    let preserve_count = 2;
+
+    /// 计算一条消息的字节数
+    fn msg_bytes(msg: &Message) -> usize {
+        match msg {
+            Message::User(u) => u.user_input_message.content.len(),
+            Message::Assistant(a) => a.assistant_response_message.content.len(),
+        }
+    }
+
+    // 按轮数截断
+    if max_turns > 0 {
+        let max_messages = preserve_count + max_turns * 2;
+        while state.history.len() > max_messages && state.history.len() > preserve_count + 2 {
+            let Some(idx) = find_removable_pair_index(state, preserve_count, pinned) else {
+                break;
+            };
+            bytes_saved += msg_bytes(&state.history[idx]);
+            state.history.remove(idx);
+            bytes_saved += msg_bytes(&state.history[idx]);
+            state.history.remove(idx);
+            removed += 1;
+        }
+    }
+
+    // 按字符数截断
+    if max_chars > 0 {
+        loop {
+            let total_chars: usize = state
+                .history
+                .iter()
+                .map(|msg| match msg {
+                    Message::User(u) => u.user_input_message.content.chars().count(),
+                    Message::Assistant(a) => a.assistant_response_message.content.chars().count(),
+                })
+                .sum();
+
+            if total_chars <= max_chars || state.history.len() <= preserve_count + 2 {
+                break;
+            }
+
+            let Some(idx) = find_removable_pair_index(state, preserve_count, pinned) else {
+                break;
+            };
+            bytes_saved += msg_bytes(&state.history[idx]);
+            state.history.remove(idx);
+            bytes_saved += msg_bytes(&state.history[idx]);
+            state.history.remove(idx);
+            removed += 1;
+        }
+    }
+
+    (removed, bytes_saved)
+}
+
+/// 汇总式历史截断：保留前 2 条（系统消息对）与最近一轮，将待移除的轮次交给
+/// `summarizer` 生成摘要，作为一对合成的 user/assistant 消息插入系统消息对
+/// 之后，而不是直接丢弃。停止条件与 `compress_history_pass` 一致。
+///
+/// 被 `pinned` 保护的轮次不会被选中移除/汇总；若剩余可移除轮次全部被保护，
+/// 则提前停止。
+///
+/// 返回 (移除的轮数, 净节省字节数, 被汇总的轮数, 摘要字符数)
+fn compress_history_pass_summarize(
+    state: &mut ConversationState,
+    max_turns: usize,
+    max_chars: usize,
+    summarizer: &dyn Summarizer,
+    pinned: &std::collections::HashSet<&str>,
+) -> (usize, usize, usize, usize) {
+    let preserve_count = 2;
+
+    if max_turns == 0 && max_chars == 0 {
+        return (0, 0, 0, 0);
+    }
+
+    let mut removed_messages: Vec<Message> = Vec::new();
+
+    loop {
+        let history_len = state.history.len();
+        if history_len <= preserve_count + 2 {
+            break;
+        }
+
+        let turns_over_limit = max_turns > 0 && (history_len - preserve_count) / 2 > max_turns;
+        let chars_over_limit = max_chars > 0 && {
+            let total_chars: usize = state
+                .history
+                .iter()
+                .map(|msg| match msg {
+                    Message::User(u) => u.user_input_message.content.chars().count(),
+                    Message::Assistant(a) => a.assistant_response_message.content.chars().count(),
+                })
+                .sum();
+            total_chars > max_chars
+        };
+
+        if !turns_over_limit && !chars_over_limit {
+            break;
+        }
+
+        let Some(idx) = find_removable_pair_index(state, preserve_count, pinned) else {
+            break;
+        };
+        removed_messages.push(state.history.remove(idx));
+        removed_messages.push(state.history.remove(idx));
     }
 
-    saved
-}
+    if removed_messages.is_empty() {
+        return (0, 0, 0, 0);
+    }
 
-// ============ 历史截断 ============
+    let turns_removed = removed_messages.len() / 2;
+    let removed_bytes: usize = removed_messages.iter().map(message_content_bytes).sum();
+
+    let summary = summarizer.summarize(&removed_messages);
+    let summary_chars = summary.chars().count();
+    let summary_bytes = summary.len();
+
+    state.history.insert(
+        preserve_count,
+        Message::Assistant(HistoryAssistantMessage::new(
+            "Acknowledged earlier context (summarized).",
+        )),
+    );
+    state.history.insert(
+        preserve_count,
+        Message::User(HistoryUserMessage::new(&summary, "claude-sonnet-4.5")),
+    );
+
+    let bytes_saved = removed_bytes.saturating_sub(summary_bytes);
+
+    (turns_removed, bytes_saved, turns_removed, summary_chars)
+}
 
-/// 历史截断：保留前 2 条（系统消息对），从前往后成对移除
+/// 历史截断（token 预算版本）：保留前 2 条（系统消息对），从前往后成对移除
+/// 最早的*未被 pin*的轮次
 ///
-/// 返回 (移除的轮数, 移除的字节数)
-fn compress_history_pass(
+/// 返回 (移除的轮数, 移除的 token 数)
+fn compress_history_pass_tokens(
     state: &mut ConversationState,
+    tokenizer: &BpeTokenizer,
     max_turns: usize,
-    max_chars: usize,
+    max_tokens: usize,
+    pinned: &std::collections::HashSet<&str>,
 ) -> (usize, usize) {
     let mut removed = 0usize;
-    let mut bytes_saved = 0usize;
+    let mut tokens_saved = 0usize;
     let preserve_count = 2;
 
-    /// 计算一条消息的字节数
-    fn msg_bytes(msg: &Message) -> usize {
+    fn msg_tokens(msg: &Message, tokenizer: &BpeTokenizer) -> usize {
         match msg {
-            Message::User(u) => u.user_input_message.content.len(),
-            Message::Assistant(a) => a.assistant_response_message.content.len(),
+            Message::User(u) => tokenizer.count(&u.user_input_message.content),
+            Message::Assistant(a) => tokenizer.count(&a.assistant_response_message.content),
         }
     }
 
@@ -443,17 +1565,115 @@ fn compress_history_pass(
     if max_turns > 0 {
         let max_messages = preserve_count + max_turns * 2;
         while state.history.len() > max_messages && state.history.len() > preserve_count + 2 {
-            bytes_saved += msg_bytes(&state.history[preserve_count]);
-            state.history.remove(preserve_count);
-            bytes_saved += msg_bytes(&state.history[preserve_count]);
-            state.history.remove(preserve_count);
+            let Some(idx) = find_removable_pair_index(state, preserve_count, pinned) else {
+                break;
+            };
+            tokens_saved += msg_tokens(&state.history[idx], tokenizer);
+            state.history.remove(idx);
+            tokens_saved += msg_tokens(&state.history[idx], tokenizer);
+            state.history.remove(idx);
             removed += 1;
         }
     }
 
-    // 按字符数截断
-    if max_chars > 0 {
+    // 按 token 数截断
+    if max_tokens > 0 {
         loop {
+            let total_tokens: usize = state
+                .history
+                .iter()
+                .map(|msg| msg_tokens(msg, tokenizer))
+                .sum();
+
+            if total_tokens <= max_tokens || state.history.len() <= preserve_count + 2 {
+                break;
+            }
+
+            let Some(idx) = find_removable_pair_index(state, preserve_count, pinned) else {
+                break;
+            };
+            tokens_saved += msg_tokens(&state.history[idx], tokenizer);
+            state.history.remove(idx);
+            tokens_saved += msg_tokens(&state.history[idx], tokenizer);
+            state.history.remove(idx);
+            removed += 1;
+        }
+    }
+
+    (removed, tokens_saved)
+}
+
+/// 计算一条消息的字节数（与 `compress_history_pass` 中的 `msg_bytes` 等价）
+fn message_content_bytes(msg: &Message) -> usize {
+    match msg {
+        Message::User(u) => u.user_input_message.content.len(),
+        Message::Assistant(a) => a.assistant_response_message.content.len(),
+    }
+}
+
+/// 将文本切分为小写单词集合，用于 Jaccard 相关性打分
+///
+/// 按非字母数字字符分词，丢弃长度小于 3 的 token（常见停用词/噪声）。
+fn tokenize_for_relevance(text: &str) -> std::collections::HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|s| s.chars().count() >= 3)
+        .map(|s| s.to_lowercase())
+        .collect()
+}
+
+/// Jaccard 相似度 `|A∩B| / |A∪B|`，并集为空时返回 0
+fn jaccard_similarity(
+    a: &std::collections::HashSet<String>,
+    b: &std::collections::HashSet<String>,
+) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let intersection = a.intersection(b).count();
+    let union = a.union(b).count();
+    if union == 0 {
+        0.0
+    } else {
+        intersection as f64 / union as f64
+    }
+}
+
+/// 相关性历史截断：保留前 2 条（系统消息对）和最近一轮，按与当前消息的
+/// Jaccard 相关性对可移除的轮次打分，优先移除相关性最低者
+///
+/// 与 `compress_history_pass` 一样成对移除（保持 user/assistant 交替），
+/// 但不是固定移除最旧的轮次，而是依据相关性排序；相关性相同时，通过一个
+/// 随轮次新旧递增的小幅"recency bonus"让并列情况偏向保留更新的轮次。
+///
+/// 被 `pinned` 保护的轮次不参与打分，永远不会被选中；若可移除区间内的轮次
+/// 全部被保护，则提前停止。
+///
+/// 返回 (移除的轮数, 移除的字节数)
+fn compress_history_pass_relevance(
+    state: &mut ConversationState,
+    max_turns: usize,
+    max_chars: usize,
+    pinned: &std::collections::HashSet<&str>,
+) -> (usize, usize) {
+    let mut removed = 0usize;
+    let mut bytes_saved = 0usize;
+    let preserve_count = 2;
+
+    if max_turns == 0 && max_chars == 0 {
+        return (removed, bytes_saved);
+    }
+
+    let current_tokens =
+        tokenize_for_relevance(&state.current_message.user_input_message.content);
+
+    let needs_more_removal = |state: &ConversationState| -> bool {
+        // 保留前 preserve_count 条 + 最近一轮（2 条），其余为可移除区间
+        if state.history.len() <= preserve_count + 2 {
+            return false;
+        }
+        let turns_over_limit =
+            max_turns > 0 && (state.history.len() - preserve_count) / 2 > max_turns;
+        let chars_over_limit = max_chars > 0 && {
             let total_chars: usize = state
                 .history
                 .iter()
@@ -462,17 +1682,57 @@ fn compress_history_pass(
                     Message::Assistant(a) => a.assistant_response_message.content.chars().count(),
                 })
                 .sum();
+            total_chars > max_chars
+        };
+        turns_over_limit || chars_over_limit
+    };
+
+    while needs_more_removal(state) {
+        // 可移除区间：[preserve_count, history.len() - 2)，按 (user, assistant) 成对排列
+        let removable_pairs = (state.history.len() - 2 - preserve_count) / 2;
+        if removable_pairs == 0 {
+            break;
+        }
 
-            if total_chars <= max_chars || state.history.len() <= preserve_count + 2 {
-                break;
+        let mut best_pair_index: Option<usize> = None;
+        let mut best_score = f64::INFINITY;
+
+        for pair in 0..removable_pairs {
+            let idx = preserve_count + pair * 2;
+            if pair_is_pinned(&state.history[idx], &state.history[idx + 1], pinned) {
+                continue;
             }
 
-            bytes_saved += msg_bytes(&state.history[preserve_count]);
-            state.history.remove(preserve_count);
-            bytes_saved += msg_bytes(&state.history[preserve_count]);
-            state.history.remove(preserve_count);
-            removed += 1;
+            let mut pair_tokens = std::collections::HashSet::new();
+            for msg in &state.history[idx..idx + 2] {
+                let text = match msg {
+                    Message::User(u) => &u.user_input_message.content,
+                    Message::Assistant(a) => &a.assistant_response_message.content,
+                };
+                pair_tokens.extend(tokenize_for_relevance(text));
+            }
+
+            // 越靠后（越新）的轮次 recency bonus 越大，用于打破相关性相同的平局
+            let recency_bonus = pair as f64 * 1e-6;
+            let score = jaccard_similarity(&current_tokens, &pair_tokens) + recency_bonus;
+
+            if score < best_score {
+                best_score = score;
+                best_pair_index = Some(pair);
+            }
         }
+
+        // 可移除区间内的轮次全部被 pin 保护，无法继续移除
+        let Some(best_pair_index) = best_pair_index else {
+            break;
+        };
+
+        let idx = preserve_count + best_pair_index * 2;
+        bytes_saved += message_content_bytes(&state.history[idx]);
+        state.history.remove(idx);
+        bytes_saved += message_content_bytes(&state.history[idx]);
+        state.history.remove(idx);
+        removed += 1;
     }
 
     (removed, bytes_saved)
@@ -560,6 +1820,128 @@ fn repair_tool_pairing_pass(state: &mut ConversationState) -> (usize, usize) {
     (removed_tool_uses, removed_tool_results)
 }
 
+// ============ 按字节预算自适应压缩 ============
+
+/// Kiro 上游请求体大小限制（约 400KB），`compress_to_budget` 的默认目标
+pub const DEFAULT_BUDGET_BYTES: usize = 400 * 1024;
+
+/// `compress_to_budget` 升级截断轮次的起始/下限 `max_chars`
+const ESCALATION_START_CHARS: usize = 16_384;
+const ESCALATION_FLOOR_CHARS: usize = 256;
+
+/// `compress_to_budget` 的结果
+#[derive(Debug)]
+pub struct BudgetCompressionResult {
+    pub stats: CompressionStats,
+    /// 压缩结束后、序列化为上游 JSON 形式的字节数
+    pub final_size_bytes: usize,
+    /// 是否在 `target_bytes` 预算内
+    pub budget_met: bool,
+}
+
+/// 按字节预算执行自适应压缩
+///
+/// 与 `compress` 的区别：`compress` 无条件跑完所有启用的层；这里先测量序列化
+/// 后的实际大小，一旦低于 `target_bytes` 就立即停止，避免对已经足够小的请求
+/// 做不必要的压缩。若按 `compress` 的低风险→高风险顺序逐层压缩后仍超预算，则
+/// 升级为反复调用 `compress_long_messages_pass`，`max_chars` 每轮减半，直到达到
+/// 预算或触及下限 `ESCALATION_FLOOR_CHARS`；最后统一执行一次
+/// `repair_tool_pairing_pass`，修复历史截断可能破坏的 tool_use/tool_result 配对。
+pub fn compress_to_budget(
+    state: &mut ConversationState,
+    config: &CompressionConfig,
+    target_bytes: usize,
+) -> BudgetCompressionResult {
+    let mut stats = CompressionStats::default();
+    let mut size = serialized_size(state);
+
+    if size <= target_bytes || !config.enabled {
+        return BudgetCompressionResult {
+            stats,
+            final_size_bytes: size,
+            budget_met: size <= target_bytes,
+        };
+    }
+
+    let pinned: std::collections::HashSet<&str> =
+        config.pinned_ids.iter().map(|s| s.as_str()).collect();
+
+    // 0.5 剪除空白/占位符且无工具负载的历史消息（无损，先于其他层执行）
+    stats.empty_messages_removed = prune_empty_pass(state);
+    size = serialized_size(state);
+
+    // 1. 空白压缩
+    if size > target_bytes && config.whitespace_compression {
+        stats.whitespace_saved = compress_whitespace_pass(state);
+        size = serialized_size(state);
+    }
+
+    // 2. thinking 丢弃/截断
+    if size > target_bytes && config.thinking_strategy != "keep" {
+        stats.thinking_saved = compress_thinking_pass(state, &config.thinking_strategy);
+        size = serialized_size(state);
+    }
+
+    // 3. tool_result 智能截断
+    if size > target_bytes && config.tool_result_max_chars > 0 {
+        stats.tool_result_saved = compress_tool_results_pass(
+            state,
+            config.tool_result_max_chars,
+            config.tool_result_head_lines,
+            config.tool_result_tail_lines,
+            &pinned,
+        );
+        size = serialized_size(state);
+    }
+
+    // 4. tool_use input 截断
+    if size > target_bytes && config.tool_use_input_max_chars > 0 {
+        stats.tool_use_input_saved =
+            compress_tool_use_inputs_pass(state, config.tool_use_input_max_chars, &pinned);
+        size = serialized_size(state);
+    }
+
+    // 5. 历史截断
+    if size > target_bytes && (config.max_history_turns > 0 || config.max_history_chars > 0) {
+        let outcome = run_history_pass(state, config, None, &pinned);
+        stats.history_turns_removed = outcome.turns_removed;
+        stats.history_bytes_saved = outcome.bytes_saved;
+        stats.history_turns_summarized = outcome.turns_summarized;
+        stats.summary_chars = outcome.summary_chars;
+        size = serialized_size(state);
+    }
+
+    // 升级：反复截断超长消息内容，max_chars 逐轮减半，直到达到预算或触及下限
+    let mut max_chars = ESCALATION_START_CHARS;
+    while size > target_bytes && max_chars >= ESCALATION_FLOOR_CHARS {
+        let saved = compress_long_messages_pass(state, max_chars, &pinned);
+        stats.history_bytes_saved += saved;
+        size = serialized_size(state);
+        max_chars /= 2;
+    }
+
+    let (removed_tool_uses, removed_tool_results) = repair_tool_pairing_pass(state);
+    if removed_tool_uses > 0 || removed_tool_results > 0 {
+        tracing::debug!(
+            removed_tool_uses,
+            removed_tool_results,
+            "按预算压缩后已修复 tool_use/tool_result 配对"
+        );
+    }
+    size = serialized_size(state);
+
+    BudgetCompressionResult {
+        stats,
+        final_size_bytes: size,
+        budget_met: size <= target_bytes,
+    }
+}
+
+/// 序列化 `ConversationState` 为上游 JSON 形式并测量字节数
+fn serialized_size(state: &ConversationState) -> usize {
+    serde_json::to_vec(state).map(|v| v.len()).unwrap_or(usize::MAX)
+}
+
 // ============ 超长消息内容截断 ============
 
 /// 截断超长的用户消息内容（history user messages 和 current_message）
@@ -567,8 +1949,14 @@ fn repair_tool_pairing_pass(state: &mut ConversationState) -> (usize, usize) {
 /// 这是最后手段的压缩层，仅在自适应二次压缩中使用。
 /// 截断策略：保留头部内容，尾部截断并附加省略标记。
 ///
+/// 携带 `pinned` 中 tool_use_id 对应 tool_result 的消息会被跳过，内容原样保留。
+///
 /// 返回节省的字节数。
-pub fn compress_long_messages_pass(state: &mut ConversationState, max_chars: usize) -> usize {
+pub fn compress_long_messages_pass(
+    state: &mut ConversationState,
+    max_chars: usize,
+    pinned: &std::collections::HashSet<&str>,
+) -> usize {
     if max_chars == 0 {
         return 0;
     }
@@ -578,15 +1966,33 @@ pub fn compress_long_messages_pass(state: &mut ConversationState, max_chars: usi
     // 遍历 history 中所有 User 消息
     for msg in &mut state.history {
         if let Message::User(user_msg) = msg {
+            let has_pinned_result = user_msg
+                .user_input_message
+                .user_input_message_context
+                .tool_results
+                .iter()
+                .any(|tr| pinned.contains(tr.tool_use_id.as_str()));
+            if has_pinned_result {
+                continue;
+            }
             saved += truncate_long_content(&mut user_msg.user_input_message.content, max_chars);
         }
     }
 
     // 处理 current_message
-    saved += truncate_long_content(
-        &mut state.current_message.user_input_message.content,
-        max_chars,
-    );
+    let current_has_pinned_result = state
+        .current_message
+        .user_input_message
+        .user_input_message_context
+        .tool_results
+        .iter()
+        .any(|tr| pinned.contains(tr.tool_use_id.as_str()));
+    if !current_has_pinned_result {
+        saved += truncate_long_content(
+            &mut state.current_message.user_input_message.content,
+            max_chars,
+        );
+    }
 
     saved
 }
@@ -594,7 +2000,7 @@ pub fn compress_long_messages_pass(state: &mut ConversationState, max_chars: usi
 /// 截断单个 content 字段，返回节省的字节数
 ///
 /// 跳过仅为空格占位符 " " 的字段（与 compress_string_field 一致）
-fn truncate_long_content(field: &mut String, max_chars: usize) -> usize {
+pub(crate) fn truncate_long_content(field: &mut String, max_chars: usize) -> usize {
     if field == " " {
         return 0;
     }
@@ -980,6 +2386,183 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pinned_tool_use_id_protects_turn_from_history_truncation() {
+        // system pair + 一个普通轮次 + pinned 轮次(tool_use/tool_result 跨两对消息)；
+        // 最近一轮（末尾 2 条）始终保留，因此普通轮次放在 pinned 轮次之前
+        let tool_use_id = "tooluse_pin";
+
+        let system_user = Message::User(HistoryUserMessage::new("system", "claude-sonnet-4.5"));
+        let system_assistant = Message::Assistant(HistoryAssistantMessage::new(
+            "I will follow these instructions.",
+        ));
+
+        let user1 = Message::User(HistoryUserMessage::new("read the spec", "claude-sonnet-4.5"));
+        let tool_use =
+            ToolUseEntry::new(tool_use_id, "Read").with_input(serde_json::json!({"path": "spec.md"}));
+        let assistant1 = Message::Assistant(HistoryAssistantMessage {
+            assistant_response_message: AssistantMessage::new(" ").with_tool_uses(vec![tool_use]),
+        });
+
+        let tool_result_ctx = UserInputMessageContext::new()
+            .with_tool_results(vec![ToolResult::success(tool_use_id, "spec contents")]);
+        let user2 = Message::User(HistoryUserMessage {
+            user_input_message: UserMessage::new(" ", "claude-sonnet-4.5")
+                .with_context(tool_result_ctx),
+        });
+        let assistant2 = Message::Assistant(HistoryAssistantMessage::new("got it"));
+
+        let user3 = Message::User(HistoryUserMessage::new("unrelated chit-chat", "claude-sonnet-4.5"));
+        let assistant3 = Message::Assistant(HistoryAssistantMessage::new("ok"));
+
+        let mut state = ConversationState::new("test")
+            .with_current_message(CurrentMessage::new(UserInputMessage::new(
+                "next",
+                "claude-sonnet-4.5",
+            )))
+            .with_history(vec![
+                system_user,
+                system_assistant,
+                user3,
+                assistant3,
+                user1,
+                assistant1,
+                user2,
+                assistant2,
+            ]);
+
+        let config = CompressionConfig {
+            max_history_turns: 1,
+            max_history_chars: 0,
+            pinned_ids: vec![tool_use_id.to_string()],
+            ..Default::default()
+        };
+
+        let stats = compress(&mut state, &config);
+
+        // 被 pin 保护的两轮（tool_use 轮 + tool_result 轮）无法被移除，只能移除普通轮次
+        assert_eq!(stats.history_turns_removed, 1);
+        assert_eq!(state.history.len(), 6);
+
+        let still_has_tool_use = state.history.iter().any(|msg| {
+            matches!(msg, Message::Assistant(a) if a
+                .assistant_response_message
+                .tool_uses
+                .as_ref()
+                .is_some_and(|tus| tus.iter().any(|tu| tu.tool_use_id == tool_use_id)))
+        });
+        assert!(still_has_tool_use, "被 pin 的 tool_use 轮次不应被移除");
+
+        let still_has_unrelated = state.history.iter().any(|msg| {
+            matches!(msg, Message::User(u) if u.user_input_message.content == "unrelated chit-chat")
+        });
+        assert!(!still_has_unrelated, "未被 pin 的普通轮次应被优先移除");
+    }
+
+    #[test]
+    fn test_prune_empty_pass_removes_placeholder_without_tool_payload() {
+        let empty_user = Message::User(HistoryUserMessage::new(" ", "claude-sonnet-4.5"));
+        let empty_assistant = Message::Assistant(HistoryAssistantMessage::new("  \n "));
+        let real_user = Message::User(HistoryUserMessage::new("do something", "claude-sonnet-4.5"));
+        let real_assistant = Message::Assistant(HistoryAssistantMessage::new("done"));
+
+        let mut state = ConversationState::new("test")
+            .with_current_message(CurrentMessage::new(UserInputMessage::new(
+                "next",
+                "claude-sonnet-4.5",
+            )))
+            .with_history(vec![
+                empty_user,
+                empty_assistant,
+                real_user,
+                real_assistant,
+            ]);
+
+        let removed = prune_empty_pass(&mut state);
+        assert_eq!(removed, 2);
+        assert_eq!(state.history.len(), 2);
+        if let Message::User(u) = &state.history[0] {
+            assert_eq!(u.user_input_message.content, "do something");
+        } else {
+            panic!("expected the surviving real user message");
+        }
+    }
+
+    #[test]
+    fn test_prune_empty_pass_keeps_empty_content_with_tool_payload() {
+        let tool_use =
+            ToolUseEntry::new("tooluse_1", "Read").with_input(serde_json::json!({"path": "a.txt"}));
+        let assistant_with_tool_use = Message::Assistant(HistoryAssistantMessage {
+            assistant_response_message: AssistantMessage::new(" ").with_tool_uses(vec![tool_use]),
+        });
+
+        let tool_result_ctx = UserInputMessageContext::new()
+            .with_tool_results(vec![ToolResult::success("tooluse_1", "ok")]);
+        let user_with_tool_result = Message::User(HistoryUserMessage {
+            user_input_message: UserMessage::new(" ", "claude-sonnet-4.5")
+                .with_context(tool_result_ctx),
+        });
+
+        let mut state = ConversationState::new("test")
+            .with_current_message(CurrentMessage::new(UserInputMessage::new(
+                "next",
+                "claude-sonnet-4.5",
+            )))
+            .with_history(vec![
+                Message::User(HistoryUserMessage::new("hi", "claude-sonnet-4.5")),
+                assistant_with_tool_use,
+                user_with_tool_result,
+                Message::Assistant(HistoryAssistantMessage::new("done")),
+            ]);
+
+        let removed = prune_empty_pass(&mut state);
+        assert_eq!(removed, 0);
+        assert_eq!(state.history.len(), 4);
+    }
+
+    #[test]
+    fn test_inject_ambient_context_filters_empty_providers() {
+        struct Provider(Option<&'static str>);
+        impl AmbientContextProvider for Provider {
+            fn provide(&self) -> Option<String> {
+                self.0.map(|s| s.to_string())
+            }
+        }
+
+        let providers: Vec<Box<dyn AmbientContextProvider>> = vec![
+            Box::new(Provider(Some("project: kiro.rs-plus"))),
+            Box::new(Provider(Some("   "))),
+            Box::new(Provider(None)),
+        ];
+        let provider_refs: Vec<&dyn AmbientContextProvider> =
+            providers.iter().map(|p| p.as_ref()).collect();
+
+        let mut state = make_simple_state(vec![], "what's next?");
+        let injected = inject_ambient_context(&mut state, &provider_refs);
+
+        assert!(injected > 0);
+        let content = &state.current_message.user_input_message.content;
+        assert!(content.starts_with("project: kiro.rs-plus"));
+        assert!(content.ends_with("what's next?"));
+        // 空白/None provider 不应产生额外的空行注入
+        assert!(!content.contains("   \n\n"));
+    }
+
+    #[test]
+    fn test_inject_ambient_context_noop_when_all_providers_empty() {
+        struct EmptyProvider;
+        impl AmbientContextProvider for EmptyProvider {
+            fn provide(&self) -> Option<String> {
+                None
+            }
+        }
+
+        let mut state = make_simple_state(vec![], "unchanged");
+        let injected = inject_ambient_context(&mut state, &[&EmptyProvider]);
+        assert_eq!(injected, 0);
+        assert_eq!(state.current_message.user_input_message.content, "unchanged");
+    }
+
     #[test]
     fn test_compress_disabled_no_change() {
         let content = "line1\n\n\n\n\nline2   ";
@@ -1003,7 +2586,7 @@ mod tests {
     fn test_compress_long_messages_truncates_current_message() {
         let long_content = "a".repeat(20000);
         let mut state = make_simple_state(vec![], &long_content);
-        let saved = compress_long_messages_pass(&mut state, 8192);
+        let saved = compress_long_messages_pass(&mut state, 8192, &std::collections::HashSet::new());
         assert!(saved > 0);
         let content = &state.current_message.user_input_message.content;
         assert!(content.len() < long_content.len());
@@ -1017,7 +2600,7 @@ mod tests {
     fn test_compress_long_messages_truncates_history_user() {
         let long_content = "b".repeat(20000);
         let mut state = make_simple_state(vec![(&long_content, "short reply")], "current");
-        let saved = compress_long_messages_pass(&mut state, 8192);
+        let saved = compress_long_messages_pass(&mut state, 8192, &std::collections::HashSet::new());
         assert!(saved > 0);
         if let Message::User(u) = &state.history[0] {
             assert!(u.user_input_message.content.len() < long_content.len());
@@ -1030,7 +2613,7 @@ mod tests {
     #[test]
     fn test_compress_long_messages_short_unchanged() {
         let mut state = make_simple_state(vec![("short user", "short assistant")], "short current");
-        let saved = compress_long_messages_pass(&mut state, 8192);
+        let saved = compress_long_messages_pass(&mut state, 8192, &std::collections::HashSet::new());
         assert_eq!(saved, 0);
         assert_eq!(
             state.current_message.user_input_message.content,
@@ -1044,7 +2627,7 @@ mod tests {
     #[test]
     fn test_compress_long_messages_skips_placeholder() {
         let mut state = make_simple_state(vec![], " ");
-        let saved = compress_long_messages_pass(&mut state, 1);
+        let saved = compress_long_messages_pass(&mut state, 1, &std::collections::HashSet::new());
         assert_eq!(saved, 0);
         assert_eq!(state.current_message.user_input_message.content, " ");
     }
@@ -1053,11 +2636,26 @@ mod tests {
     fn test_compress_long_messages_zero_max_chars_noop() {
         let long_content = "x".repeat(20000);
         let mut state = make_simple_state(vec![], &long_content);
-        let saved = compress_long_messages_pass(&mut state, 0);
+        let saved = compress_long_messages_pass(&mut state, 0, &std::collections::HashSet::new());
         assert_eq!(saved, 0);
         assert_eq!(
             state.current_message.user_input_message.content,
             long_content
         );
     }
+
+    #[test]
+    fn test_compress_request_returns_serialized_body_and_stats() {
+        let mut state = make_simple_state(
+            vec![("hello   \n\n\n\nworld", "reply   ")],
+            "current   message",
+        );
+        let config = CompressionConfig::default();
+        let result = compress_request(&mut state, &config);
+
+        assert!(result.stats.whitespace_saved > 0);
+        // body 应该是压缩后 state 的有效 JSON 序列化结果
+        let parsed: serde_json::Value = serde_json::from_str(&result.body).unwrap();
+        assert!(parsed.get("currentMessage").is_some() || parsed.get("current_message").is_some());
+    }
 }
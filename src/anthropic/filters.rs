@@ -0,0 +1,474 @@
+//! 可插拔请求/事件过滤器管道
+//!
+//! 压缩、脱敏这类"定死"的逻辑散落在 `compressor.rs`/`handlers.rs` 里，用户
+//! 想加一条自定义的脱敏规则或审计逻辑，只能直接改这些文件。这里提供一个
+//! 轻量扩展点：`RequestFilter` 在 `convert_request` 完成之后、请求体
+//! `serde_json::to_string` 之前对 `KiroRequest` 做就地修改；`EventFilter`
+//! 在流式/缓冲响应路径上对每个解析出的 `Event` 做就地修改。过滤器按注册
+//! 顺序依次执行，注册在 `FilterPipeline` 上后随 `AppState` clone 共享，
+//! 调用方无需为每个请求重新构造过滤器列表。
+//!
+//! 这层过滤器管道与 `compressor.rs` 里既有的压缩/脱敏 pass 并存，不取代
+//! 它们：后者仍在 `convert_request` 内部按 `CompressionConfig` 自动执行，
+//! 这里只是在其后再开放一个可插拔的扩展点，便于接入既有配置覆盖不到的
+//! 自定义逻辑（第三方审计规则、额外的系统提示注入等）。
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use regex::Regex;
+
+use crate::kiro::model::events::Event;
+use crate::kiro::model::requests::conversation::Message;
+use crate::kiro::model::requests::kiro::KiroRequest;
+
+/// 请求过滤器：在请求转换完成、序列化为上游请求体之前对 `KiroRequest` 做就地修改
+pub trait RequestFilter: Send + Sync {
+    /// 过滤器名称，仅用于日志
+    fn name(&self) -> &str;
+    fn on_request(&self, req: &mut KiroRequest) -> Result<()>;
+}
+
+/// 事件过滤器：在流式/缓冲响应路径上对每个解析出的 `Event` 做就地修改
+pub trait EventFilter: Send + Sync {
+    /// 过滤器名称，仅用于日志
+    fn name(&self) -> &str;
+    fn on_event(&self, ev: &mut Event);
+}
+
+/// 按注册顺序依次执行的请求/事件过滤器管道，随 `AppState` clone 共享
+#[derive(Clone, Default)]
+pub struct FilterPipeline {
+    request_filters: Arc<Vec<Arc<dyn RequestFilter>>>,
+    event_filters: Arc<Vec<Arc<dyn EventFilter>>>,
+}
+
+impl FilterPipeline {
+    pub fn new(
+        request_filters: Vec<Arc<dyn RequestFilter>>,
+        event_filters: Vec<Arc<dyn EventFilter>>,
+    ) -> Self {
+        Self {
+            request_filters: Arc::new(request_filters),
+            event_filters: Arc::new(event_filters),
+        }
+    }
+
+    /// 未注册任何过滤器的空管道（默认行为，等价于过滤器功能关闭）
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// 依次执行所有请求过滤器；某个过滤器出错时记录日志并中止后续过滤器，
+    /// 但不影响请求本身继续处理——过滤器是可选的增强逻辑，不应成为新的故障点
+    pub fn run_request_filters(&self, req: &mut KiroRequest) {
+        for filter in self.request_filters.iter() {
+            if let Err(e) = filter.on_request(req) {
+                tracing::warn!(filter = filter.name(), error = %e, "请求过滤器执行失败，已跳过剩余过滤器");
+                break;
+            }
+        }
+    }
+
+    /// 依次执行所有事件过滤器
+    pub fn run_event_filters(&self, ev: &mut Event) {
+        for filter in self.event_filters.iter() {
+            filter.on_event(ev);
+        }
+    }
+}
+
+impl std::fmt::Debug for FilterPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FilterPipeline")
+            .field(
+                "request_filters",
+                &self.request_filters.iter().map(|f| f.name()).collect::<Vec<_>>(),
+            )
+            .field(
+                "event_filters",
+                &self.event_filters.iter().map(|f| f.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// 内置过滤器：凭据/密钥脱敏
+///
+/// 对请求中用户输入、历史消息文本，以及流式响应里的助手回复文本按正则
+/// 匹配替换为占位符。无法编译的正则会被跳过并记录告警日志，与
+/// `compressor::compress_redaction_pass` 的容错方式一致。
+pub struct CredentialRedactionFilter {
+    patterns: Vec<Regex>,
+    marker: String,
+}
+
+impl CredentialRedactionFilter {
+    pub fn new(patterns: &[String], marker: impl Into<String>) -> Self {
+        let compiled = patterns
+            .iter()
+            .filter_map(|p| match Regex::new(p) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    tracing::warn!(pattern = %p, error = %e, "脱敏正则编译失败，已跳过");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            patterns: compiled,
+            marker: marker.into(),
+        }
+    }
+
+    fn redact(&self, text: &mut String) {
+        if self.patterns.is_empty() {
+            return;
+        }
+        for re in &self.patterns {
+            if re.is_match(text) {
+                *text = re.replace_all(text, self.marker.as_str()).into_owned();
+            }
+        }
+    }
+}
+
+impl RequestFilter for CredentialRedactionFilter {
+    fn name(&self) -> &str {
+        "credential_redaction"
+    }
+
+    fn on_request(&self, req: &mut KiroRequest) -> Result<()> {
+        self.redact(&mut req.conversation_state.current_message.user_input_message.content);
+        for msg in &mut req.conversation_state.history {
+            match msg {
+                Message::User(u) => self.redact(&mut u.user_input_message.content),
+                Message::Assistant(a) => self.redact(&mut a.assistant_response_message.content),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EventFilter for CredentialRedactionFilter {
+    fn name(&self) -> &str {
+        "credential_redaction"
+    }
+
+    fn on_event(&self, ev: &mut Event) {
+        if let Event::AssistantResponse(resp) = ev {
+            self.redact(&mut resp.content);
+        }
+    }
+}
+
+/// 内置过滤器：系统提示注入
+///
+/// 把固定的前置指令拼接在 `current_message` 用户输入之前（仓库里
+/// `KiroRequest` 没有独立的 system 字段，约定与 Kiro 上游协议一致，把
+/// 系统级指令当作当前消息的前缀），用于统一下发安全/合规提示而无需
+/// 客户端每次请求都携带。
+pub struct SystemPromptInjectionFilter {
+    preamble: String,
+}
+
+impl SystemPromptInjectionFilter {
+    pub fn new(preamble: impl Into<String>) -> Self {
+        Self {
+            preamble: preamble.into(),
+        }
+    }
+}
+
+impl RequestFilter for SystemPromptInjectionFilter {
+    fn name(&self) -> &str {
+        "system_prompt_injection"
+    }
+
+    fn on_request(&self, req: &mut KiroRequest) -> Result<()> {
+        if self.preamble.is_empty() {
+            return Ok(());
+        }
+        let content = &mut req.conversation_state.current_message.user_input_message.content;
+        *content = format!("{}\n\n{}", self.preamble, content);
+        Ok(())
+    }
+}
+
+/// 内置过滤器：剥离图片
+///
+/// 清空 `current_message` 与历史用户消息中的图片，仅保留文本内容。
+/// 用于部分凭据/模型不支持图片输入，或出于带宽/隐私考虑要求纯文本转发的场景。
+pub struct ImageStrippingFilter;
+
+impl RequestFilter for ImageStrippingFilter {
+    fn name(&self) -> &str {
+        "image_stripping"
+    }
+
+    fn on_request(&self, req: &mut KiroRequest) -> Result<()> {
+        req.conversation_state
+            .current_message
+            .user_input_message
+            .images
+            .clear();
+        for msg in &mut req.conversation_state.history {
+            if let Message::User(u) = msg {
+                u.user_input_message.images.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kiro::model::requests::conversation::{
+        ConversationState, CurrentMessage, HistoryAssistantMessage, HistoryUserMessage, UserInputMessage,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn make_request(current: &str, history: Vec<Message>) -> KiroRequest {
+        let state = ConversationState::new("test")
+            .with_current_message(CurrentMessage::new(UserInputMessage::new(
+                current,
+                "claude-sonnet-4.5",
+            )))
+            .with_history(history);
+        KiroRequest {
+            conversation_state: state,
+            profile_arn: None,
+        }
+    }
+
+    // ===== CredentialRedactionFilter =====
+
+    #[test]
+    fn test_credential_redaction_replaces_match_in_current_and_history() {
+        let filter = CredentialRedactionFilter::new(&[r"sk-[a-zA-Z0-9]+".to_string()], "[REDACTED]");
+        let mut req = make_request(
+            "my key is sk-abc123",
+            vec![
+                Message::User(HistoryUserMessage::new("old key sk-def456", "claude-sonnet-4.5")),
+                Message::Assistant(HistoryAssistantMessage::new("here is sk-ghi789 for you")),
+            ],
+        );
+
+        filter.on_request(&mut req).unwrap();
+
+        assert_eq!(
+            req.conversation_state.current_message.user_input_message.content,
+            "my key is [REDACTED]"
+        );
+        match &req.conversation_state.history[0] {
+            Message::User(u) => assert_eq!(u.user_input_message.content, "old key [REDACTED]"),
+            _ => panic!("expected user message"),
+        }
+        match &req.conversation_state.history[1] {
+            Message::Assistant(a) => {
+                assert_eq!(a.assistant_response_message.content, "here is [REDACTED] for you")
+            }
+            _ => panic!("expected assistant message"),
+        }
+    }
+
+    #[test]
+    fn test_credential_redaction_no_patterns_is_noop() {
+        let filter = CredentialRedactionFilter::new(&[], "[REDACTED]");
+        let mut req = make_request("my key is sk-abc123", Vec::new());
+
+        filter.on_request(&mut req).unwrap();
+
+        assert_eq!(
+            req.conversation_state.current_message.user_input_message.content,
+            "my key is sk-abc123"
+        );
+    }
+
+    #[test]
+    fn test_credential_redaction_invalid_pattern_is_skipped() {
+        // 非法正则（未闭合的括号）应被跳过，而不是导致 panic 或构造失败
+        let filter = CredentialRedactionFilter::new(&["(unclosed".to_string()], "[REDACTED]");
+        let mut req = make_request("unchanged content", Vec::new());
+
+        filter.on_request(&mut req).unwrap();
+
+        assert_eq!(
+            req.conversation_state.current_message.user_input_message.content,
+            "unchanged content"
+        );
+    }
+
+    #[test]
+    fn test_credential_redaction_on_event_replaces_assistant_response() {
+        let filter = CredentialRedactionFilter::new(&[r"sk-[a-zA-Z0-9]+".to_string()], "[REDACTED]");
+        let mut ev = Event::AssistantResponse(Default::default());
+        if let Event::AssistantResponse(resp) = &mut ev {
+            resp.content = "call me at sk-abc123".to_string();
+        }
+
+        filter.on_event(&mut ev);
+
+        match ev {
+            Event::AssistantResponse(resp) => assert_eq!(resp.content, "call me at [REDACTED]"),
+            _ => panic!("expected assistant response event"),
+        }
+    }
+
+    // ===== SystemPromptInjectionFilter =====
+
+    #[test]
+    fn test_system_prompt_injection_prepends_preamble() {
+        let filter = SystemPromptInjectionFilter::new("Follow the safety policy.");
+        let mut req = make_request("help me write a function", Vec::new());
+
+        filter.on_request(&mut req).unwrap();
+
+        assert_eq!(
+            req.conversation_state.current_message.user_input_message.content,
+            "Follow the safety policy.\n\nhelp me write a function"
+        );
+    }
+
+    #[test]
+    fn test_system_prompt_injection_empty_preamble_is_noop() {
+        let filter = SystemPromptInjectionFilter::new("");
+        let mut req = make_request("help me write a function", Vec::new());
+
+        filter.on_request(&mut req).unwrap();
+
+        assert_eq!(
+            req.conversation_state.current_message.user_input_message.content,
+            "help me write a function"
+        );
+    }
+
+    // ===== ImageStrippingFilter =====
+
+    #[test]
+    fn test_image_stripping_clears_current_and_history_images() {
+        let filter = ImageStrippingFilter;
+        let mut req = make_request("look at this", Vec::new());
+        req.conversation_state
+            .current_message
+            .user_input_message
+            .images
+            .push(Default::default());
+
+        let mut history_user = HistoryUserMessage::new("look at this too", "claude-sonnet-4.5");
+        history_user.user_input_message.images.push(Default::default());
+        req.conversation_state
+            .history
+            .push(Message::User(history_user));
+        req.conversation_state
+            .history
+            .push(Message::Assistant(HistoryAssistantMessage::new("sure")));
+
+        filter.on_request(&mut req).unwrap();
+
+        assert!(req
+            .conversation_state
+            .current_message
+            .user_input_message
+            .images
+            .is_empty());
+        match &req.conversation_state.history[0] {
+            Message::User(u) => assert!(u.user_input_message.images.is_empty()),
+            _ => panic!("expected user message"),
+        }
+    }
+
+    #[test]
+    fn test_image_stripping_already_empty_is_noop() {
+        let filter = ImageStrippingFilter;
+        let mut req = make_request("no images here", Vec::new());
+
+        filter.on_request(&mut req).unwrap();
+
+        assert!(req
+            .conversation_state
+            .current_message
+            .user_input_message
+            .images
+            .is_empty());
+    }
+
+    // ===== FilterPipeline =====
+
+    struct FailingFilter;
+
+    impl RequestFilter for FailingFilter {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn on_request(&self, _req: &mut KiroRequest) -> Result<()> {
+            anyhow::bail!("boom")
+        }
+    }
+
+    struct CountingFilter {
+        calls: AtomicUsize,
+    }
+
+    impl RequestFilter for CountingFilter {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn on_request(&self, _req: &mut KiroRequest) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    impl EventFilter for CountingFilter {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn on_event(&self, _ev: &mut Event) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_pipeline_error_short_circuits_remaining_request_filters() {
+        let counting = Arc::new(CountingFilter { calls: AtomicUsize::new(0) });
+        let pipeline = FilterPipeline::new(
+            vec![Arc::new(FailingFilter), counting.clone()],
+            Vec::new(),
+        );
+        let mut req = make_request("hello", Vec::new());
+
+        // 过滤器出错不应该 panic 或中止请求本身
+        pipeline.run_request_filters(&mut req);
+
+        assert_eq!(counting.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(req.conversation_state.current_message.user_input_message.content, "hello");
+    }
+
+    #[test]
+    fn test_pipeline_runs_all_event_filters() {
+        let counting = Arc::new(CountingFilter { calls: AtomicUsize::new(0) });
+        let pipeline = FilterPipeline::new(Vec::new(), vec![counting.clone(), counting.clone()]);
+        let mut ev = Event::AssistantResponse(Default::default());
+
+        pipeline.run_event_filters(&mut ev);
+
+        assert_eq!(counting.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_empty_pipeline_is_noop() {
+        let pipeline = FilterPipeline::empty();
+        let mut req = make_request("hello", Vec::new());
+        let mut ev = Event::AssistantResponse(Default::default());
+
+        pipeline.run_request_filters(&mut req);
+        pipeline.run_event_filters(&mut ev);
+
+        assert_eq!(req.conversation_state.current_message.user_input_message.content, "hello");
+    }
+}
@@ -2,16 +2,19 @@
 
 use std::convert::Infallible;
 
+use crate::common::embedding::TurnEmbedder;
 use crate::kiro::model::events::Event;
+use crate::kiro::model::requests::conversation::{HistoryAssistantMessage, HistoryUserMessage, Message};
 use crate::kiro::model::requests::kiro::KiroRequest;
 use crate::kiro::parser::decoder::EventStreamDecoder;
 use crate::token;
-use anyhow::Error;
+use anyhow::{Context, Error};
 use axum::{
     Json as JsonExtractor,
     body::Body,
     extract::State,
-    http::{StatusCode, header},
+    extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Json, Response},
 };
 use bytes::Bytes;
@@ -32,7 +35,9 @@ const ADAPTIVE_HISTORY_PRESERVE_MESSAGES: usize = 2;
 /// 消息内容二次压缩的最低阈值（字符数）
 const ADAPTIVE_MIN_MESSAGE_CONTENT_MAX_CHARS: usize = 8192;
 
+use super::compressor::{HeuristicSummarizer, Summarizer};
 use super::converter::{ConversionError, convert_request};
+use super::filters::FilterPipeline;
 use super::middleware::AppState;
 use super::stream::{BufferedStreamContext, SseEvent, StreamContext};
 use super::types::{
@@ -68,9 +73,17 @@ struct AdaptiveCompressionOutcome {
     final_bytes: usize,
     iters: usize,
     additional_history_turns_removed: usize,
+    /// `history_summarize = true` 时，第五层被汇总（而非直接丢弃）的轮数
+    history_turns_summarized: usize,
     final_tool_result_max_chars: usize,
     final_tool_use_input_max_chars: usize,
     final_message_content_max_chars: usize,
+    /// 图片收缩层（降像素/降质量重新编码）累计节省的字节数
+    image_bytes_saved: usize,
+    /// 质量阶梯降到下限后仍超预算、被整张剔除的历史图片数
+    images_evicted: usize,
+    /// 收缩结束时的估算 input token 数（`token_budget` 为 `None` 时保持 0，即未参与估算）
+    final_input_tokens: usize,
 }
 
 /// 计算 KiroRequest 中所有图片 base64 数据的总字节数。
@@ -99,13 +112,116 @@ fn total_image_bytes(kiro_request: &KiroRequest) -> usize {
     total
 }
 
+/// 使用 BPE 分词器估算 KiroRequest 当前文本内容的总 token 数
+///
+/// 仅统计 current_message 与历史消息的 content 字段（与 compressor.rs 的
+/// `compress_history_pass_tokens::msg_tokens` 口径一致），不含 tool_result/
+/// tool_use 的结构化内容；token 预算只需要"足够接近"，用于快速判断每轮是否已收敛。
+fn estimate_kiro_request_tokens(
+    kiro_request: &KiroRequest,
+    tokenizer: &crate::common::tokenizer::BpeTokenizer,
+) -> usize {
+    let state = &kiro_request.conversation_state;
+    let mut total = tokenizer.count(&state.current_message.user_input_message.content);
+    for msg in &state.history {
+        total += match msg {
+            Message::User(u) => tokenizer.count(&u.user_input_message.content),
+            Message::Assistant(a) => tokenizer.count(&a.assistant_response_message.content),
+        };
+    }
+    total
+}
+
+/// 把"请求体大小预检"这一结构化事件转发到诊断导出器（若已配置），
+/// 字段与对应的 `tracing` 日志保持一致，供运维集中观测压缩效果
+fn emit_size_precheck_diagnostics(
+    diagnostics: &crate::common::diagnostics::DiagnosticsExporter,
+    conversation_id: &str,
+    request_body_bytes: usize,
+    max_body: usize,
+    byte_over_budget: bool,
+    token_over_budget: bool,
+    token_budget_limit: Option<usize>,
+) {
+    let mut fields = serde_json::Map::new();
+    fields.insert("conversation_id".to_string(), json!(conversation_id));
+    fields.insert("request_body_bytes".to_string(), json!(request_body_bytes));
+    fields.insert("threshold".to_string(), json!(max_body));
+    fields.insert("byte_over_budget".to_string(), json!(byte_over_budget));
+    fields.insert("token_over_budget".to_string(), json!(token_over_budget));
+    fields.insert("token_budget".to_string(), json!(token_budget_limit));
+    diagnostics.record("request_size_precheck", fields);
+}
+
+/// 把"自适应二次压缩结果"这一结构化事件转发到诊断导出器（若已配置），
+/// 字段与对应的 `tracing::warn!` 日志保持一致
+fn emit_adaptive_shrink_diagnostics(
+    diagnostics: &crate::common::diagnostics::DiagnosticsExporter,
+    conversation_id: &str,
+    outcome: &AdaptiveCompressionOutcome,
+    max_body: usize,
+    token_budget_limit: Option<usize>,
+) {
+    let mut fields = serde_json::Map::new();
+    fields.insert("conversation_id".to_string(), json!(conversation_id));
+    fields.insert("initial_bytes".to_string(), json!(outcome.initial_bytes));
+    fields.insert("final_bytes".to_string(), json!(outcome.final_bytes));
+    fields.insert("threshold".to_string(), json!(max_body));
+    fields.insert("iters".to_string(), json!(outcome.iters));
+    fields.insert(
+        "additional_history_turns_removed".to_string(),
+        json!(outcome.additional_history_turns_removed),
+    );
+    fields.insert(
+        "final_tool_result_max_chars".to_string(),
+        json!(outcome.final_tool_result_max_chars),
+    );
+    fields.insert(
+        "final_tool_use_input_max_chars".to_string(),
+        json!(outcome.final_tool_use_input_max_chars),
+    );
+    fields.insert(
+        "final_message_content_max_chars".to_string(),
+        json!(outcome.final_message_content_max_chars),
+    );
+    fields.insert("token_budget".to_string(), json!(token_budget_limit));
+    fields.insert("final_input_tokens".to_string(), json!(outcome.final_input_tokens));
+    diagnostics.record("adaptive_shrink_outcome", fields);
+}
+
+/// 把"请求体超过安全阈值，拒绝发送"这一结构化事件转发到诊断导出器
+/// （若已配置），字段与对应的 `tracing::warn!` 日志保持一致
+fn emit_request_rejected_diagnostics(
+    diagnostics: &crate::common::diagnostics::DiagnosticsExporter,
+    conversation_id: &str,
+    request_body_bytes: usize,
+    image_bytes: usize,
+    effective_bytes: usize,
+    max_body: usize,
+) {
+    let mut fields = serde_json::Map::new();
+    fields.insert("conversation_id".to_string(), json!(conversation_id));
+    fields.insert("request_body_bytes".to_string(), json!(request_body_bytes));
+    fields.insert("image_bytes".to_string(), json!(image_bytes));
+    fields.insert("effective_bytes".to_string(), json!(effective_bytes));
+    fields.insert("threshold".to_string(), json!(max_body));
+    fields.insert("reason".to_string(), json!("too_large"));
+    diagnostics.record("request_rejected", fields);
+}
+
 fn adaptive_shrink_request_body(
     kiro_request: &mut KiroRequest,
     base_config: &crate::model::config::CompressionConfig,
     max_body: usize,
     request_body: &mut String,
+    embedder: Option<&dyn TurnEmbedder>,
+    token_budget: Option<(usize, &crate::common::tokenizer::BpeTokenizer)>,
 ) -> Result<Option<AdaptiveCompressionOutcome>, serde_json::Error> {
-    if max_body == 0 || request_body.len() <= max_body || !base_config.enabled {
+    let byte_over_budget = max_body > 0 && request_body.len() > max_body;
+    let token_over_budget = token_budget.is_some_and(|(budget, tokenizer)| {
+        estimate_kiro_request_tokens(kiro_request, tokenizer) > budget
+    });
+    if (!byte_over_budget && !token_over_budget) || !base_config.enabled {
         return Ok(None);
     }
 
@@ -114,16 +230,23 @@ fn adaptive_shrink_request_body(
         final_bytes: request_body.len(),
         iters: 0,
         additional_history_turns_removed: 0,
+        history_turns_summarized: 0,
         final_tool_result_max_chars: base_config.tool_result_max_chars,
         final_tool_use_input_max_chars: base_config.tool_use_input_max_chars,
         final_message_content_max_chars: 0,
+        image_bytes_saved: 0,
+        images_evicted: 0,
+        final_input_tokens: 0,
     };
 
     // 二次压缩策略：
     // 1) 逐步降低 tool_result_max_chars（仅当存在 tool_result/tools）
     // 2) 逐步降低 tool_use_input_max_chars（仅当存在 tool_use）
-    // 3) 截断超长用户消息内容（当单条消息已超过阈值时优先）
-    // 4) 按 request_body_bytes 成对移除最老的 user+assistant 两条消息（保留前 2 条）
+    // 3) 图片收缩：按像素预算缩小 + 质量阶梯重新编码（仅当存在图片），质量阶梯
+    //    耗尽后若 `image_evict_history` 开启则整张剔除最旧的历史图片
+    // 4) 截断超长用户消息内容（当单条消息已超过阈值时优先）
+    // 5) 按 request_body_bytes 成对移除 user+assistant 两条消息（保留前 2 条；配置了
+    //    语义嵌入模型时移除最不相关的轮次，否则移除最老的未被 pin 轮次）
     //
     // 每轮都会重新跑一次压缩管道（包含 tool 配对修复），再重新序列化计算字节数。
     let mut adaptive_config = base_config.clone();
@@ -197,8 +320,44 @@ fn adaptive_shrink_request_body(
     let mut message_content_max_chars =
         (max_content_chars * 3 / 4).max(ADAPTIVE_MIN_MESSAGE_CONTENT_MAX_CHARS);
 
+    // 是否存在任何图片（否则图片收缩层直接跳过）
+    let has_any_images = !kiro_request
+        .conversation_state
+        .current_message
+        .user_input_message
+        .images
+        .is_empty()
+        || kiro_request.conversation_state.history.iter().any(|msg| match msg {
+            Message::User(u) => !u.user_input_message.images.is_empty(),
+            _ => false,
+        });
+    // 图片质量阶梯：85 → 70 → 55 → ... → image_min_quality，逐轮下降一档
+    let image_quality_ladder: Vec<u8> = {
+        let min_quality = adaptive_config.image_min_quality.max(1);
+        let mut ladder = Vec::new();
+        let mut quality: i32 = 85;
+        while quality > min_quality as i32 {
+            ladder.push(quality as u8);
+            quality -= 15;
+        }
+        ladder.push(min_quality);
+        ladder
+    };
+    let mut image_quality_idx = 0usize;
+
     for _ in 0..ADAPTIVE_COMPRESSION_MAX_ITERS {
-        if request_body.len() <= max_body {
+        let token_count = token_budget.map(|(_, tokenizer)| {
+            estimate_kiro_request_tokens(kiro_request, tokenizer)
+        });
+        if let Some(count) = token_count {
+            outcome.final_input_tokens = count;
+        }
+        // 字节预算与 token 预算须同时满足：200k-context 模型即使字节数尚未
+        // 触顶，上游也可能因 context_length 超限而拒绝请求。
+        let token_satisfied =
+            token_budget.is_none_or(|(budget, _)| token_count.unwrap_or(0) <= budget);
+        let byte_satisfied = max_body == 0 || request_body.len() <= max_body;
+        if byte_satisfied && token_satisfied {
             break;
         }
 
@@ -222,6 +381,76 @@ fn adaptive_shrink_request_body(
                 adaptive_config.tool_use_input_max_chars = next;
                 changed = true;
             }
+        } else if has_any_images && {
+            let image_quality_remaining = image_quality_idx < image_quality_ladder.len();
+            let history_has_images = kiro_request.conversation_state.history.iter().any(|msg| {
+                matches!(msg, Message::User(u) if !u.user_input_message.images.is_empty())
+            });
+            image_quality_remaining || (adaptive_config.image_evict_history && history_has_images)
+        } {
+            // 第三层：图片收缩。图片字节常常是体积大户，优先于文本截断/历史移除，
+            // 避免"还有图片可以瘦身"时就先丢弃对话上下文。
+            let mut saved = 0usize;
+            if image_quality_idx < image_quality_ladder.len() {
+                let quality = image_quality_ladder[image_quality_idx];
+                for img in &mut kiro_request
+                    .conversation_state
+                    .current_message
+                    .user_input_message
+                    .images
+                {
+                    if let Ok((data, _)) = crate::image::recompress_image_to_budget(
+                        &img.source.bytes,
+                        adaptive_config.image_max_pixels,
+                        &[quality],
+                        usize::MAX,
+                    ) && data.len() < img.source.bytes.len()
+                    {
+                        saved += img.source.bytes.len() - data.len();
+                        img.source.bytes = data;
+                    }
+                }
+                for msg in &mut kiro_request.conversation_state.history {
+                    if let Message::User(u) = msg {
+                        for img in &mut u.user_input_message.images {
+                            if let Ok((data, _)) = crate::image::recompress_image_to_budget(
+                                &img.source.bytes,
+                                adaptive_config.image_max_pixels,
+                                &[quality],
+                                usize::MAX,
+                            ) && data.len() < img.source.bytes.len()
+                            {
+                                saved += img.source.bytes.len() - data.len();
+                                img.source.bytes = data;
+                            }
+                        }
+                    }
+                }
+                image_quality_idx += 1;
+            } else if adaptive_config.image_evict_history {
+                // 质量阶梯已耗尽：整张剔除最旧的一条携带图片的历史消息（保留
+                // current_message 中的图片，只清空该条历史消息的 images）
+                for msg in &mut kiro_request.conversation_state.history {
+                    if let Message::User(u) = msg
+                        && !u.user_input_message.images.is_empty()
+                    {
+                        let evicted_bytes: usize = u
+                            .user_input_message
+                            .images
+                            .iter()
+                            .map(|img| img.source.bytes.len())
+                            .sum();
+                        saved += evicted_bytes;
+                        outcome.images_evicted += u.user_input_message.images.len();
+                        u.user_input_message.images.clear();
+                        break;
+                    }
+                }
+            }
+            if saved > 0 {
+                outcome.image_bytes_saved += saved;
+                changed = true;
+            }
         } else {
             // 如果任意单条 user content 已经超过 max_body，则移除历史并不能让请求落到阈值内，
             // 必须优先截断超长消息内容。
@@ -235,6 +464,9 @@ fn adaptive_shrink_request_body(
                 }
                 max_bytes
             };
+            let current_vector = embedder.map(|e| {
+                e.embed(&kiro_request.conversation_state.current_message.user_input_message.content)
+            });
 
             let history = &mut kiro_request.conversation_state.history;
             if (max_single_user_content_bytes > max_body
@@ -242,9 +474,15 @@ fn adaptive_shrink_request_body(
                 && message_content_max_chars >= ADAPTIVE_MIN_MESSAGE_CONTENT_MAX_CHARS
             {
                 // 第三层：截断超长消息内容
+                let pinned: std::collections::HashSet<&str> = adaptive_config
+                    .pinned_ids
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect();
                 let saved = super::compressor::compress_long_messages_pass(
                     &mut kiro_request.conversation_state,
                     message_content_max_chars,
+                    &pinned,
                 );
                 if saved > 0 {
                     changed = true;
@@ -255,18 +493,84 @@ fn adaptive_shrink_request_body(
                 message_content_max_chars =
                     (message_content_max_chars * 3 / 4).max(ADAPTIVE_MIN_MESSAGE_CONTENT_MAX_CHARS);
             } else if history.len() > ADAPTIVE_HISTORY_PRESERVE_MESSAGES + 2 {
-                // 第四层：移除最老历史消息（成对移除 user+assistant）
+                // 第五层：移除历史消息（成对移除 user+assistant），跳过被 pin 保护的轮次。
+                // 配置了语义嵌入模型时，优先移除与当前问题语义最不相关的轮次；否则退回
+                // 按时间顺序移除最早的未被 pin 轮次。`history_summarize` 开启时，被移除
+                // 的轮次不直接丢弃，而是由 `HeuristicSummarizer` 合成一条摘要轮次插回
+                // 保留边界，避免上下文被完全丢失；摘要本身仍受
+                // `message_content_max_chars` 截断约束，不能重新引入体积膨胀。
                 let preserve = ADAPTIVE_HISTORY_PRESERVE_MESSAGES;
-                let min_len = preserve + 2;
-                let removable = history.len().saturating_sub(min_len);
+                let pinned: std::collections::HashSet<&str> = adaptive_config
+                    .pinned_ids
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect();
+                let mut removed_msgs = 0usize;
+                let mut removed_messages: Vec<Message> = Vec::new();
                 // 单轮最多移除 16 条消息（8 轮），避免一次性丢弃过多上下文
-                let mut remove_msgs = removable.min(16);
-                remove_msgs -= remove_msgs % 2; // 保持成对移除
-                if remove_msgs > 0 {
-                    history.drain(preserve..preserve + remove_msgs);
-                    outcome.additional_history_turns_removed += remove_msgs / 2;
+                while removed_msgs < 16 {
+                    let idx = match (embedder, &current_vector) {
+                        (Some(embedder), Some(current_vector)) => {
+                            super::compressor::find_least_relevant_pair_index(
+                                history,
+                                preserve,
+                                &pinned,
+                                embedder,
+                                current_vector,
+                            )
+                        }
+                        _ => {
+                            let mut idx = preserve;
+                            let end = history.len().saturating_sub(2);
+                            let mut found = None;
+                            while idx < end {
+                                if !super::compressor::pair_is_pinned(
+                                    &history[idx],
+                                    &history[idx + 1],
+                                    &pinned,
+                                ) {
+                                    found = Some(idx);
+                                    break;
+                                }
+                                idx += 2;
+                            }
+                            found
+                        }
+                    };
+
+                    let Some(idx) = idx else { break };
+                    if adaptive_config.history_summarize {
+                        removed_messages.extend(history.drain(idx..idx + 2));
+                    } else {
+                        history.drain(idx..idx + 2);
+                    }
+                    removed_msgs += 2;
+                }
+                if removed_msgs > 0 {
+                    outcome.additional_history_turns_removed += removed_msgs / 2;
                     changed = true;
                 }
+                if !removed_messages.is_empty() {
+                    let mut summary_content = HeuristicSummarizer.summarize(&removed_messages);
+                    super::compressor::truncate_long_content(
+                        &mut summary_content,
+                        message_content_max_chars,
+                    );
+                    outcome.history_turns_summarized += removed_messages.len() / 2;
+                    history.insert(
+                        preserve,
+                        Message::Assistant(HistoryAssistantMessage::new(
+                            "Acknowledged earlier context (summarized).",
+                        )),
+                    );
+                    history.insert(
+                        preserve,
+                        Message::User(HistoryUserMessage::new(
+                            &summary_content,
+                            "claude-sonnet-4.5",
+                        )),
+                    );
+                }
             }
         }
 
@@ -288,7 +592,81 @@ fn adaptive_shrink_request_body(
     Ok(Some(outcome))
 }
 
-fn map_kiro_provider_error_to_response(request_body: &str, err: Error) -> Response {
+/// 所有凭据配额耗尽时，没有更准确的上游重置时间可用时的保守兜底 Retry-After
+const DEFAULT_QUOTA_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// `request_timeout_secs = 0`（不限制）时使用的等效超时时长
+///
+/// `tokio::time::sleep_until` 需要一个具体的 `Instant`，用一个足够大的时长
+/// 代替"真正不限制"，避免为了支持禁用超时而给每条流式处理路径的 select!
+/// 状态机额外增加一个条件分支。
+const EFFECTIVELY_NO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(i64::MAX as u64 / 2);
+
+/// 尝试在宽限期内拿到一个全局并发槽位；拿不到时返回一个带 `Retry-After` 的
+/// 429 响应，调用方应直接返回它而不再派发到上游
+async fn try_acquire_request_permit(
+    state: &AppState,
+) -> Result<tokio::sync::OwnedSemaphorePermit, Response> {
+    let grace = Duration::from_secs(state.request_limits.semaphore_acquire_timeout_secs);
+    match state.request_concurrency.acquire_with_grace(grace).await {
+        Some(permit) => Ok(permit),
+        None => {
+            tracing::warn!(
+                max_concurrent_requests = state.request_limits.max_concurrent_requests,
+                grace_secs = state.request_limits.semaphore_acquire_timeout_secs,
+                "并发上游调用已达上限，拒绝请求"
+            );
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(ErrorResponse::new(
+                    "rate_limit_error",
+                    "Too many concurrent requests in flight. Please retry shortly.",
+                )),
+            )
+                .into_response();
+            if let Ok(value) = header::HeaderValue::from_str("1") {
+                response.headers_mut().insert(header::RETRY_AFTER, value);
+            }
+            Err(response)
+        }
+    }
+}
+
+/// 请求头名：单次请求覆盖 `/cc/v1/messages` 的流式响应模式
+///
+/// 取值 `incremental` / `buffered`，大小写不敏感；缺失或无法识别时回退到
+/// `cc_streaming.incremental_by_default` 部署级配置
+const STREAM_MODE_HEADER: &str = "x-stream-mode";
+
+/// 解析 `/cc/v1/messages` 本次请求应使用的流式响应模式：
+/// 增量（true）还是缓冲（false，默认）
+fn resolve_cc_incremental_streaming(state: &AppState, headers: &HeaderMap) -> bool {
+    match headers
+        .get(STREAM_MODE_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(mode) if mode.eq_ignore_ascii_case("incremental") => true,
+        Some(mode) if mode.eq_ignore_ascii_case("buffered") => false,
+        _ => state.cc_streaming.incremental_by_default,
+    }
+}
+
+/// 计算本次请求的墙钟超时截止时刻；`request_timeout_secs = 0` 表示不限制
+fn request_deadline(state: &AppState) -> Instant {
+    let timeout = state.request_limits.request_timeout_secs;
+    let duration = if timeout == 0 {
+        EFFECTIVELY_NO_TIMEOUT
+    } else {
+        Duration::from_secs(timeout)
+    };
+    Instant::now() + duration
+}
+
+fn map_kiro_provider_error_to_response(
+    request_body: &str,
+    err: Error,
+    quota_tracker: &crate::common::quota::QuotaTracker,
+) -> Response {
     if is_input_too_long_error(&err) {
         tracing::warn!(
             kiro_request_body_bytes = request_body.len(),
@@ -321,8 +699,24 @@ fn map_kiro_provider_error_to_response(request_body: &str, err: Error) -> Respon
     }
 
     if is_quota_exhausted_error(&err) {
-        tracing::warn!(error = %err, "所有凭据配额已耗尽");
-        return (
+        // 没有逐凭据的精确重置时间可用时，以一个保守兜底值刷新/播种追踪器，
+        // 使短时间内的连续请求看到一个随时间收敛的 Retry-After，而不是恒定值。
+        let retry_after = match quota_tracker.earliest_reset_remaining() {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => {
+                quota_tracker.record_exhausted("all-credentials", DEFAULT_QUOTA_RETRY_AFTER);
+                DEFAULT_QUOTA_RETRY_AFTER
+            }
+        };
+        let retry_after_secs = retry_after.as_secs().max(1);
+
+        tracing::warn!(
+            error = %err,
+            retry_after_secs,
+            "所有凭据配额已耗尽"
+        );
+
+        let mut response = (
             StatusCode::TOO_MANY_REQUESTS,
             Json(ErrorResponse::new(
                 "rate_limit_error",
@@ -330,6 +724,26 @@ fn map_kiro_provider_error_to_response(request_body: &str, err: Error) -> Respon
             )),
         )
             .into_response();
+
+        let reset_unix = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            + retry_after_secs;
+        let headers = response.headers_mut();
+        headers.insert(
+            header::RETRY_AFTER,
+            header::HeaderValue::from_str(&retry_after_secs.to_string())
+                .unwrap_or_else(|_| header::HeaderValue::from_static("60")),
+        );
+        if let Ok(value) = header::HeaderValue::from_str("0") {
+            headers.insert("anthropic-ratelimit-requests-remaining", value);
+        }
+        if let Ok(value) = header::HeaderValue::from_str(&reset_unix.to_string()) {
+            headers.insert("anthropic-ratelimit-requests-reset", value);
+        }
+
+        return response;
     }
 
     tracing::error!("Kiro API 调用失败: {}", err);
@@ -374,13 +788,10 @@ fn mask_user_id(user_id: Option<&str>) -> String {
     }
 }
 
-/// GET /v1/models
-///
-/// 返回可用的模型列表
-pub async fn get_models() -> impl IntoResponse {
-    tracing::info!("Received GET /v1/models request");
-
-    let models = vec![
+/// 已知模型及其元数据（`GET /v1/models` 与 token 预算估算共用同一张表，
+/// 避免 context_length 等字段在两处各维护一份而逐渐漂移）。
+fn model_catalog() -> Vec<Model> {
+    vec![
         Model {
             id: "claude-sonnet-4-6".to_string(),
             object: "model".to_string(),
@@ -561,14 +972,40 @@ pub async fn get_models() -> impl IntoResponse {
             max_completion_tokens: Some(64_000),
             thinking: Some(true),
         },
-    ];
+    ]
+}
+
+/// 按模型 id 查找其 context_length（在 `model_catalog` 中找不到该模型时返回 `None`，
+/// 调用方应退回"仅按字节预算收缩"，不强加 token 上限）
+fn model_context_length(model_id: &str) -> Option<u32> {
+    model_catalog()
+        .into_iter()
+        .find(|m| m.id == model_id)
+        .and_then(|m| m.context_length)
+}
+
+/// GET /v1/models
+///
+/// 返回可用的模型列表
+pub async fn get_models() -> impl IntoResponse {
+    tracing::info!("Received GET /v1/models request");
 
     Json(ModelsResponse {
         object: "list".to_string(),
-        data: models,
+        data: model_catalog(),
     })
 }
 
+/// GET /metrics
+///
+/// Prometheus 文本 exposition 格式的进程内指标，详见 [`crate::common::metrics::Metrics`]。
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
 /// POST /v1/messages
 ///
 /// 创建消息（对话）
@@ -599,6 +1036,35 @@ pub async fn post_messages(
         estimated_input_tokens,
         "Received POST /v1/messages request"
     );
+
+    state.metrics.record_messages_request(false, payload.stream);
+    state
+        .metrics
+        .estimated_input_tokens
+        .observe(estimated_input_tokens.max(0) as u64);
+
+    // 按 user_id 的请求/token 预算：在派发到上游前提前拒绝，避免单个用户的
+    // 突发流量耗尽全部凭据配额
+    if let Some(quota_config) = &state.user_quota_config
+        && let Some(uid) = user_id.as_deref()
+        && !state
+            .user_budget_tracker
+            .try_consume(uid, quota_config, estimated_input_tokens.max(0) as u64)
+    {
+        tracing::warn!(
+            user_id = %mask_user_id(Some(uid)),
+            "用户请求/token 预算超限，提前拒绝"
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(ErrorResponse::new(
+                "rate_limit_error",
+                "Per-user request/token budget exceeded. Please slow down or wait for the budget window to reset.",
+            )),
+        )
+            .into_response();
+    }
+
     // 检查 KiroProvider 是否可用
     let provider = match &state.kiro_provider {
         Some(p) => p.clone(),
@@ -672,6 +1138,7 @@ pub async fn post_messages(
         conversation_state: conversion_result.conversation_state,
         profile_arn: state.profile_arn.clone(),
     };
+    state.filters.run_request_filters(&mut kiro_request);
 
     let mut request_body = match serde_json::to_string(&kiro_request) {
         Ok(body) => body,
@@ -688,17 +1155,52 @@ pub async fn post_messages(
         }
     };
 
+    // token 预算：以所选模型的 context_length 减去本次请求的 max_tokens 输出余量，
+    // 作为与字节预算并行的第二重收缩目标（200k-context 模型常常先触发 token 上限，
+    // 而不是 5MiB 字节上限，单靠字节预算无法提前拦截上游的 CONTENT_LENGTH_EXCEEDS_THRESHOLD）
+    let token_budget_limit = model_context_length(&payload.model)
+        .map(|ctx| (ctx as i64 - payload.max_tokens as i64).max(0) as usize);
+    let budget_tokenizer = token_budget_limit.and_then(|_| {
+        match crate::common::tokenizer::BpeTokenizer::for_model(&payload.model) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                tracing::warn!(error = %e, model = %payload.model, "构造 BPE 分词器失败，跳过 token 预算约束");
+                None
+            }
+        }
+    });
+    let token_budget = token_budget_limit.zip(budget_tokenizer.as_ref());
+
     // 请求体大小预检（上游存在硬性请求体大小限制；按实际序列化后的总字节数判断）
     let max_body = state.compression_config.max_request_body_bytes;
-    if max_body > 0 && request_body.len() > max_body && state.compression_config.enabled {
-        // 自适应二次压缩：按 request_body_bytes 迭代截断，尽量把请求缩到阈值内
+    let byte_over_budget = max_body > 0 && request_body.len() > max_body;
+    let token_over_budget = token_budget.is_some_and(|(limit, tokenizer)| {
+        estimate_kiro_request_tokens(&kiro_request, tokenizer) > limit
+    });
+    emit_size_precheck_diagnostics(
+        &state.diagnostics,
+        kiro_request.conversation_state.conversation_id.as_str(),
+        request_body.len(),
+        max_body,
+        byte_over_budget,
+        token_over_budget,
+        token_budget_limit,
+    );
+
+    if (byte_over_budget || token_over_budget) && state.compression_config.enabled {
+        // 自适应二次压缩：同时按 request_body_bytes 与 token 预算迭代收缩，直到两者都满足
         match adaptive_shrink_request_body(
             &mut kiro_request,
             &state.compression_config,
             max_body,
             &mut request_body,
+            state.turn_embedder.as_deref(),
+            token_budget,
         ) {
             Ok(Some(outcome)) => {
+                state
+                    .metrics
+                    .record_adaptive_compression(outcome.initial_bytes, outcome.final_bytes, outcome.iters);
                 tracing::warn!(
                     conversation_id = kiro_request.conversation_state.conversation_id.as_str(),
                     initial_bytes = outcome.initial_bytes,
@@ -709,8 +1211,17 @@ pub async fn post_messages(
                     final_tool_result_max_chars = outcome.final_tool_result_max_chars,
                     final_tool_use_input_max_chars = outcome.final_tool_use_input_max_chars,
                     final_message_content_max_chars = outcome.final_message_content_max_chars,
+                    token_budget = ?token_budget_limit,
+                    final_input_tokens = outcome.final_input_tokens,
                     "请求体超过阈值，已执行自适应二次压缩"
                 );
+                emit_adaptive_shrink_diagnostics(
+                    &state.diagnostics,
+                    kiro_request.conversation_state.conversation_id.as_str(),
+                    &outcome,
+                    max_body,
+                    token_budget_limit,
+                );
             }
             Ok(None) => {}
             Err(e) => {
@@ -731,6 +1242,7 @@ pub async fn post_messages(
     let final_img_bytes = total_image_bytes(&kiro_request);
     let final_effective_len = request_body.len().saturating_sub(final_img_bytes);
     if max_body > 0 && request_body.len() > max_body {
+        state.metrics.requests_rejected_too_large_total.inc();
         tracing::warn!(
             conversation_id = kiro_request.conversation_state.conversation_id.as_str(),
             request_body_bytes = request_body.len(),
@@ -739,6 +1251,14 @@ pub async fn post_messages(
             threshold = max_body,
             "请求体超过安全阈值，拒绝发送"
         );
+        emit_request_rejected_diagnostics(
+            &state.diagnostics,
+            kiro_request.conversation_state.conversation_id.as_str(),
+            request_body.len(),
+            final_img_bytes,
+            final_effective_len,
+            max_body,
+        );
         #[cfg(feature = "sensitive-logs")]
         tracing::error!(
             "自适应压缩仍超限，完整请求体（用于诊断）: {}",
@@ -772,6 +1292,12 @@ pub async fn post_messages(
         .map(|t| t.is_enabled())
         .unwrap_or(false);
 
+    let permit = match try_acquire_request_permit(&state).await {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+    let deadline = request_deadline(&state);
+
     if payload.stream {
         // 流式响应
         handle_stream_request(
@@ -781,6 +1307,13 @@ pub async fn post_messages(
             estimated_input_tokens,
             thinking_enabled,
             user_id.as_deref(),
+            &state.quota_tracker,
+            state.metrics.clone(),
+            state.shutdown.clone(),
+            permit,
+            deadline,
+            false,
+            state.filters.clone(),
         )
         .await
     } else {
@@ -791,32 +1324,61 @@ pub async fn post_messages(
             &payload.model,
             estimated_input_tokens,
             user_id.as_deref(),
+            &state.quota_tracker,
+            &state.metrics,
+            permit,
+            deadline,
+            state.filters.clone(),
         )
         .await
     }
 }
 async fn handle_stream_request(
-    provider: std::sync::Arc<crate::kiro::provider::KiroProvider>,
+    provider: std::sync::Arc<dyn crate::kiro::provider::KiroProviderApi>,
     request_body: &str,
     model: &str,
     input_tokens: i32,
     thinking_enabled: bool,
     user_id: Option<&str>,
+    quota_tracker: &crate::common::quota::QuotaTracker,
+    metrics: std::sync::Arc<crate::common::metrics::Metrics>,
+    shutdown: std::sync::Arc<crate::common::shutdown::ShutdownCoordinator>,
+    permit: tokio::sync::OwnedSemaphorePermit,
+    deadline: Instant,
+    correct_usage_from_context_event: bool,
+    filters: FilterPipeline,
 ) -> Response {
     // 调用 Kiro API（支持多凭据故障转移）
     let response = match provider.call_api_stream(request_body, user_id).await {
         Ok(resp) => resp,
-        Err(e) => return map_kiro_provider_error_to_response(request_body, e),
+        Err(e) => return map_kiro_provider_error_to_response(request_body, e, quota_tracker),
     };
 
     // 创建流处理上下文
     let mut ctx = StreamContext::new_with_thinking(model, input_tokens, thinking_enabled);
+    if correct_usage_from_context_event {
+        // 增量模式：message_start 仍按估算值立即发出，但收尾 message_delta 的
+        // usage 改为携带 contextUsageEvent 校正后的精确 input_tokens/output_tokens；
+        // contextUsageEvent 始终没有到达时 ctx 内部回退为估算值
+        ctx.enable_context_usage_correction();
+    }
 
     // 生成初始事件
     let initial_events = ctx.generate_initial_events();
 
-    // 创建 SSE 流
-    let stream = create_sse_stream(response, ctx, initial_events);
+    // 创建 SSE 流（携带恢复所需的上下文，供上游异常中断时重试/续写）
+    let resume = StreamResumeState {
+        provider: provider.clone(),
+        request_body: request_body.to_string(),
+        model: model.to_string(),
+        user_id: user_id.map(|s| s.to_string()),
+        attempts: 0,
+        metrics,
+        filters,
+    };
+    // 并发槽位守卫随流一起移入 create_sse_stream，直到流结束（正常/异常/
+    // 超时/优雅关闭收尾）才释放，而不是在发起上游调用后立即释放
+    let stream = create_sse_stream(response, ctx, initial_events, resume, shutdown, permit, deadline);
 
     // 返回 SSE 响应
     Response::builder()
@@ -831,16 +1393,225 @@ async fn handle_stream_request(
 /// Ping 事件间隔（25秒）
 const PING_INTERVAL_SECS: u64 = 25;
 
+/// 上游流异常中断时的最大恢复次数（无论是"重试原始请求"还是"续写剩余内容"都计入同一预算）
+const STREAM_RESUME_MAX_RETRIES: usize = 3;
+/// 重试退避基数（毫秒），按 `2^(attempt-1)` 指数增长
+const STREAM_RESUME_BACKOFF_BASE_MS: u64 = 500;
+
+/// 事件解码缓冲区溢出时发送的 SSE 错误事件
+///
+/// 解码器已无法继续安全地解析后续帧（已观测到的帧可能已被静默丢弃），与其让流
+/// 在没有任何信号的情况下卡住或输出不完整内容，不如给客户端一个明确的错误
+/// 事件并立即结束流，让客户端据此重试而不是误以为响应已正常完成。
+fn decoder_overflow_sse(message: &str) -> Bytes {
+    let payload = json!({
+        "type": "error",
+        "error": {
+            "type": "overloaded_error",
+            "message": message,
+        }
+    });
+    Bytes::from(format!("event: error\ndata: {}\n\n", payload))
+}
+
 /// 创建 ping 事件的 SSE 字符串
 fn create_ping_sse() -> Bytes {
     Bytes::from("event: ping\ndata: {\"type\": \"ping\"}\n\n")
 }
 
+/// 上游流恢复所需的上下文：原始请求体/凭据亲和 user_id 用于重试，
+/// `attempts` 在"重试原始请求"与"续写剩余内容"两种恢复路径间共享同一预算。
+struct StreamResumeState {
+    provider: std::sync::Arc<dyn crate::kiro::provider::KiroProviderApi>,
+    request_body: String,
+    model: String,
+    user_id: Option<String>,
+    attempts: usize,
+    /// 共享指标注册表，供流循环内记录 ping 保活次数/上游异常中断次数
+    metrics: std::sync::Arc<crate::common::metrics::Metrics>,
+    /// 事件过滤器管道，在每个解析出的 `Event` 交给 `ctx` 处理前先就地执行
+    filters: FilterPipeline,
+}
+
+/// 将已部分生成的助手回复归档进历史，构造一条"续写"请求体
+///
+/// 把原 current_message 降级为历史用户轮次，已输出的部分内容作为历史助手轮次，
+/// current_message 替换为续写指令，使上游从"助手说到一半"的状态继续生成。
+fn build_continuation_request_body(
+    original_body: &str,
+    partial_text: &str,
+    model: &str,
+) -> anyhow::Result<String> {
+    let mut kiro_request: KiroRequest =
+        serde_json::from_str(original_body).context("解析原始请求体失败")?;
+    let prior_user_content = std::mem::take(
+        &mut kiro_request.conversation_state.current_message.user_input_message.content,
+    );
+    kiro_request
+        .conversation_state
+        .history
+        .push(Message::User(HistoryUserMessage::new(&prior_user_content, model)));
+    kiro_request
+        .conversation_state
+        .history
+        .push(Message::Assistant(HistoryAssistantMessage::new(partial_text)));
+    kiro_request.conversation_state.current_message.user_input_message.content =
+        "Continue exactly where you left off. Do not repeat or restate anything already said."
+            .to_string();
+    serde_json::to_string(&kiro_request).context("序列化续写请求体失败")
+}
+
+/// [`advance_stream_state`] 推进一次后，调用方（SSE/WS）应采取的动作
+///
+/// 两种传输共享同一套"解码 -> 过滤 -> 喂给 `StreamContext`；异常中断时先退避
+/// 重试原始请求，已有输出则改为续写"状态机，只是各自把 `SseEvent` 序列化成
+/// 自己的线上格式（SSE 字节 / WS JSON 帧），因此只有这一层状态流转的结果
+/// 需要对外暴露。
+enum StreamAdvance {
+    /// 正常解码出的若干事件，流尚未终止
+    Continue(Vec<SseEvent>),
+    /// 解码缓冲区溢出，流必须立即终止；携带用于构造错误提示的原始错误信息
+    DecodeOverflow(String),
+    /// 异常中断且重试/续写均已失败或次数耗尽（或正常结束）：这是最后一批事件
+    Terminal(Vec<SseEvent>),
+    /// 异常中断后重试/续写成功：`body_stream`/`decoder` 已被原地替换为新的
+    /// 上游响应，调用方不产出事件，直接进入下一轮循环
+    Resumed,
+}
+
+/// 共享状态机：处理 `body_stream.next()` 产出的一个 chunk 结果
+///
+/// 异常中断（`Err`，或 `None` 到来时解码器仍有未消费的缓冲字节且未观测到
+/// 终止事件）时按 `resume.attempts`/`STREAM_RESUME_MAX_RETRIES` 做指数退避
+/// 重试：尚无输出则重试原始请求，已有输出则把已生成内容归档为历史并构造
+/// 续写请求，拼接到同一条流里。`body_stream`/`decoder` 在重试/续写成功时
+/// 被原地替换；`resume.attempts`/`resume.request_body`/`ctx` 的其余状态变更
+/// 均直接体现在传入的可变引用上。
+async fn advance_stream_state(
+    chunk_result: Option<anyhow::Result<Bytes>>,
+    body_stream: &mut crate::kiro::provider::ProviderByteStream,
+    decoder: &mut EventStreamDecoder,
+    ctx: &mut StreamContext,
+    resume: &mut StreamResumeState,
+) -> StreamAdvance {
+    // 异常中断：Err，或 None 到来时解码器仍有未消费的缓冲字节且未观测到终止事件
+    // （正常的 end_turn/tool_use 在此之前已经让 ctx 记录下终止事件）。
+    let abnormal_end = match &chunk_result {
+        Some(Err(_)) => true,
+        None => decoder.has_pending_bytes() && !ctx.has_terminal_event(),
+        Some(Ok(_)) => false,
+    };
+    if abnormal_end {
+        resume.metrics.upstream_stream_errors_total.inc();
+    }
+    if let Some(Err(e)) = &chunk_result {
+        tracing::error!("读取响应流失败: {}", e);
+    }
+
+    match chunk_result {
+        Some(Ok(chunk)) => {
+            if let Err(e) = decoder.feed(&chunk) {
+                tracing::error!("事件解码缓冲区溢出，已无法继续安全解析，终止流: {}", e);
+                return StreamAdvance::DecodeOverflow(e.to_string());
+            }
+
+            let mut events = Vec::new();
+            for result in decoder.decode_iter() {
+                match result {
+                    Ok(frame) => {
+                        if let Ok(mut event) = Event::from_frame(frame) {
+                            resume.filters.run_event_filters(&mut event);
+                            events.extend(ctx.process_kiro_event(&event));
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("解码事件失败: {}", e);
+                    }
+                }
+            }
+            StreamAdvance::Continue(events)
+        }
+        _ if abnormal_end => {
+            resume.attempts += 1;
+            let flushed = ctx.has_flushed_content();
+
+            if resume.attempts > STREAM_RESUME_MAX_RETRIES {
+                tracing::error!(
+                    attempts = resume.attempts,
+                    flushed_content = flushed,
+                    "上游流恢复次数耗尽，回退为最终事件"
+                );
+            } else if !flushed {
+                // 尚未向客户端输出任何可见内容：退避后直接重试原始请求
+                let backoff = Duration::from_millis(
+                    STREAM_RESUME_BACKOFF_BASE_MS * (1u64 << (resume.attempts - 1)),
+                );
+                tracing::warn!(
+                    attempt = resume.attempts,
+                    backoff_ms = backoff.as_millis() as u64,
+                    "上游流异常中断（尚无输出），退避后重试原始请求"
+                );
+                tokio::time::sleep(backoff).await;
+                match resume.provider.call_api_stream(&resume.request_body, resume.user_id.as_deref()).await {
+                    Ok(new_response) => {
+                        tracing::info!(attempt = resume.attempts, "流重试成功，恢复处理");
+                        *body_stream = new_response;
+                        *decoder = EventStreamDecoder::new();
+                        return StreamAdvance::Resumed;
+                    }
+                    Err(e) => {
+                        tracing::warn!(attempt = resume.attempts, error = %e, "流重试失败");
+                    }
+                }
+            } else {
+                // 已输出部分内容：把已生成的内容归档为历史，续写剩余部分，
+                // 拼接进同一条流（抑制续写响应里重复的 message_start 等初始事件）
+                tracing::warn!(
+                    attempt = resume.attempts,
+                    "上游流异常中断（已输出部分内容），构造续写请求"
+                );
+                let partial_text = ctx.accumulated_text();
+                match build_continuation_request_body(&resume.request_body, &partial_text, &resume.model) {
+                    Ok(continuation_body) => {
+                        match resume.provider.call_api_stream(&continuation_body, resume.user_id.as_deref()).await {
+                            Ok(new_response) => {
+                                tracing::info!(attempt = resume.attempts, "续写请求成功，恢复处理");
+                                ctx.mark_resumed();
+                                resume.request_body = continuation_body;
+                                *body_stream = new_response;
+                                *decoder = EventStreamDecoder::new();
+                                return StreamAdvance::Resumed;
+                            }
+                            Err(e) => {
+                                tracing::error!(attempt = resume.attempts, error = %e, "续写请求失败，回退为最终事件");
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(attempt = resume.attempts, error = %e, "构造续写请求体失败，回退为最终事件");
+                    }
+                }
+            }
+
+            // 恢复失败或次数耗尽：生成最终事件，调用方发送后应结束
+            StreamAdvance::Terminal(ctx.generate_final_events())
+        }
+        None => {
+            // 正常结束：已观测到终止事件（或解码器已无残留字节），生成最终事件
+            StreamAdvance::Terminal(ctx.generate_final_events())
+        }
+    }
+}
+
 /// 创建 SSE 事件流
 fn create_sse_stream(
-    response: reqwest::Response,
+    response: crate::kiro::provider::ProviderByteStream,
     ctx: StreamContext,
     initial_events: Vec<SseEvent>,
+    resume: StreamResumeState,
+    shutdown: std::sync::Arc<crate::common::shutdown::ShutdownCoordinator>,
+    permit: tokio::sync::OwnedSemaphorePermit,
+    deadline: Instant,
 ) -> impl Stream<Item = Result<Bytes, Infallible>> {
     // 先发送初始事件
     let initial_stream = stream::iter(
@@ -850,77 +1621,93 @@ fn create_sse_stream(
     );
 
     // 然后处理 Kiro 响应流，同时每25秒发送 ping 保活
-    let body_stream = response.bytes_stream();
+    let body_stream = response;
     let ping_period = Duration::from_secs(PING_INTERVAL_SECS);
     let ping_interval = interval_at(Instant::now() + ping_period, ping_period);
 
+    // 登记为一条正在进行的流，供优雅关闭协调器统计排空进度；订阅关闭信号，
+    // 与数据/ping 分支一起被 select! 轮询
+    let drain_guard = shutdown.register_stream();
+    let shutdown_rx = shutdown.subscribe();
+
     let processing_stream = stream::unfold(
-        (body_stream, ctx, EventStreamDecoder::new(), false, ping_interval),
-        |(mut body_stream, mut ctx, mut decoder, finished, mut ping_interval)| async move {
+        (
+            body_stream,
+            ctx,
+            EventStreamDecoder::new(),
+            false,
+            ping_interval,
+            resume,
+            shutdown_rx,
+            drain_guard,
+            permit,
+            deadline,
+        ),
+        |(mut body_stream, mut ctx, mut decoder, finished, mut ping_interval, mut resume, mut shutdown_rx, drain_guard, permit, deadline)| async move {
             if finished {
                 return None;
             }
 
             // 使用 select! 同时等待数据和 ping 定时器
             tokio::select! {
-                // 处理数据流
+                // 处理数据流：解码/过滤/恢复状态机与 WS 传输（`run_ws_event_loop`）
+                // 共享同一个 `advance_stream_state`，这里只负责把结果转换为 SSE 字节
                 chunk_result = body_stream.next() => {
-                    match chunk_result {
-                        Some(Ok(chunk)) => {
-                            // 解码事件
-                            if let Err(e) = decoder.feed(&chunk) {
-                                tracing::warn!("缓冲区溢出: {}", e);
-                            }
-
-                            let mut events = Vec::new();
-                            for result in decoder.decode_iter() {
-                                match result {
-                                    Ok(frame) => {
-                                        if let Ok(event) = Event::from_frame(frame) {
-                                            let sse_events = ctx.process_kiro_event(&event);
-                                            events.extend(sse_events);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        tracing::warn!("解码事件失败: {}", e);
-                                    }
-                                }
-                            }
-
-                            // 转换为 SSE 字节流
+                    match advance_stream_state(chunk_result, &mut body_stream, &mut decoder, &mut ctx, &mut resume).await {
+                        StreamAdvance::Continue(events) => {
                             let bytes: Vec<Result<Bytes, Infallible>> = events
                                 .into_iter()
                                 .map(|e| Ok(Bytes::from(e.to_sse_string())))
                                 .collect();
-
-                            Some((stream::iter(bytes), (body_stream, ctx, decoder, false, ping_interval)))
+                            Some((stream::iter(bytes), (body_stream, ctx, decoder, false, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
                         }
-                        Some(Err(e)) => {
-                            tracing::error!("读取响应流失败: {}", e);
-                            // 发送最终事件并结束
-                            let final_events = ctx.generate_final_events();
-                            let bytes: Vec<Result<Bytes, Infallible>> = final_events
-                                .into_iter()
-                                .map(|e| Ok(Bytes::from(e.to_sse_string())))
-                                .collect();
-                            Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval)))
+                        StreamAdvance::DecodeOverflow(message) => {
+                            let bytes: Vec<Result<Bytes, Infallible>> = vec![Ok(decoder_overflow_sse(&message))];
+                            Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
                         }
-                        None => {
-                            // 流结束，发送最终事件
-                            let final_events = ctx.generate_final_events();
-                            let bytes: Vec<Result<Bytes, Infallible>> = final_events
+                        StreamAdvance::Terminal(events) => {
+                            let bytes: Vec<Result<Bytes, Infallible>> = events
                                 .into_iter()
                                 .map(|e| Ok(Bytes::from(e.to_sse_string())))
                                 .collect();
-                            Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval)))
+                            Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
+                        }
+                        StreamAdvance::Resumed => {
+                            Some((stream::iter(Vec::new()), (body_stream, ctx, decoder, false, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
                         }
                     }
                 }
                 // 发送 ping 保活
                 _ = ping_interval.tick() => {
                     tracing::trace!("发送 ping 保活事件");
+                    resume.metrics.ping_keepalives_total.inc();
                     let bytes: Vec<Result<Bytes, Infallible>> = vec![Ok(create_ping_sse())];
-                    Some((stream::iter(bytes), (body_stream, ctx, decoder, false, ping_interval)))
+                    Some((stream::iter(bytes), (body_stream, ctx, decoder, false, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
+                }
+                // 优雅关闭：停止拉取新的上游数据，生成带收尾 stop_reason 的
+                // 最终事件后正常结束，而不是让客户端看到裸的 socket 重置
+                changed = shutdown_rx.changed() => {
+                    if changed.is_ok() && *shutdown_rx.borrow() {
+                        tracing::info!("收到优雅关闭信号，流式响应进入收尾");
+                        let final_events = ctx.generate_draining_final_events();
+                        let bytes: Vec<Result<Bytes, Infallible>> = final_events
+                            .into_iter()
+                            .map(|e| Ok(Bytes::from(e.to_sse_string())))
+                            .collect();
+                        Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
+                    } else {
+                        Some((stream::iter(Vec::new()), (body_stream, ctx, decoder, false, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
+                    }
+                }
+                // 整体请求超时：停止拉取上游数据，以独立的超时 stop_reason 收尾
+                _ = tokio::time::sleep_until(deadline) => {
+                    tracing::warn!("请求超过配置的超时时长，流式响应进入收尾");
+                    let final_events = ctx.generate_timeout_final_events();
+                    let bytes: Vec<Result<Bytes, Infallible>> = final_events
+                        .into_iter()
+                        .map(|e| Ok(Bytes::from(e.to_sse_string())))
+                        .collect();
+                    Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval, resume, shutdown_rx, drain_guard, permit, deadline)))
                 }
             }
         },
@@ -930,30 +1717,485 @@ fn create_sse_stream(
     initial_stream.chain(processing_stream)
 }
 
+/// `GET /v1/messages/ws`（WebSocket 升级）—— 同一事件序列的 WebSocket 传输
+///
+/// 部分代理环境无法透传 `text/event-stream`，此端点复用完全相同的请求准备
+/// 与 `StreamContext` 事件生成逻辑，只是把每个 `SseEvent` 序列化为纯 JSON
+/// 文本帧（不带 `event:`/`data:` 前缀），并用原生 WebSocket ping 帧替代
+/// `create_ping_sse` 的 25 秒保活。客户端主动关闭连接时视为取消，直接丢弃
+/// 上游 `bytes_stream` 即可中止请求。
+pub async fn messages_ws(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| run_messages_ws_session(socket, state, "/v1/messages/ws"))
+}
+
+/// `GET /cc/v1/messages/ws`（WebSocket 升级）—— 与 [`messages_ws`] 相同的事件序列，仅日志标签不同
+pub async fn messages_ws_cc(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| run_messages_ws_session(socket, state, "/cc/v1/messages/ws"))
+}
+
+/// 把单个 `SseEvent` 序列化为 WebSocket 文本帧并发送（JSON payload，不带 SSE 帧头）
+async fn send_ws_event(socket: &mut WebSocket, event: &SseEvent) -> Result<(), axum::Error> {
+    socket.send(WsMessage::Text(event.to_json_string().into())).await
+}
+
+/// 发送一条错误 JSON 帧后关闭连接
+async fn send_ws_error(socket: &mut WebSocket, error_type: &str, message: impl Into<String>) {
+    let payload = serde_json::to_string(&ErrorResponse::new(error_type, message))
+        .unwrap_or_else(|_| "{\"type\":\"error\"}".to_string());
+    let _ = socket.send(WsMessage::Text(payload.into())).await;
+    let _ = socket.send(WsMessage::Close(None)).await;
+}
+
+/// 接收首帧文本消息并解析为 `MessagesRequest`；失败时向客户端回传错误帧并关闭
+async fn receive_ws_request(socket: &mut WebSocket) -> Option<MessagesRequest> {
+    match socket.recv().await {
+        Some(Ok(WsMessage::Text(text))) => match serde_json::from_str::<MessagesRequest>(&text) {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                tracing::warn!(error = %e, "WebSocket 首帧 JSON 解析失败");
+                send_ws_error(
+                    socket,
+                    "invalid_request_error",
+                    format!("Invalid JSON payload: {}", e),
+                )
+                .await;
+                None
+            }
+        },
+        Some(Ok(_)) => {
+            tracing::warn!("WebSocket 首帧不是文本帧");
+            send_ws_error(
+                socket,
+                "invalid_request_error",
+                "First WebSocket frame must be a text frame containing the JSON request body",
+            )
+            .await;
+            None
+        }
+        Some(Err(e)) => {
+            tracing::warn!(error = %e, "WebSocket 读取首帧失败");
+            None
+        }
+        None => {
+            tracing::info!("客户端未发送请求即关闭 WebSocket 连接");
+            None
+        }
+    }
+}
+
+/// 把 [`map_kiro_provider_error_to_response`] 的错误分类结果转发为 WS 错误帧
+async fn send_ws_provider_error(
+    socket: &mut WebSocket,
+    request_body: &str,
+    err: Error,
+    quota_tracker: &crate::common::quota::QuotaTracker,
+) {
+    let response = map_kiro_provider_error_to_response(request_body, err, quota_tracker);
+    let status = response.status();
+    let error_type = if status == StatusCode::TOO_MANY_REQUESTS {
+        "rate_limit_error"
+    } else if status == StatusCode::BAD_REQUEST {
+        "invalid_request_error"
+    } else if status == StatusCode::SERVICE_UNAVAILABLE {
+        "service_unavailable"
+    } else {
+        "api_error"
+    };
+    send_ws_error(socket, error_type, format!("上游调用失败 (HTTP {})", status)).await;
+}
+
+/// WebSocket 传输的整条会话生命周期：接收请求 -> 执行与 `post_messages` 相同的
+/// 请求准备流水线 -> 调用上游 -> 以 JSON 文本帧转发事件，直至正常/异常结束
+async fn run_messages_ws_session(mut socket: WebSocket, state: AppState, route: &'static str) {
+    let mut payload = match receive_ws_request(&mut socket).await {
+        Some(p) => p,
+        None => return,
+    };
+
+    override_thinking_from_model_name(&mut payload);
+
+    let user_id = payload.metadata.as_ref().and_then(|m| m.user_id.clone());
+
+    let estimated_input_tokens = token::count_all_tokens(
+        payload.model.clone(),
+        payload.system.clone(),
+        payload.messages.clone(),
+        payload.tools.clone(),
+    ) as i32;
+
+    tracing::info!(
+        model = %payload.model,
+        max_tokens = %payload.max_tokens,
+        message_count = %payload.messages.len(),
+        user_id = %mask_user_id(user_id.as_deref()),
+        estimated_input_tokens,
+        route,
+        "Received WebSocket upgrade for messages endpoint"
+    );
+
+    let is_cc = route.starts_with("/cc");
+    state.metrics.record_messages_request(is_cc, true);
+    state
+        .metrics
+        .estimated_input_tokens
+        .observe(estimated_input_tokens.max(0) as u64);
+
+    if let Some(quota_config) = &state.user_quota_config
+        && let Some(uid) = user_id.as_deref()
+        && !state
+            .user_budget_tracker
+            .try_consume(uid, quota_config, estimated_input_tokens.max(0) as u64)
+    {
+        tracing::warn!(user_id = %mask_user_id(Some(uid)), "用户请求/token 预算超限，提前拒绝");
+        send_ws_error(
+            &mut socket,
+            "rate_limit_error",
+            "Per-user request/token budget exceeded. Please slow down or wait for the budget window to reset.",
+        )
+        .await;
+        return;
+    }
+
+    let provider = match &state.kiro_provider {
+        Some(p) => p.clone(),
+        None => {
+            tracing::error!("KiroProvider 未配置");
+            send_ws_error(&mut socket, "service_unavailable", "Kiro API provider not configured").await;
+            return;
+        }
+    };
+
+    if websearch::should_handle_websearch_request(&payload) {
+        tracing::warn!("WebSocket 传输暂不支持纯 WebSearch 请求，拒绝");
+        send_ws_error(
+            &mut socket,
+            "invalid_request_error",
+            "Standalone web_search requests are not supported over the WebSocket transport; use the SSE/non-streaming endpoints instead.",
+        )
+        .await;
+        return;
+    }
+
+    if websearch::has_web_search_tool(&payload) {
+        tracing::info!("检测到混合工具列表中的 web_search，剔除后转发上游");
+        websearch::strip_web_search_tools(&mut payload);
+    }
+
+    let conversion_result = match convert_request(&payload, &state.compression_config) {
+        Ok(result) => result,
+        Err(e) => {
+            let (error_type, message) = match &e {
+                ConversionError::UnsupportedModel(model) => {
+                    ("invalid_request_error", format!("模型不支持: {}", model))
+                }
+                ConversionError::EmptyMessages => {
+                    ("invalid_request_error", "消息列表为空".to_string())
+                }
+                ConversionError::EmptyMessageContent => {
+                    ("invalid_request_error", "消息内容为空".to_string())
+                }
+            };
+            tracing::warn!("请求转换失败: {}", e);
+            send_ws_error(&mut socket, error_type, message).await;
+            return;
+        }
+    };
+
+    let mut kiro_request = KiroRequest {
+        conversation_state: conversion_result.conversation_state,
+        profile_arn: state.profile_arn.clone(),
+    };
+    state.filters.run_request_filters(&mut kiro_request);
+
+    let mut request_body = match serde_json::to_string(&kiro_request) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("序列化请求失败: {}", e);
+            send_ws_error(&mut socket, "internal_error", format!("序列化请求失败: {}", e)).await;
+            return;
+        }
+    };
+
+    let token_budget_limit = model_context_length(&payload.model)
+        .map(|ctx| (ctx as i64 - payload.max_tokens as i64).max(0) as usize);
+    let budget_tokenizer = token_budget_limit.and_then(|_| {
+        match crate::common::tokenizer::BpeTokenizer::for_model(&payload.model) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                tracing::warn!(error = %e, model = %payload.model, "构造 BPE 分词器失败，跳过 token 预算约束");
+                None
+            }
+        }
+    });
+    let token_budget = token_budget_limit.zip(budget_tokenizer.as_ref());
+
+    let max_body = state.compression_config.max_request_body_bytes;
+    let byte_over_budget = max_body > 0 && request_body.len() > max_body;
+    let token_over_budget = token_budget
+        .is_some_and(|(limit, tokenizer)| estimate_kiro_request_tokens(&kiro_request, tokenizer) > limit);
+    emit_size_precheck_diagnostics(
+        &state.diagnostics,
+        kiro_request.conversation_state.conversation_id.as_str(),
+        request_body.len(),
+        max_body,
+        byte_over_budget,
+        token_over_budget,
+        token_budget_limit,
+    );
+    if (byte_over_budget || token_over_budget) && state.compression_config.enabled {
+        match adaptive_shrink_request_body(
+            &mut kiro_request,
+            &state.compression_config,
+            max_body,
+            &mut request_body,
+            state.turn_embedder.as_deref(),
+            token_budget,
+        ) {
+            Ok(Some(outcome)) => {
+                state
+                    .metrics
+                    .record_adaptive_compression(outcome.initial_bytes, outcome.final_bytes, outcome.iters);
+                tracing::warn!(
+                    conversation_id = kiro_request.conversation_state.conversation_id.as_str(),
+                    initial_bytes = outcome.initial_bytes,
+                    final_bytes = outcome.final_bytes,
+                    threshold = max_body,
+                    iters = outcome.iters,
+                    token_budget = ?token_budget_limit,
+                    final_input_tokens = outcome.final_input_tokens,
+                    "请求体超过阈值，已执行自适应二次压缩"
+                );
+                emit_adaptive_shrink_diagnostics(
+                    &state.diagnostics,
+                    kiro_request.conversation_state.conversation_id.as_str(),
+                    &outcome,
+                    max_body,
+                    token_budget_limit,
+                );
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!("自适应二次压缩序列化失败: {}", e);
+                send_ws_error(&mut socket, "internal_error", format!("序列化请求失败: {}", e)).await;
+                return;
+            }
+        }
+    }
+
+    let final_img_bytes = total_image_bytes(&kiro_request);
+    let final_effective_len = request_body.len().saturating_sub(final_img_bytes);
+    if max_body > 0 && request_body.len() > max_body {
+        state.metrics.requests_rejected_too_large_total.inc();
+        tracing::warn!(
+            conversation_id = kiro_request.conversation_state.conversation_id.as_str(),
+            request_body_bytes = request_body.len(),
+            image_bytes = final_img_bytes,
+            effective_bytes = final_effective_len,
+            threshold = max_body,
+            "请求体超过安全阈值，拒绝发送"
+        );
+        emit_request_rejected_diagnostics(
+            &state.diagnostics,
+            kiro_request.conversation_state.conversation_id.as_str(),
+            request_body.len(),
+            final_img_bytes,
+            final_effective_len,
+            max_body,
+        );
+        send_ws_error(
+            &mut socket,
+            "invalid_request_error",
+            format!(
+                "Request too large ({} bytes total; images {} bytes; non-image {} bytes; limit {}). Reduce conversation history/tool output or number/size of images.",
+                request_body.len(),
+                final_img_bytes,
+                final_effective_len,
+                max_body
+            ),
+        )
+        .await;
+        return;
+    }
+
+    let thinking_enabled = payload
+        .thinking
+        .as_ref()
+        .map(|t| t.is_enabled())
+        .unwrap_or(false);
+
+    // 与 `post_messages` 保持一致：WebSocket 会话同样占用全局并发槽位并受
+    // 请求级墙钟超时约束，只是拒绝/超时的收尾方式换成了 WS 错误帧/超时事件
+    let permit = match try_acquire_request_permit(&state).await {
+        Ok(permit) => permit,
+        Err(_response) => {
+            send_ws_error(
+                &mut socket,
+                "rate_limit_error",
+                "Too many concurrent requests in flight. Please retry shortly.",
+            )
+            .await;
+            return;
+        }
+    };
+    let deadline = request_deadline(&state);
+
+    let response = match provider.call_api_stream(&request_body, user_id.as_deref()).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            send_ws_provider_error(&mut socket, &request_body, e, &state.quota_tracker).await;
+            return;
+        }
+    };
+
+    let mut ctx = StreamContext::new_with_thinking(&payload.model, estimated_input_tokens, thinking_enabled);
+    for event in ctx.generate_initial_events() {
+        if send_ws_event(&mut socket, &event).await.is_err() {
+            return;
+        }
+    }
+
+    let resume = StreamResumeState {
+        provider,
+        request_body,
+        model: payload.model.clone(),
+        user_id,
+        attempts: 0,
+        metrics: state.metrics.clone(),
+        filters: state.filters.clone(),
+    };
+    // 并发槽位守卫随事件循环一起移入，直到会话结束（正常/异常/超时/客户端
+    // 关闭/优雅关闭收尾）才释放，而不是在发起上游调用后立即释放
+    run_ws_event_loop(&mut socket, response, ctx, resume, state.shutdown.clone(), permit, deadline).await;
+}
+
+/// WebSocket 版本的上游事件转发循环
+///
+/// 解码/过滤/异常中断重试续写的状态机与 `create_sse_stream` 共用同一个
+/// `advance_stream_state`（唯一的差异来源已收敛到那一处），这里只是以命令式
+/// `loop { select! }` 驱动，用原生 ping 帧替代 SSE 的 ping 事件，并额外检测
+/// 客户端关闭；优雅关闭与整体超时的收尾事件与 SSE 传输相同
+async fn run_ws_event_loop(
+    socket: &mut WebSocket,
+    response: crate::kiro::provider::ProviderByteStream,
+    mut ctx: StreamContext,
+    mut resume: StreamResumeState,
+    shutdown: std::sync::Arc<crate::common::shutdown::ShutdownCoordinator>,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+    deadline: Instant,
+) {
+    let mut body_stream = response;
+    let mut decoder = EventStreamDecoder::new();
+    let ping_period = Duration::from_secs(PING_INTERVAL_SECS);
+    let mut ping_interval = interval_at(Instant::now() + ping_period, ping_period);
+    let _drain_guard = shutdown.register_stream();
+    let mut shutdown_rx = shutdown.subscribe();
+
+    loop {
+        tokio::select! {
+            // 解码/过滤/恢复状态机与 SSE 传输（`create_sse_stream`）共享同一个
+            // `advance_stream_state`，这里只负责把结果转换为 WS 帧
+            chunk_result = body_stream.next() => {
+                match advance_stream_state(chunk_result, &mut body_stream, &mut decoder, &mut ctx, &mut resume).await {
+                    StreamAdvance::Continue(events) => {
+                        for sse_event in events {
+                            if send_ws_event(socket, &sse_event).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    StreamAdvance::DecodeOverflow(message) => {
+                        send_ws_error(socket, "overloaded_error", format!("上游响应解码失败（缓冲区溢出）: {}", message)).await;
+                        return;
+                    }
+                    StreamAdvance::Terminal(events) => {
+                        for event in events {
+                            if send_ws_event(socket, &event).await.is_err() {
+                                return;
+                            }
+                        }
+                        let _ = socket.send(WsMessage::Close(None)).await;
+                        return;
+                    }
+                    StreamAdvance::Resumed => {}
+                }
+            }
+            _ = ping_interval.tick() => {
+                tracing::trace!("发送 WebSocket ping 保活帧");
+                resume.metrics.ping_keepalives_total.inc();
+                if socket.send(WsMessage::Ping(Bytes::new())).await.is_err() {
+                    return;
+                }
+            }
+            changed = shutdown_rx.changed() => {
+                if changed.is_ok() && *shutdown_rx.borrow() {
+                    tracing::info!("收到优雅关闭信号，WebSocket 流式会话进入收尾");
+                    for event in ctx.generate_draining_final_events() {
+                        if send_ws_event(socket, &event).await.is_err() {
+                            return;
+                        }
+                    }
+                    let _ = socket.send(WsMessage::Close(None)).await;
+                    return;
+                }
+            }
+            // 整体请求超时：停止拉取上游数据，以独立的超时 stop_reason 收尾
+            _ = tokio::time::sleep_until(deadline) => {
+                tracing::warn!("请求超过配置的超时时长，WebSocket 流式会话进入收尾");
+                for event in ctx.generate_timeout_final_events() {
+                    if send_ws_event(socket, &event).await.is_err() {
+                        return;
+                    }
+                }
+                let _ = socket.send(WsMessage::Close(None)).await;
+                return;
+            }
+            incoming = socket.recv() => {
+                match incoming {
+                    Some(Ok(WsMessage::Close(_))) | None => {
+                        tracing::info!("客户端关闭 WebSocket 连接，中止上游请求");
+                        return;
+                    }
+                    Some(Err(e)) => {
+                        tracing::warn!(error = %e, "WebSocket 读取失败，中止上游请求");
+                        return;
+                    }
+                    _ => {
+                        // 忽略客户端在流式过程中发送的其他帧（如 Pong、多余文本帧）
+                    }
+                }
+            }
+        }
+    }
+}
+
 /// 处理非流式请求
 async fn handle_non_stream_request(
-    provider: std::sync::Arc<crate::kiro::provider::KiroProvider>,
+    provider: std::sync::Arc<dyn crate::kiro::provider::KiroProviderApi>,
     request_body: &str,
     model: &str,
     input_tokens: i32,
     user_id: Option<&str>,
+    quota_tracker: &crate::common::quota::QuotaTracker,
+    metrics: &crate::common::metrics::Metrics,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+    deadline: Instant,
+    filters: FilterPipeline,
 ) -> Response {
-    // 调用 Kiro API（支持多凭据故障转移）
-    let response = match provider.call_api(request_body, user_id).await {
-        Ok(resp) => resp,
-        Err(e) => return map_kiro_provider_error_to_response(request_body, e),
-    };
-
-    // 读取响应体
-    let body_bytes = match response.bytes().await {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            tracing::error!("读取响应体失败: {}", e);
+    // 调用 Kiro API（支持多凭据故障转移）并读取完整响应体，整体请求不得超过
+    // 配置的超时时长。`KiroProviderApi::call_api` 直接返回完整 body（不再是
+    // "先拿 reqwest::Response 再单独读 body" 的两段式），读取响应体失败会
+    // 体现为 `Err`，与上游调用失败走同一条 `map_kiro_provider_error_to_response`
+    // 分类路径。
+    let body_bytes = match tokio::time::timeout_at(deadline, provider.call_api(request_body, user_id)).await {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(e)) => return map_kiro_provider_error_to_response(request_body, e, quota_tracker),
+        Err(_) => {
+            tracing::warn!("请求超过配置的超时时长，中止上游调用");
             return (
-                StatusCode::BAD_GATEWAY,
+                StatusCode::GATEWAY_TIMEOUT,
                 Json(ErrorResponse::new(
-                    "api_error",
-                    format!("读取响应失败: {}", e),
+                    "timeout_error",
+                    "Upstream request exceeded the configured timeout.",
                 )),
             )
                 .into_response();
@@ -963,7 +2205,15 @@ async fn handle_non_stream_request(
     // 解析事件流
     let mut decoder = EventStreamDecoder::new();
     if let Err(e) = decoder.feed(&body_bytes) {
-        tracing::warn!("缓冲区溢出: {}", e);
+        tracing::error!("事件解码缓冲区溢出，响应体可能已损坏或格式异常: {}", e);
+        return (
+            StatusCode::BAD_GATEWAY,
+            Json(ErrorResponse::new(
+                "api_error",
+                format!("上游响应解码失败（缓冲区溢出）: {}", e),
+            )),
+        )
+            .into_response();
     }
 
     let mut text_content = String::new();
@@ -980,7 +2230,8 @@ async fn handle_non_stream_request(
     for result in decoder.decode_iter() {
         match result {
             Ok(frame) => {
-                if let Ok(event) = Event::from_frame(frame) {
+                if let Ok(mut event) = Event::from_frame(frame) {
+                    filters.run_event_filters(&mut event);
                     match event {
                         Event::AssistantResponse(resp) => {
                             text_content.push_str(&resp.content);
@@ -1002,6 +2253,7 @@ async fn handle_non_stream_request(
                                     serde_json::json!({})
                                 } else {
                                     serde_json::from_str(buffer).unwrap_or_else(|e| {
+                                        metrics.tool_input_parse_failures_total.inc();
                                         // 检测是否为截断导致的解析失败
                                         if let Some(truncation_info) =
                                             super::truncation::detect_truncation(
@@ -1010,6 +2262,7 @@ async fn handle_non_stream_request(
                                                 buffer,
                                             )
                                         {
+                                            metrics.tool_input_truncations_detected_total.inc();
                                             let soft_msg =
                                                 super::truncation::build_soft_failure_result(
                                                     &truncation_info,
@@ -1059,6 +2312,9 @@ async fn handle_non_stream_request(
                                 (context_usage.context_usage_percentage * context_window / 100.0)
                                     as i32;
                             context_input_tokens = Some(actual_input_tokens);
+                            metrics
+                                .context_usage_input_tokens
+                                .observe(actual_input_tokens.max(0) as u64);
                             // 上下文使用量达到 100% 时，设置 stop_reason 为 model_context_window_exceeded
                             if context_usage.context_usage_percentage >= 100.0 {
                                 stop_reason = "model_context_window_exceeded".to_string();
@@ -1213,10 +2469,16 @@ pub async fn count_tokens(
 /// POST /cc/v1/messages
 ///
 /// Claude Code 兼容端点，与 /v1/messages 的区别在于：
-/// - 流式响应会等待 kiro 端返回 contextUsageEvent 后再发送 message_start
-/// - message_start 中的 input_tokens 是从 contextUsageEvent 计算的准确值
+/// - 默认（缓冲模式）流式响应会等待 kiro 端返回 contextUsageEvent 后再发送
+///   message_start，message_start 中的 input_tokens 是从 contextUsageEvent
+///   计算的准确值，但牺牲了首字节延迟
+/// - 增量模式（`cc_streaming.incrementalByDefault` 或请求头
+///   `X-Stream-Mode: incremental` 开启）立即按估算值发送 message_start，
+///   随后像 /v1/messages 一样实时转发事件，contextUsageEvent 到达后的精确
+///   input_tokens/output_tokens 改为携带在收尾 message_delta 的 usage 中
 pub async fn post_messages_cc(
     State(state): State<AppState>,
+    headers: HeaderMap,
     JsonExtractor(mut payload): JsonExtractor<MessagesRequest>,
 ) -> Response {
     // 检查 KiroProvider 是否可用
@@ -1255,6 +2517,35 @@ pub async fn post_messages_cc(
         "Received POST /cc/v1/messages request"
     );
 
+    state.metrics.record_messages_request(true, payload.stream);
+    state
+        .metrics
+        .estimated_input_tokens
+        .observe(estimated_input_tokens.max(0) as u64);
+
+    // 按 user_id 的请求/token 预算：在派发到上游前提前拒绝，避免单个用户的
+    // 突发流量耗尽全部凭据配额
+    let cc_user_id = payload.metadata.as_ref().and_then(|m| m.user_id.clone());
+    if let Some(quota_config) = &state.user_quota_config
+        && let Some(uid) = cc_user_id.as_deref()
+        && !state
+            .user_budget_tracker
+            .try_consume(uid, quota_config, estimated_input_tokens.max(0) as u64)
+    {
+        tracing::warn!(
+            user_id = %mask_user_id(Some(uid)),
+            "用户请求/token 预算超限，提前拒绝"
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(ErrorResponse::new(
+                "rate_limit_error",
+                "Per-user request/token budget exceeded. Please slow down or wait for the budget window to reset.",
+            )),
+        )
+            .into_response();
+    }
+
     // 检查是否为纯 WebSearch 请求（仅 web_search 单工具 / tool_choice 强制 / 前缀匹配）
     if websearch::should_handle_websearch_request(&payload) {
         tracing::info!("检测到纯 WebSearch 请求，路由到本地 WebSearch 处理");
@@ -1312,6 +2603,7 @@ pub async fn post_messages_cc(
         conversation_state: conversion_result.conversation_state,
         profile_arn: state.profile_arn.clone(),
     };
+    state.filters.run_request_filters(&mut kiro_request);
 
     let mut request_body = match serde_json::to_string(&kiro_request) {
         Ok(body) => body,
@@ -1330,15 +2622,30 @@ pub async fn post_messages_cc(
 
     // 请求体大小预检（上游存在硬性请求体大小限制；按实际序列化后的总字节数判断）
     let max_body = state.compression_config.max_request_body_bytes;
-    if max_body > 0 && request_body.len() > max_body && state.compression_config.enabled {
+    let byte_over_budget = max_body > 0 && request_body.len() > max_body;
+    emit_size_precheck_diagnostics(
+        &state.diagnostics,
+        kiro_request.conversation_state.conversation_id.as_str(),
+        request_body.len(),
+        max_body,
+        byte_over_budget,
+        false,
+        None,
+    );
+    if byte_over_budget && state.compression_config.enabled {
         // 自适应二次压缩：按 request_body_bytes 迭代截断，尽量把请求缩到阈值内
         match adaptive_shrink_request_body(
             &mut kiro_request,
             &state.compression_config,
             max_body,
             &mut request_body,
+            state.turn_embedder.as_deref(),
+            None,
         ) {
             Ok(Some(outcome)) => {
+                state
+                    .metrics
+                    .record_adaptive_compression(outcome.initial_bytes, outcome.final_bytes, outcome.iters);
                 tracing::warn!(
                     conversation_id = kiro_request.conversation_state.conversation_id.as_str(),
                     initial_bytes = outcome.initial_bytes,
@@ -1351,6 +2658,13 @@ pub async fn post_messages_cc(
                     final_message_content_max_chars = outcome.final_message_content_max_chars,
                     "请求体超过阈值，已执行自适应二次压缩"
                 );
+                emit_adaptive_shrink_diagnostics(
+                    &state.diagnostics,
+                    kiro_request.conversation_state.conversation_id.as_str(),
+                    &outcome,
+                    max_body,
+                    None,
+                );
             }
             Ok(None) => {}
             Err(e) => {
@@ -1371,6 +2685,7 @@ pub async fn post_messages_cc(
     let final_img_bytes = total_image_bytes(&kiro_request);
     let final_effective_len = request_body.len().saturating_sub(final_img_bytes);
     if max_body > 0 && request_body.len() > max_body {
+        state.metrics.requests_rejected_too_large_total.inc();
         tracing::warn!(
             conversation_id = kiro_request.conversation_state.conversation_id.as_str(),
             request_body_bytes = request_body.len(),
@@ -1379,6 +2694,14 @@ pub async fn post_messages_cc(
             threshold = max_body,
             "请求体超过安全阈值，拒绝发送"
         );
+        emit_request_rejected_diagnostics(
+            &state.diagnostics,
+            kiro_request.conversation_state.conversation_id.as_str(),
+            request_body.len(),
+            final_img_bytes,
+            final_effective_len,
+            max_body,
+        );
         #[cfg(feature = "sensitive-logs")]
         tracing::error!(
             "自适应压缩仍超限，完整请求体（用于诊断）: {}",
@@ -1412,18 +2735,52 @@ pub async fn post_messages_cc(
         .map(|t| t.is_enabled())
         .unwrap_or(false);
 
+    let permit = match try_acquire_request_permit(&state).await {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+    let deadline = request_deadline(&state);
+
     if payload.stream {
-        // 流式响应（缓冲模式）
         let user_id = payload.metadata.as_ref().and_then(|m| m.user_id.as_deref());
-        handle_stream_request_buffered(
-            provider,
-            &request_body,
-            &payload.model,
-            estimated_input_tokens,
-            thinking_enabled,
-            user_id,
-        )
-        .await
+        if resolve_cc_incremental_streaming(&state, &headers) {
+            // 增量模式：复用 /v1/messages 的实时转发流程，仅额外开启
+            // contextUsageEvent 校正，收尾 message_delta 携带精确 usage
+            handle_stream_request(
+                provider,
+                &request_body,
+                &payload.model,
+                estimated_input_tokens,
+                thinking_enabled,
+                user_id,
+                &state.quota_tracker,
+                state.metrics.clone(),
+                state.shutdown.clone(),
+                permit,
+                deadline,
+                true,
+                state.filters.clone(),
+            )
+            .await
+        } else {
+            // 缓冲模式（默认）：等待 contextUsageEvent 后再发送 message_start
+            handle_stream_request_buffered(
+                provider,
+                &request_body,
+                &payload.model,
+                estimated_input_tokens,
+                thinking_enabled,
+                user_id,
+                &state.quota_tracker,
+                state.metrics.clone(),
+                state.shutdown.clone(),
+                permit,
+                deadline,
+                state.filters.clone(),
+                state.cc_streaming.max_buffered_response_bytes,
+            )
+            .await
+        }
     } else {
         // 非流式响应（复用现有逻辑，已经使用正确的 input_tokens）
         let user_id = payload.metadata.as_ref().and_then(|m| m.user_id.as_deref());
@@ -1433,6 +2790,11 @@ pub async fn post_messages_cc(
             &payload.model,
             estimated_input_tokens,
             user_id,
+            &state.quota_tracker,
+            &state.metrics,
+            permit,
+            deadline,
+            state.filters.clone(),
         )
         .await
     }
@@ -1443,24 +2805,41 @@ pub async fn post_messages_cc(
 /// 与 `handle_stream_request` 不同，此函数会缓冲所有事件直到流结束，
 /// 然后用从 contextUsageEvent 计算的正确 input_tokens 生成 message_start 事件。
 async fn handle_stream_request_buffered(
-    provider: std::sync::Arc<crate::kiro::provider::KiroProvider>,
+    provider: std::sync::Arc<dyn crate::kiro::provider::KiroProviderApi>,
     request_body: &str,
     model: &str,
     estimated_input_tokens: i32,
     thinking_enabled: bool,
     user_id: Option<&str>,
+    quota_tracker: &crate::common::quota::QuotaTracker,
+    metrics: std::sync::Arc<crate::common::metrics::Metrics>,
+    shutdown: std::sync::Arc<crate::common::shutdown::ShutdownCoordinator>,
+    permit: tokio::sync::OwnedSemaphorePermit,
+    deadline: Instant,
+    filters: FilterPipeline,
+    max_buffered_bytes: usize,
 ) -> Response {
     // 调用 Kiro API（支持多凭据故障转移）
     let response = match provider.call_api_stream(request_body, user_id).await {
         Ok(resp) => resp,
-        Err(e) => return map_kiro_provider_error_to_response(request_body, e),
+        Err(e) => return map_kiro_provider_error_to_response(request_body, e, quota_tracker),
     };
 
     // 创建缓冲流处理上下文
     let ctx = BufferedStreamContext::new(model, estimated_input_tokens, thinking_enabled);
 
-    // 创建缓冲 SSE 流
-    let stream = create_buffered_sse_stream(response, ctx);
+    // 创建缓冲 SSE 流（并发槽位守卫随流一起移入，直到收尾才释放）
+    let stream = create_buffered_sse_stream(
+        response,
+        ctx,
+        metrics,
+        shutdown,
+        permit,
+        deadline,
+        filters,
+        estimated_input_tokens,
+        max_buffered_bytes,
+    );
 
     // 返回 SSE 响应
     Response::builder()
@@ -1472,6 +2851,63 @@ async fn handle_stream_request_buffered(
         .unwrap()
 }
 
+/// 缓冲流的运行模式：正常累积，或因超过 `max_buffered_bytes` 已降级为实时透传
+///
+/// `Buffering` 持有原始 `BufferedStreamContext`；一旦累积字节数超过配置上限，
+/// `degrade_to_passthrough` 把已缓冲内容（按估算 `input_tokens` 生成
+/// `message_start`）立即落盘为待发送事件，并转入 `PassThrough`——后续事件走
+/// 与增量模式（`StreamContext::process_kiro_event`）一致的实时转发路径，
+/// 不再继续无界累积内存。
+enum BufferedMode {
+    Buffering(BufferedStreamContext),
+    PassThrough(StreamContext),
+}
+
+impl BufferedMode {
+    /// 当前已缓冲的字节数；已降级为透传模式后恒为 0（不再缓冲）
+    fn buffered_bytes(&self) -> usize {
+        match self {
+            BufferedMode::Buffering(ctx) => ctx.buffered_bytes(),
+            BufferedMode::PassThrough(_) => 0,
+        }
+    }
+
+    /// 处理一个上游事件：缓冲模式下按原逻辑累积且不返回事件，
+    /// 透传模式下与实时流一致，立即返回要发送的 SSE 事件
+    fn handle_event(&mut self, event: &Event) -> Vec<SseEvent> {
+        match self {
+            BufferedMode::Buffering(ctx) => {
+                ctx.process_and_buffer(event);
+                Vec::new()
+            }
+            BufferedMode::PassThrough(ctx) => ctx.process_kiro_event(event),
+        }
+    }
+
+    /// 超过内存上限时调用一次：把已缓冲内容按估算 `input_tokens` 落盘为待发送事件，
+    /// 并把自身降级为透传模式；已处于透传模式时是空操作
+    fn degrade_to_passthrough(&mut self, estimated_input_tokens: i32) -> Vec<SseEvent> {
+        if !matches!(self, BufferedMode::Buffering(_)) {
+            return Vec::new();
+        }
+        let placeholder = BufferedMode::PassThrough(StreamContext::new_with_thinking("", 0, false));
+        let BufferedMode::Buffering(ctx) = std::mem::replace(self, placeholder) else {
+            unreachable!("刚判断过处于 Buffering 状态")
+        };
+        let (flush_events, live_ctx) = ctx.into_live_context(estimated_input_tokens);
+        *self = BufferedMode::PassThrough(live_ctx);
+        flush_events
+    }
+
+    /// 流正常/异常结束时生成收尾事件
+    fn finish_and_get_all_events(&mut self) -> Vec<SseEvent> {
+        match self {
+            BufferedMode::Buffering(ctx) => ctx.finish_and_get_all_events(),
+            BufferedMode::PassThrough(ctx) => ctx.generate_final_events(),
+        }
+    }
+}
+
 /// 创建缓冲 SSE 事件流
 ///
 /// 工作流程：
@@ -1479,23 +2915,44 @@ async fn handle_stream_request_buffered(
 /// 2. 使用 StreamContext 的事件处理逻辑处理所有 Kiro 事件，结果缓存
 /// 3. 流结束后，用正确的 input_tokens 更正 message_start 事件
 /// 4. 一次性发送所有事件
+///
+/// 若累积的缓冲字节数超过 `max_buffered_bytes`，放弃继续缓冲：立即把已缓冲
+/// 内容（按 `estimated_input_tokens` 生成 `message_start`）下发，并把剩余
+/// 响应降级为与增量模式一致的实时透传，避免超大响应无界占用内存。
 fn create_buffered_sse_stream(
-    response: reqwest::Response,
+    response: crate::kiro::provider::ProviderByteStream,
     ctx: BufferedStreamContext,
+    metrics: std::sync::Arc<crate::common::metrics::Metrics>,
+    shutdown: std::sync::Arc<crate::common::shutdown::ShutdownCoordinator>,
+    permit: tokio::sync::OwnedSemaphorePermit,
+    deadline: Instant,
+    filters: FilterPipeline,
+    estimated_input_tokens: i32,
+    max_buffered_bytes: usize,
 ) -> impl Stream<Item = Result<Bytes, Infallible>> {
-    let body_stream = response.bytes_stream();
+    let body_stream = response;
     let ping_period = Duration::from_secs(PING_INTERVAL_SECS);
     let ping_interval = interval_at(Instant::now() + ping_period, ping_period);
 
+    let drain_guard = shutdown.register_stream();
+    let shutdown_rx = shutdown.subscribe();
+    let mode = BufferedMode::Buffering(ctx);
+
     stream::unfold(
         (
             body_stream,
-            ctx,
+            mode,
             EventStreamDecoder::new(),
             false,
             ping_interval,
+            metrics,
+            shutdown_rx,
+            drain_guard,
+            permit,
+            deadline,
+            filters,
         ),
-        |(mut body_stream, mut ctx, mut decoder, finished, mut ping_interval)| async move {
+        |(mut body_stream, mut mode, mut decoder, finished, mut ping_interval, metrics, mut shutdown_rx, drain_guard, permit, deadline, filters)| async move {
             if finished {
                 return None;
             }
@@ -1509,8 +2966,36 @@ fn create_buffered_sse_stream(
                     // 优先检查 ping 保活（等待期间唯一发送的数据）
                     _ = ping_interval.tick() => {
                         tracing::trace!("发送 ping 保活事件（缓冲模式）");
+                        metrics.ping_keepalives_total.inc();
                         let bytes: Vec<Result<Bytes, Infallible>> = vec![Ok(create_ping_sse())];
-                        return Some((stream::iter(bytes), (body_stream, ctx, decoder, false, ping_interval)));
+                        return Some((stream::iter(bytes), (body_stream, mode, decoder, false, ping_interval, metrics, shutdown_rx, drain_guard, permit, deadline, filters)));
+                    }
+
+                    // 优雅关闭：停止继续缓冲上游 chunk，立即按已缓冲内容收尾
+                    // （缓冲模式没有独立的"收尾 stop_reason"变体，直接复用
+                    // 正常结束时的 finish_and_get_all_events）
+                    changed = shutdown_rx.changed() => {
+                        if changed.is_ok() && *shutdown_rx.borrow() {
+                            tracing::info!("收到优雅关闭信号，缓冲流式响应进入收尾");
+                            let all_events = mode.finish_and_get_all_events();
+                            let bytes: Vec<Result<Bytes, Infallible>> = all_events
+                                .into_iter()
+                                .map(|e| Ok(Bytes::from(e.to_sse_string())))
+                                .collect();
+                            return Some((stream::iter(bytes), (body_stream, mode, decoder, true, ping_interval, metrics, shutdown_rx, drain_guard, permit, deadline, filters)));
+                        }
+                    }
+
+                    // 整体请求超时：停止继续缓冲上游 chunk，立即按已缓冲内容收尾
+                    // （同样没有独立的"超时"变体，直接复用 finish_and_get_all_events）
+                    _ = tokio::time::sleep_until(deadline) => {
+                        tracing::warn!("请求超过配置的超时时长，缓冲流式响应进入收尾");
+                        let all_events = mode.finish_and_get_all_events();
+                        let bytes: Vec<Result<Bytes, Infallible>> = all_events
+                            .into_iter()
+                            .map(|e| Ok(Bytes::from(e.to_sse_string())))
+                            .collect();
+                        return Some((stream::iter(bytes), (body_stream, mode, decoder, true, ping_interval, metrics, shutdown_rx, drain_guard, permit, deadline, filters)));
                     }
 
                     // 然后处理数据流
@@ -1519,15 +3004,19 @@ fn create_buffered_sse_stream(
                             Some(Ok(chunk)) => {
                                 // 解码事件
                                 if let Err(e) = decoder.feed(&chunk) {
-                                    tracing::warn!("缓冲区溢出: {}", e);
+                                    tracing::error!("事件解码缓冲区溢出，已无法继续安全解析，终止流: {}", e);
+                                    let bytes: Vec<Result<Bytes, Infallible>> =
+                                        vec![Ok(decoder_overflow_sse(&e.to_string()))];
+                                    return Some((stream::iter(bytes), (body_stream, mode, decoder, true, ping_interval, metrics, shutdown_rx, drain_guard, permit, deadline, filters)));
                                 }
 
+                                let mut events: Vec<SseEvent> = Vec::new();
                                 for result in decoder.decode_iter() {
                                     match result {
                                         Ok(frame) => {
-                                            if let Ok(event) = Event::from_frame(frame) {
-                                                // 缓冲事件（复用 StreamContext 的处理逻辑）
-                                                ctx.process_and_buffer(&event);
+                                            if let Ok(mut event) = Event::from_frame(frame) {
+                                                filters.run_event_filters(&mut event);
+                                                events.extend(mode.handle_event(&event));
                                             }
                                         }
                                         Err(e) => {
@@ -1535,26 +3024,46 @@ fn create_buffered_sse_stream(
                                         }
                                     }
                                 }
-                                // 继续读取下一个 chunk，不发送任何数据
+
+                                // 仍处于缓冲模式时检查内存上限；超过则立即落盘已缓冲内容，
+                                // 并把剩余响应降级为实时透传
+                                if mode.buffered_bytes() > max_buffered_bytes {
+                                    let buffered_bytes = mode.buffered_bytes();
+                                    tracing::warn!(
+                                        buffered_bytes,
+                                        max_buffered_bytes,
+                                        "缓冲响应超过内存上限，放弃继续缓冲，降级为实时透传"
+                                    );
+                                    events.extend(mode.degrade_to_passthrough(estimated_input_tokens));
+                                }
+
+                                // 透传模式下事件需立即发送；缓冲模式下 events 恒为空，不发送任何数据
+                                let bytes: Vec<Result<Bytes, Infallible>> = events
+                                    .into_iter()
+                                    .map(|e| Ok(Bytes::from(e.to_sse_string())))
+                                    .collect();
+
+                                Some((stream::iter(bytes), (body_stream, mode, decoder, false, ping_interval, metrics, shutdown_rx, drain_guard, permit, deadline, filters)))
                             }
                             Some(Err(e)) => {
+                                metrics.upstream_stream_errors_total.inc();
                                 tracing::error!("读取响应流失败: {}", e);
                                 // 发生错误，完成处理并返回所有事件
-                                let all_events = ctx.finish_and_get_all_events();
+                                let all_events = mode.finish_and_get_all_events();
                                 let bytes: Vec<Result<Bytes, Infallible>> = all_events
                                     .into_iter()
                                     .map(|e| Ok(Bytes::from(e.to_sse_string())))
                                     .collect();
-                                return Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval)));
+                                return Some((stream::iter(bytes), (body_stream, mode, decoder, true, ping_interval, metrics, shutdown_rx, drain_guard, permit, deadline, filters)));
                             }
                             None => {
                                 // 流结束，完成处理并返回所有事件（已更正 input_tokens）
-                                let all_events = ctx.finish_and_get_all_events();
+                                let all_events = mode.finish_and_get_all_events();
                                 let bytes: Vec<Result<Bytes, Infallible>> = all_events
                                     .into_iter()
                                     .map(|e| Ok(Bytes::from(e.to_sse_string())))
                                     .collect();
-                                return Some((stream::iter(bytes), (body_stream, ctx, decoder, true, ping_interval)));
+                                return Some((stream::iter(bytes), (body_stream, mode, decoder, true, ping_interval, metrics, shutdown_rx, drain_guard, permit, deadline, filters)));
                             }
                         }
                     }
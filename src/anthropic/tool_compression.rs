@@ -1,95 +1,397 @@
 //! 工具定义压缩模块
 //!
-//! 当工具定义的总序列化大小超过阈值时，通过两步压缩减小体积：
+//! 当工具定义的总大小超过阈值时，依次尝试三步压缩减小体积：
+//! 0. 无损去重 `input_schema`：把单个工具自身 schema 树内重复出现的子
+//!    schema 提取到 `$defs`，原处替换为 `$ref`（见 [`dedup_schemas`]）
 //! 1. 简化 `input_schema`：移除非必要字段（description 等），仅保留结构骨架
-//! 2. 按比例截断 `description`：根据超出比例缩短描述，最短保留 50 字符
+//! 2. 按比例截断 `description`：根据超出比例缩短描述，最短保留
+//!    `MIN_DESCRIPTION_TOKENS` 个 token
+//!
+//! 大小按 token 数而非字节数衡量：字节数在 ASCII JSON 字段名与 CJK 描述间
+//! 的 bytes-per-token 差异很大，同一个字节阈值对中文工具描述会明显过度
+//! 压缩。实际估算通过 [`TokenEstimator`] 完成——默认使用
+//! [`crate::common::tokenizer::BpeTokenizer`] 按模型名选择的词表（o200k_base
+//! 或 cl100k_base），构造失败（词表加载出错）时回退到轻量启发式估算。
 
+use crate::common::tokenizer::BpeTokenizer;
 use crate::kiro::model::requests::tool::{InputSchema, Tool as KiroTool, ToolSpecification};
 
-/// 工具定义总大小阈值（20KB）
-const TOOL_SIZE_THRESHOLD: usize = 20 * 1024;
+/// 工具定义总大小阈值（token 数），等价于原先 20KB 字节阈值按约 4 bytes/token 换算
+const TOOL_SIZE_THRESHOLD: usize = 5120;
+
+/// description 最短保留 token 数
+const MIN_DESCRIPTION_TOKENS: usize = 20;
+
+/// 可插拔的 token 估算器
+///
+/// 允许在不改动压缩逻辑的前提下替换具体的计数/截断实现（例如测试中使用
+/// 确定性的启发式估算，而不必加载真实词表）。
+pub trait TokenEstimator {
+    /// 估算一段文本的 token 数
+    fn estimate(&self, text: &str) -> usize;
+    /// 把文本截断到不超过 `max_tokens` 个 token（UTF-8 安全）
+    fn truncate_to_budget(&self, text: &str, max_tokens: usize) -> String;
+}
+
+/// 基于 `BpeTokenizer`（按模型名选择 o200k_base 或 cl100k_base 词表）的精确估算器
+struct BpeTokenEstimator(BpeTokenizer);
+
+impl TokenEstimator for BpeTokenEstimator {
+    fn estimate(&self, text: &str) -> usize {
+        self.0.count(text)
+    }
 
-/// description 最短保留字符数
-const MIN_DESCRIPTION_CHARS: usize = 50;
+    fn truncate_to_budget(&self, text: &str, max_tokens: usize) -> String {
+        self.0.truncate_to_tokens(text, max_tokens).0
+    }
+}
 
-/// 如果工具定义总大小超过阈值，执行压缩
+/// 未配置/无法加载词表时的启发式兜底估算器
+///
+/// ASCII 文本按每 4 字节计 1 个 token 估算，多字节字符（CJK 等）按每字符计
+/// 1 个 token 估算——粗略但足以让阈值在多语言场景下保持量级正确。
+struct HeuristicTokenEstimator;
+
+impl HeuristicTokenEstimator {
+    /// 逐字符累计估算的 token 数，ASCII 每满 4 个计 1 token，
+    /// 多字节字符每个计 1 token，末尾不足 4 个的 ASCII 余量再计 1 token
+    fn count_up_to(text: &str, stop_at: Option<usize>) -> (usize, usize) {
+        let mut tokens = 0usize;
+        let mut ascii_run = 0usize;
+        for (idx, ch) in text.char_indices() {
+            if let Some(max_tokens) = stop_at {
+                if tokens >= max_tokens {
+                    return (tokens, idx);
+                }
+            }
+            if ch.is_ascii() {
+                ascii_run += 1;
+                if ascii_run == 4 {
+                    tokens += 1;
+                    ascii_run = 0;
+                }
+            } else {
+                tokens += 1;
+                ascii_run = 0;
+            }
+        }
+        if ascii_run > 0 {
+            tokens += 1;
+        }
+        (tokens, text.len())
+    }
+}
+
+impl TokenEstimator for HeuristicTokenEstimator {
+    fn estimate(&self, text: &str) -> usize {
+        Self::count_up_to(text, None).0
+    }
+
+    fn truncate_to_budget(&self, text: &str, max_tokens: usize) -> String {
+        let (_, byte_end) = Self::count_up_to(text, Some(max_tokens));
+        text[..byte_end].to_string()
+    }
+}
+
+/// 构造默认 token 估算器：优先使用 `model_name` 对应的 BPE 词表，
+/// 加载失败（罕见，如词表文件缺失）时记录告警并回退为启发式估算
+fn default_token_estimator(model_name: &str) -> Box<dyn TokenEstimator> {
+    match BpeTokenizer::for_model(model_name) {
+        Ok(tokenizer) => Box::new(BpeTokenEstimator(tokenizer)),
+        Err(e) => {
+            tracing::warn!(error = %e, model = model_name, "构造 BPE 分词器失败，工具压缩回退为启发式 token 估算");
+            Box::new(HeuristicTokenEstimator)
+        }
+    }
+}
+
+/// 如果工具定义总大小（token 数）超过阈值，执行压缩
 ///
 /// 返回压缩后的工具列表（如果未超阈值则原样返回）
-pub fn compress_tools_if_needed(tools: &[KiroTool]) -> Vec<KiroTool> {
-    let total_size = estimate_tools_size(tools);
-    if total_size <= TOOL_SIZE_THRESHOLD {
+pub fn compress_tools_if_needed(tools: &[KiroTool], model_name: &str) -> Vec<KiroTool> {
+    let estimator = default_token_estimator(model_name);
+    compress_tools_with_estimator(tools, estimator.as_ref())
+}
+
+/// 使用指定 [`TokenEstimator`] 压缩工具定义，供需要自定义估算器的调用方
+/// （如测试）直接复用压缩逻辑
+pub fn compress_tools_with_estimator(tools: &[KiroTool], estimator: &dyn TokenEstimator) -> Vec<KiroTool> {
+    let total_tokens = estimate_tools_size(tools, estimator);
+    if total_tokens <= TOOL_SIZE_THRESHOLD {
         return tools.to_vec();
     }
 
     tracing::info!(
-        total_size,
+        total_tokens,
         threshold = TOOL_SIZE_THRESHOLD,
         tool_count = tools.len(),
         "工具定义超过阈值，开始压缩"
     );
 
+    // 第 0 步：无损去重（$defs/$ref），不丢失任何信息，优先于有损简化尝试
+    let deduped = dedup_schemas(tools);
+    let tokens_after_dedup = estimate_tools_size(&deduped, estimator);
+    if tokens_after_dedup <= TOOL_SIZE_THRESHOLD {
+        tracing::info!(
+            original_tokens = total_tokens,
+            deduped_tokens = tokens_after_dedup,
+            "schema 去重后已低于阈值，未进行有损压缩"
+        );
+        return deduped;
+    }
+
     // 第一步：简化 input_schema
-    let mut compressed: Vec<KiroTool> = tools.iter().map(simplify_schema).collect();
+    let mut compressed: Vec<KiroTool> = deduped.iter().map(simplify_schema).collect();
 
-    let size_after_schema = estimate_tools_size(&compressed);
-    if size_after_schema <= TOOL_SIZE_THRESHOLD {
+    let tokens_after_schema = estimate_tools_size(&compressed, estimator);
+    if tokens_after_schema <= TOOL_SIZE_THRESHOLD {
         tracing::info!(
-            original_size = total_size,
-            compressed_size = size_after_schema,
+            original_tokens = total_tokens,
+            compressed_tokens = tokens_after_schema,
             "schema 简化后已低于阈值"
         );
         return compressed;
     }
-    // 第二步：按比例截断 description（基于字节大小）
-    let ratio = TOOL_SIZE_THRESHOLD as f64 / size_after_schema as f64;
+    // 第二步：按比例截断 description（基于 token 数）
+    let ratio = TOOL_SIZE_THRESHOLD as f64 / tokens_after_schema as f64;
     for tool in &mut compressed {
         let desc = &tool.tool_specification.description;
-        let target_bytes = (desc.len() as f64 * ratio) as usize;
-        // 最短保留 MIN_DESCRIPTION_CHARS 个字符对应的字节数（至少 50 字符）
-        let min_bytes = desc
-            .char_indices()
-            .nth(MIN_DESCRIPTION_CHARS)
-            .map(|(idx, _)| idx)
-            .unwrap_or(desc.len());
-        let target_bytes = target_bytes.max(min_bytes);
-        if desc.len() > target_bytes {
-            // UTF-8 安全截断：找到不超过 target_bytes 的最大字符边界
-            let truncate_at = desc
-                .char_indices()
-                .take_while(|(idx, _)| *idx <= target_bytes)
-                .last()
-                .map(|(idx, ch)| idx + ch.len_utf8())
-                .unwrap_or(0);
-            tool.tool_specification.description = desc[..truncate_at].to_string();
+        let desc_tokens = estimator.estimate(desc);
+        let target_tokens = ((desc_tokens as f64 * ratio) as usize).max(MIN_DESCRIPTION_TOKENS);
+        if desc_tokens > target_tokens {
+            tool.tool_specification.description = estimator.truncate_to_budget(desc, target_tokens);
         }
     }
 
-    let final_size = estimate_tools_size(&compressed);
+    let final_tokens = estimate_tools_size(&compressed, estimator);
     tracing::info!(
-        original_size = total_size,
-        after_schema = size_after_schema,
-        final_size,
+        original_tokens = total_tokens,
+        after_schema = tokens_after_schema,
+        final_tokens,
         "工具压缩完成"
     );
 
     compressed
 }
 
-/// 估算工具列表的总序列化大小（字节）
-fn estimate_tools_size(tools: &[KiroTool]) -> usize {
+/// 估算工具列表的总大小（token 数）
+fn estimate_tools_size(tools: &[KiroTool], estimator: &dyn TokenEstimator) -> usize {
     tools
         .iter()
         .map(|t| {
             let spec = &t.tool_specification;
-            spec.name.len()
-                + spec.description.len()
+            estimator.estimate(&spec.name)
+                + estimator.estimate(&spec.description)
                 + serde_json::to_string(&spec.input_schema.json)
-                    .map(|s| s.len())
+                    .map(|s| estimator.estimate(&s))
                     .unwrap_or(0)
         })
         .sum()
 }
 
+/// 子 schema 去重候选的最小规范化序列化长度（字节）
+///
+/// 太小的子 schema（如裸 `{"type":"string"}`）去重后引入的 `$ref` 间接层
+/// 本身就有几十字节的开销，收益抵不过节省的重复内容，所以只对足够"具体"
+/// 的子 schema（通常是带多个属性的对象，如 path/range/options 参数）去重。
+const MIN_DEDUP_CANDIDATE_BYTES: usize = 60;
+
+/// 对每个工具各自的 `input_schema` 做无损去重：把该工具自身 schema 树内
+/// （`properties`/`items` 任意深度）重复出现 ≥2 次的子 schema 提取到该
+/// 工具 schema 根部新增的 `$defs` 里，原处替换为 `{"$ref": "#/$defs/DefN"}`。
+///
+/// `$ref` 按 JSON Schema 语义只能解析同一份文档内的 `$defs`，而这里每个
+/// 工具的 `input_schema.json` 都是独立下发给上游模型的自包含文档，彼此之间
+/// 没有共享的顶层容器可以让 `$ref` 跨工具解析——因此去重只在单个工具自己
+/// 的 schema 树内统计重复次数，不跨工具合并候选（跨工具的同名字段，如多个
+/// 工具各自都有的 `path` 属性，只出现一次时无法无损去重，仍原样保留）。
+pub(crate) fn dedup_schemas(tools: &[KiroTool]) -> Vec<KiroTool> {
+    tools
+        .iter()
+        .map(|tool| {
+            let deduped_schema = dedup_schema(&tool.tool_specification.input_schema.json);
+            KiroTool {
+                tool_specification: ToolSpecification {
+                    name: tool.tool_specification.name.clone(),
+                    description: tool.tool_specification.description.clone(),
+                    input_schema: InputSchema::from_json(deduped_schema),
+                },
+            }
+        })
+        .collect()
+}
+
+/// 对单个工具的 schema 树执行去重，返回（必要时）带 `$defs` 的新 schema
+fn dedup_schema(schema: &serde_json::Value) -> serde_json::Value {
+    let mut counts: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    collect_dedup_candidates(schema, &mut counts);
+
+    let dup_keys: std::collections::HashSet<String> = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= 2)
+        .map(|(key, _)| key)
+        .collect();
+
+    if dup_keys.is_empty() {
+        return schema.clone();
+    }
+
+    // 按首次遇到的顺序分配确定性的 $defs 名字（HashSet 的迭代顺序不稳定，
+    // 不能直接用来命名，否则同样的输入两次运行会得到不同的 $defs 名）
+    let mut def_names: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+    assign_def_names(schema, &dup_keys, &mut def_names);
+
+    let mut defs = serde_json::Map::new();
+    let rewritten = rewrite_schema_with_refs(schema, &dup_keys, &def_names, &mut defs);
+
+    let Some(obj) = rewritten.as_object() else {
+        return rewritten;
+    };
+    let mut obj = obj.clone();
+    obj.insert("$defs".to_string(), serde_json::Value::Object(defs));
+    serde_json::Value::Object(obj)
+}
+
+/// 规范化一个 JSON 值：对象的 key 按字典序排序，使结构相同但字段顺序不同的
+/// 子 schema 被视为同一个去重候选（序列化后字符串相等即视为结构相同）
+fn canonicalize(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let sorted: std::collections::BTreeMap<String, serde_json::Value> =
+                map.iter().map(|(k, v)| (k.clone(), canonicalize(v))).collect();
+            serde_json::Value::Object(sorted.into_iter().collect())
+        }
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.iter().map(canonicalize).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+fn canonical_key(value: &serde_json::Value) -> String {
+    serde_json::to_string(&canonicalize(value)).unwrap_or_default()
+}
+
+/// 递归收集某个 schema 节点下 `properties`/`items` 子 schema 的出现次数
+fn collect_dedup_candidates(schema: &serde_json::Value, counts: &mut std::collections::HashMap<String, usize>) {
+    let Some(obj) = schema.as_object() else {
+        return;
+    };
+    if let Some(serde_json::Value::Object(props)) = obj.get("properties") {
+        for prop_schema in props.values() {
+            register_dedup_candidate(prop_schema, counts);
+        }
+    }
+    if let Some(items) = obj.get("items") {
+        register_dedup_candidate(items, counts);
+    }
+}
+
+/// 登记一个候选子 schema 的出现次数，并递归深入它自己的 properties/items
+fn register_dedup_candidate(schema: &serde_json::Value, counts: &mut std::collections::HashMap<String, usize>) {
+    if !schema.is_object() {
+        return;
+    }
+    let key = canonical_key(schema);
+    if key.len() >= MIN_DEDUP_CANDIDATE_BYTES {
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    collect_dedup_candidates(schema, counts);
+}
+
+/// 按首次遇到候选子 schema 的顺序分配确定性的 `DefN` 名字；已分配名字的
+/// 候选子树内部不再继续分配（其内容整体被 `$ref` 替换，不再单独拆分）
+fn assign_def_names(
+    schema: &serde_json::Value,
+    dup_keys: &std::collections::HashSet<String>,
+    def_names: &mut std::collections::HashMap<String, String>,
+) {
+    let Some(obj) = schema.as_object() else {
+        return;
+    };
+    if let Some(serde_json::Value::Object(props)) = obj.get("properties") {
+        for prop_schema in props.values() {
+            assign_def_name_for_node(prop_schema, dup_keys, def_names);
+        }
+    }
+    if let Some(items) = obj.get("items") {
+        assign_def_name_for_node(items, dup_keys, def_names);
+    }
+}
+
+fn assign_def_name_for_node(
+    node: &serde_json::Value,
+    dup_keys: &std::collections::HashSet<String>,
+    def_names: &mut std::collections::HashMap<String, String>,
+) {
+    if !node.is_object() {
+        return;
+    }
+    let key = canonical_key(node);
+    if dup_keys.contains(&key) {
+        if !def_names.contains_key(&key) {
+            let idx = def_names.len() + 1;
+            def_names.insert(key, format!("Def{idx}"));
+        }
+        return;
+    }
+    assign_def_names(node, dup_keys, def_names);
+}
+
+/// 重写一个 schema 节点：把其 `properties`/`items` 中命中去重候选的子 schema
+/// 替换为 `$ref`，并把原始内容登记进 `defs`（同一 key 只登记一次）
+fn rewrite_schema_with_refs(
+    schema: &serde_json::Value,
+    dup_keys: &std::collections::HashSet<String>,
+    def_names: &std::collections::HashMap<String, String>,
+    defs: &mut serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Value {
+    let Some(obj) = schema.as_object() else {
+        return schema.clone();
+    };
+    let mut result = obj.clone();
+
+    if let Some(serde_json::Value::Object(props)) = obj.get("properties") {
+        let mut new_props = serde_json::Map::new();
+        for (name, prop_schema) in props {
+            new_props.insert(
+                name.clone(),
+                maybe_replace_with_ref(prop_schema, dup_keys, def_names, defs),
+            );
+        }
+        result.insert("properties".to_string(), serde_json::Value::Object(new_props));
+    }
+    if let Some(items) = obj.get("items") {
+        result.insert(
+            "items".to_string(),
+            maybe_replace_with_ref(items, dup_keys, def_names, defs),
+        );
+    }
+
+    serde_json::Value::Object(result)
+}
+
+fn maybe_replace_with_ref(
+    node: &serde_json::Value,
+    dup_keys: &std::collections::HashSet<String>,
+    def_names: &std::collections::HashMap<String, String>,
+    defs: &mut serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Value {
+    if !node.is_object() {
+        return node.clone();
+    }
+    let key = canonical_key(node);
+    if dup_keys.contains(&key) {
+        let name = def_names
+            .get(&key)
+            .expect("dup_keys 中的每个 key 都应已在 assign_def_names 中分配过名字")
+            .clone();
+        defs.entry(name.clone()).or_insert_with(|| node.clone());
+        return serde_json::json!({ "$ref": format!("#/$defs/{name}") });
+    }
+    rewrite_schema_with_refs(node, dup_keys, def_names, defs)
+}
+
 /// 简化工具的 input_schema
 ///
 /// 保留结构骨架（type, properties 的 key 和 type, required），
@@ -113,6 +415,12 @@ fn simplify_json_schema(schema: &serde_json::Value) -> serde_json::Value {
         return schema.clone();
     };
 
+    // $ref 节点是 dedup_schemas 已经无损提取过的引用，本身没有可再简化的内容，
+    // 原样保留——否则会被当成空 properties 的 object 丢失掉这个引用
+    if let Some(r) = obj.get("$ref") {
+        return serde_json::json!({ "$ref": r.clone() });
+    }
+
     let mut result = serde_json::Map::new();
 
     // 保留顶层结构字段
@@ -122,6 +430,15 @@ fn simplify_json_schema(schema: &serde_json::Value) -> serde_json::Value {
         }
     }
 
+    // $defs 里的子 schema 同样需要简化（保留其内部可能出现的嵌套 $ref）
+    if let Some(serde_json::Value::Object(defs)) = obj.get("$defs") {
+        let simplified_defs: serde_json::Map<String, serde_json::Value> = defs
+            .iter()
+            .map(|(name, def_schema)| (name.clone(), simplify_json_schema(def_schema)))
+            .collect();
+        result.insert("$defs".to_string(), serde_json::Value::Object(simplified_defs));
+    }
+
     // 简化 properties：仅保留每个属性的 type
     if let Some(serde_json::Value::Object(props)) = obj.get("properties") {
         let mut simplified_props = serde_json::Map::new();
@@ -201,7 +518,7 @@ mod tests {
             "A short description",
             serde_json::json!({"type": "object", "properties": {}}),
         )];
-        let result = compress_tools_if_needed(&tools);
+        let result = compress_tools_with_estimator(&tools, &HeuristicTokenEstimator);
         assert_eq!(result.len(), 1);
         assert_eq!(
             result[0].tool_specification.description,
@@ -211,7 +528,7 @@ mod tests {
 
     #[test]
     fn test_compression_triggers_over_threshold() {
-        // 创建大量工具使总大小超过 20KB
+        // 创建大量工具使总大小（token 数）超过阈值
         let long_desc = "x".repeat(2000);
         let tools: Vec<KiroTool> = (0..15)
             .map(|i| {
@@ -229,11 +546,12 @@ mod tests {
             })
             .collect();
 
-        let original_size = estimate_tools_size(&tools);
+        let estimator = HeuristicTokenEstimator;
+        let original_size = estimate_tools_size(&tools, &estimator);
         assert!(original_size > TOOL_SIZE_THRESHOLD, "测试数据应超过阈值");
 
-        let result = compress_tools_if_needed(&tools);
-        let compressed_size = estimate_tools_size(&result);
+        let result = compress_tools_with_estimator(&tools, &estimator);
+        let compressed_size = estimate_tools_size(&result, &estimator);
         assert!(
             compressed_size < original_size,
             "压缩后应更小: {} < {}",
@@ -242,6 +560,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_heuristic_estimator_counts_ascii_and_multibyte() {
+        let estimator = HeuristicTokenEstimator;
+        // 8 个 ASCII 字符 ≈ 2 个 token
+        assert_eq!(estimator.estimate("abcdefgh"), 2);
+        // 3 个多字节字符 ≈ 3 个 token
+        assert_eq!(estimator.estimate("中文字"), 3);
+    }
+
+    #[test]
+    fn test_heuristic_truncate_to_budget_is_utf8_safe() {
+        let estimator = HeuristicTokenEstimator;
+        let text = "中文字符描述测试文本";
+        let truncated = estimator.truncate_to_budget(text, 3);
+        assert!(truncated.chars().count() <= text.chars().count());
+        // 截断结果必须是合法 UTF-8（可直接作为 &str 使用本身就验证了这一点）
+        assert!(estimator.estimate(&truncated) <= 3);
+    }
+
     #[test]
     fn test_simplify_schema_removes_descriptions() {
         let tool = make_tool(
@@ -273,4 +610,118 @@ mod tests {
         // type 应保留
         assert_eq!(path_prop.get("type").unwrap(), "string");
     }
+
+    /// 展开 `$ref` 回原始内容，用于测试断言无损往返等价，不是生产代码的一部分
+    fn expand_refs(schema: &serde_json::Value, defs: &serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+        match schema {
+            serde_json::Value::Object(obj) => {
+                if let Some(serde_json::Value::String(r)) = obj.get("$ref") {
+                    let name = r.strip_prefix("#/$defs/").expect("仅支持 #/$defs/ 局部引用");
+                    let target = defs.get(name).expect("引用的 def 必须存在");
+                    return expand_refs(target, defs);
+                }
+                let mut result = serde_json::Map::new();
+                for (k, v) in obj {
+                    if k == "$defs" {
+                        continue;
+                    }
+                    result.insert(k.clone(), expand_refs(v, defs));
+                }
+                serde_json::Value::Object(result)
+            }
+            serde_json::Value::Array(items) => {
+                serde_json::Value::Array(items.iter().map(|v| expand_refs(v, defs)).collect())
+            }
+            other => other.clone(),
+        }
+    }
+
+    #[test]
+    fn test_dedup_schemas_round_trips_via_ref_expansion() {
+        let shared = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "start": {"type": "integer", "description": "start offset into the file"},
+                "end": {"type": "integer", "description": "end offset into the file"}
+            },
+            "required": ["start", "end"]
+        });
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "range_a": shared.clone(),
+                "range_b": shared.clone(),
+                "label": {"type": "string"}
+            }
+        });
+        let tool = make_tool("test", "desc", schema.clone());
+
+        let deduped = dedup_schemas(&[tool]);
+        let deduped_schema = &deduped[0].tool_specification.input_schema.json;
+
+        // 确实去重了：$defs 出现，且两处重复的子 schema 都被替换为 $ref
+        let defs = deduped_schema
+            .get("$defs")
+            .and_then(|d| d.as_object())
+            .expect("应生成 $defs");
+        assert_eq!(defs.len(), 1, "重复的 shared 子 schema 应只登记一份");
+        let props = deduped_schema.get("properties").unwrap();
+        assert!(props.get("range_a").unwrap().get("$ref").is_some());
+        assert!(props.get("range_b").unwrap().get("$ref").is_some());
+        // label 只出现一次，体积也低于去重候选阈值，应原样保留，未被替换
+        assert_eq!(props.get("label").unwrap(), &serde_json::json!({"type": "string"}));
+
+        // 展开 $ref 后应与原始 schema 完全等价（无损）
+        let expanded = expand_refs(deduped_schema, defs);
+        assert_eq!(expanded, schema);
+    }
+
+    #[test]
+    fn test_dedup_schemas_is_noop_when_nothing_repeats() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "path": {"type": "string", "description": "file path"},
+                "count": {"type": "integer"}
+            }
+        });
+        let tool = make_tool("test", "desc", schema.clone());
+
+        let deduped = dedup_schemas(&[tool]);
+
+        assert_eq!(deduped[0].tool_specification.input_schema.json, schema);
+    }
+
+    #[test]
+    fn test_compress_tools_with_estimator_prefers_dedup_over_lossy_simplification() {
+        // range 子 schema 在这个工具内部重复了足够多次，纯靠无损去重就应该
+        // 把总 token 数压到阈值以下，不需要再丢失 description/内容
+        let range = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "start": {"type": "integer", "description": "inclusive start offset"},
+                "end": {"type": "integer", "description": "exclusive end offset"}
+            },
+            "required": ["start", "end"]
+        });
+        let mut properties = serde_json::Map::new();
+        for i in 0..120 {
+            properties.insert(format!("range_{i}"), range.clone());
+        }
+        let schema = serde_json::json!({"type": "object", "properties": properties});
+        let tools = vec![make_tool("ranged_tool", "A tool with many ranges", schema)];
+
+        let estimator = HeuristicTokenEstimator;
+        let original_size = estimate_tools_size(&tools, &estimator);
+        assert!(original_size > TOOL_SIZE_THRESHOLD, "测试数据应超过阈值");
+
+        let result = compress_tools_with_estimator(&tools, &estimator);
+
+        // description 应完整保留（无损去重足够，未触发有损简化/截断）
+        assert_eq!(
+            result[0].tool_specification.description,
+            "A tool with many ranges"
+        );
+        assert!(result[0].tool_specification.input_schema.json.get("$defs").is_some());
+    }
 }
@@ -0,0 +1,72 @@
+//! 全局上游并发限制
+//!
+//! 用一个进程内共享的 `tokio::sync::Semaphore` 限制同时进行中的上游调用数，
+//! 避免突发请求压垮凭据；`acquire_with_grace` 在短暂的宽限期内拿不到槽位就
+//! 放弃等待，调用方应据此直接拒绝请求（HTTP 429）而不是无限排队。
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+/// 全局并发限制器，随 `AppState` clone 共享同一个信号量
+#[derive(Debug, Clone)]
+pub struct RequestConcurrencyLimiter {
+    semaphore: Arc<Semaphore>,
+}
+
+impl RequestConcurrencyLimiter {
+    pub fn new(max_concurrent_requests: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent_requests.max(1))),
+        }
+    }
+
+    /// 当前仍可用的并发槽位数（近似值，仅用于日志/可观测性）
+    pub fn available_permits(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    /// 尝试在 `grace` 时间内拿到一个并发槽位；超时或信号量已关闭时返回 `None`，
+    /// 调用方应将其视为"服务过载"并直接拒绝请求
+    pub async fn acquire_with_grace(&self, grace: Duration) -> Option<OwnedSemaphorePermit> {
+        match tokio::time::timeout(grace, self.semaphore.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Some(permit),
+            Ok(Err(AcquireError { .. })) => {
+                tracing::error!("并发限制信号量已关闭（不应发生）");
+                None
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire_succeeds_when_permits_available() {
+        let limiter = RequestConcurrencyLimiter::new(2);
+        let permit = limiter.acquire_with_grace(Duration::from_millis(100)).await;
+        assert!(permit.is_some());
+        assert_eq!(limiter.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_times_out_when_exhausted() {
+        let limiter = RequestConcurrencyLimiter::new(1);
+        let _held = limiter.acquire_with_grace(Duration::from_millis(100)).await.unwrap();
+        let second = limiter.acquire_with_grace(Duration::from_millis(50)).await;
+        assert!(second.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_succeeds_once_previous_permit_dropped() {
+        let limiter = RequestConcurrencyLimiter::new(1);
+        let held = limiter.acquire_with_grace(Duration::from_millis(100)).await.unwrap();
+        drop(held);
+        let second = limiter.acquire_with_grace(Duration::from_millis(100)).await;
+        assert!(second.is_some());
+    }
+}
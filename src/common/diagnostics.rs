@@ -0,0 +1,191 @@
+//! 结构化诊断数据导出
+//!
+//! 请求路径上产生的结构化 `tracing` 事件（conversation_id、压缩前后字节数、
+//! 迭代次数、拒绝原因等）默认只落在本地日志里，排查问题需要登录到具体实例
+//! 翻日志。这里在其基础上，把同样的字段异步批量投递到一个外部 HTTP 批量
+//! 接收端点（换行分隔 JSON，一行一个对象，ES Bulk 友好），供运维集中观测
+//! 压缩效果与 400 拒绝原因，而不必逐台实例排查。
+//!
+//! 请求路径只通过一个有界 `mpsc::Sender::try_send` 入队，通道已满时直接丢弃
+//! 本条记录并打一条告警日志——诊断数据允许损失，但绝不能拖慢或阻塞请求路径。
+
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+use crate::model::config::DiagnosticsConfig;
+
+/// 单个批次最多投递尝试次数（含首次投递）
+const FLUSH_MAX_RETRIES: usize = 3;
+/// 重试退避基数（毫秒），按 `2^(attempt-1)` 指数增长（与上游流恢复退避一致）
+const FLUSH_BACKOFF_BASE_MS: u64 = 500;
+
+/// 单条结构化诊断记录
+///
+/// `fields` 以自由 JSON 对象形式承载具体字段（request_body_bytes、
+/// image_bytes、effective_bytes、compression iters、
+/// final_tool_result_max_chars 等）；不同 `event` 的字段集合不同，
+/// 因此不为每种事件单独定义结构体，与调用方已有的 `tracing::warn!` 字段
+/// 一一对应即可。
+#[derive(Debug, Clone, Serialize)]
+struct DiagnosticsRecord {
+    event: &'static str,
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+/// 诊断导出器句柄；随 `AppState` clone 共享同一个后台投递任务
+#[derive(Debug, Clone)]
+pub struct DiagnosticsExporter {
+    tx: Option<mpsc::Sender<DiagnosticsRecord>>,
+}
+
+impl DiagnosticsExporter {
+    /// 按配置启动后台投递任务；`config` 为 `None`（未配置 endpoint）时返回一个
+    /// 空操作的导出器，`record` 调用直接丢弃，调用方无需额外判空
+    pub fn spawn(config: Option<DiagnosticsConfig>) -> Self {
+        let Some(config) = config else {
+            return Self { tx: None };
+        };
+
+        let (tx, rx) = mpsc::channel(config.channel_capacity.max(1));
+        tokio::spawn(run_flush_loop(config, rx));
+        Self { tx: Some(tx) }
+    }
+
+    /// 未配置导出端点时的空操作导出器（用于测试或未启用该功能的部署）
+    pub fn disabled() -> Self {
+        Self { tx: None }
+    }
+
+    /// 记录一条诊断事件；通道已满或未配置导出端点时直接丢弃
+    pub fn record(&self, event: &'static str, fields: serde_json::Map<String, serde_json::Value>) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let record = DiagnosticsRecord {
+            event,
+            timestamp_ms: now_unix_ms(),
+            fields,
+        };
+        if tx.try_send(record).is_err() {
+            tracing::warn!(event, "诊断记录通道已满，已丢弃本条记录");
+        }
+    }
+}
+
+fn now_unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// 批量投递循环：每收到一条记录即入队，凑满 `batch_size` 或等到
+/// `flush_interval_secs` 定时器先触发者攒批发送，失败时指数退避重试，
+/// 重试耗尽后丢弃本批次
+async fn run_flush_loop(config: DiagnosticsConfig, mut rx: mpsc::Receiver<DiagnosticsRecord>) {
+    let client = reqwest::Client::new();
+    let flush_period = Duration::from_secs(config.flush_interval_secs.max(1));
+    let batch_size = config.batch_size.max(1);
+
+    let mut ticker = tokio::time::interval(flush_period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut batch: Vec<DiagnosticsRecord> = Vec::with_capacity(batch_size);
+
+    loop {
+        tokio::select! {
+            biased;
+
+            received = rx.recv() => {
+                match received {
+                    Some(record) => {
+                        batch.push(record);
+                        if batch.len() >= batch_size {
+                            flush_batch(&client, &config, std::mem::take(&mut batch)).await;
+                        }
+                    }
+                    None => {
+                        if !batch.is_empty() {
+                            flush_batch(&client, &config, std::mem::take(&mut batch)).await;
+                        }
+                        tracing::info!("诊断导出通道已关闭，投递任务退出");
+                        return;
+                    }
+                }
+            }
+
+            _ = ticker.tick() => {
+                if !batch.is_empty() {
+                    flush_batch(&client, &config, std::mem::take(&mut batch)).await;
+                }
+            }
+        }
+    }
+}
+
+/// 把一批记录序列化为换行分隔 JSON 并 POST 到 `config.endpoint`，
+/// 失败时按指数退避重试，重试耗尽后丢弃本批次（诊断数据允许损失）
+async fn flush_batch(client: &reqwest::Client, config: &DiagnosticsConfig, batch: Vec<DiagnosticsRecord>) {
+    let mut body = String::new();
+    for record in &batch {
+        match serde_json::to_string(record) {
+            Ok(line) => {
+                body.push_str(&line);
+                body.push('\n');
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "序列化诊断记录失败，已跳过该条");
+            }
+        }
+    }
+    if body.is_empty() {
+        return;
+    }
+
+    for attempt in 1..=FLUSH_MAX_RETRIES {
+        let mut request = client
+            .post(&config.endpoint)
+            .header("Content-Type", "application/x-ndjson")
+            .body(body.clone());
+
+        if let (Some(name), Some(value)) = (
+            config.auth_header_name.as_deref(),
+            config.auth_header_value.as_deref(),
+        ) {
+            request = request.header(name, value);
+        }
+
+        match request.send().await {
+            Ok(resp) if resp.status().is_success() => return,
+            Ok(resp) => {
+                tracing::warn!(status = %resp.status(), attempt, "诊断批次投递收到非成功状态码");
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, attempt, "诊断批次投递失败");
+            }
+        }
+
+        if attempt < FLUSH_MAX_RETRIES {
+            let backoff = Duration::from_millis(FLUSH_BACKOFF_BASE_MS * (1u64 << (attempt - 1)));
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    tracing::error!(batch_len = batch.len(), "诊断批次重试耗尽，已丢弃本批次");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_exporter_record_is_noop() {
+        let exporter = DiagnosticsExporter::disabled();
+        // 未配置导出端点时 record 应直接返回，不 panic
+        exporter.record("request_size_precheck", serde_json::Map::new());
+    }
+}
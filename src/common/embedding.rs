@@ -0,0 +1,127 @@
+//! 轻量语义向量化，用于历史轮次相关性打分
+//!
+//! 具体的嵌入实现（本地量化 BERT/MiniLM，通过 `candle` 加载）由调用方提供并
+//! 注入（例如挂载在 `AppState` 上，启动时加载一次），这里只定义抽象接口、
+//! 相似度计算与按内容哈希缓存嵌入结果的包装类型，避免每轮自适应压缩迭代都
+//! 重新计算同一轮次的向量。
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// 将一段文本编码为语义向量
+///
+/// 实现应返回 L2 归一化后的向量，使 `cosine_similarity` 可以直接退化为点积。
+pub trait TurnEmbedder: Send + Sync {
+    fn embed(&self, text: &str) -> Vec<f32>;
+}
+
+/// 余弦相似度；假定输入向量已 L2 归一化，退化为点积
+///
+/// 维度不一致或任一向量为空时返回 0.0（视为完全不相关，而非报错，
+/// 与 `jaccard_similarity` 空集合返回 0 的约定一致）。
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.is_empty() || b.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// 按文本内容哈希缓存嵌入结果的 `TurnEmbedder` 包装
+///
+/// 自适应压缩可能对同一份历史反复迭代多轮，每轮都会重新对尚存的轮次打分；
+/// 缓存避免对未变化的轮次重复调用底层模型。
+pub struct CachedEmbedder<E: TurnEmbedder> {
+    inner: E,
+    cache: Mutex<HashMap<u64, Vec<f32>>>,
+}
+
+impl<E: TurnEmbedder> CachedEmbedder<E> {
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn hash_text(text: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl<E: TurnEmbedder> TurnEmbedder for CachedEmbedder<E> {
+    fn embed(&self, text: &str) -> Vec<f32> {
+        let key = Self::hash_text(text);
+
+        if let Some(cached) = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&key)
+        {
+            return cached.clone();
+        }
+
+        let vector = self.inner.embed(text);
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(key, vector.clone());
+        vector
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_cosine_similarity_identical_vectors() {
+        let v = vec![0.6, 0.8];
+        assert!((cosine_similarity(&v, &v) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cosine_similarity_orthogonal_vectors() {
+        let a = vec![1.0, 0.0];
+        let b = vec![0.0, 1.0];
+        assert!(cosine_similarity(&a, &b).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cosine_similarity_mismatched_dims_returns_zero() {
+        let a = vec![1.0, 0.0];
+        let b = vec![1.0, 0.0, 0.0];
+        assert_eq!(cosine_similarity(&a, &b), 0.0);
+    }
+
+    struct CountingEmbedder {
+        calls: AtomicUsize,
+    }
+
+    impl TurnEmbedder for CountingEmbedder {
+        fn embed(&self, text: &str) -> Vec<f32> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            vec![text.len() as f32, 0.0]
+        }
+    }
+
+    #[test]
+    fn test_cached_embedder_reuses_result_for_same_text() {
+        let embedder = CachedEmbedder::new(CountingEmbedder {
+            calls: AtomicUsize::new(0),
+        });
+
+        let first = embedder.embed("hello");
+        let second = embedder.embed("hello");
+        assert_eq!(first, second);
+        assert_eq!(embedder.inner.calls.load(Ordering::SeqCst), 1);
+
+        embedder.embed("different");
+        assert_eq!(embedder.inner.calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,318 @@
+//! 进程内 Prometheus 风格指标注册表
+//!
+//! 不引入额外的 `prometheus` crate 依赖，直接用原子计数器/直方图手写文本
+//! exposition 格式（与 [`crate::common::quota::QuotaTracker`] 一样是自包含的
+//! 轻量实现），由 `/metrics` 端点导出，便于把压缩/限流/故障转移行为接入
+//! 外部监控系统，而不必只靠一次性的 `tracing` 日志行做事后排查。
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 单调递增计数器
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 固定分桶直方图；桶计数直接按累积语义存储，与 Prometheus 文本格式的
+/// `le` 桶定义一致（无需在渲染时再做前缀和）
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: &'static [f64],
+    bucket_counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            bucket_counts: (0..=buckets.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        for (i, &boundary) in self.buckets.iter().enumerate() {
+            if (value as f64) <= boundary {
+                self.bucket_counts[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.bucket_counts[self.buckets.len()].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (i, &boundary) in self.buckets.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{le=\"{boundary}\"}} {}",
+                self.bucket_counts[i].load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{le=\"+Inf\"}} {}",
+            self.bucket_counts[self.buckets.len()].load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "{name}_sum {}", self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_count {}", self.count.load(Ordering::Relaxed));
+    }
+}
+
+const BYTES_BUCKETS: &[f64] = &[
+    1024.0,
+    8192.0,
+    65536.0,
+    262144.0,
+    1_048_576.0,
+    2_097_152.0,
+    5_242_880.0,
+    10_485_760.0,
+];
+const ITERS_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0];
+const TOKENS_BUCKETS: &[f64] = &[1024.0, 4096.0, 16384.0, 32768.0, 65536.0, 131072.0, 200000.0];
+
+/// 进程内共享指标注册表；随 `AppState` clone 共享同一组原子计数器
+#[derive(Debug)]
+pub struct Metrics {
+    pub messages_requests_total: Counter,
+    pub messages_stream_requests_total: Counter,
+    pub messages_non_stream_requests_total: Counter,
+    pub cc_messages_requests_total: Counter,
+    pub cc_messages_stream_requests_total: Counter,
+    pub cc_messages_non_stream_requests_total: Counter,
+
+    pub adaptive_compressions_total: Counter,
+    pub adaptive_initial_bytes: Histogram,
+    pub adaptive_final_bytes: Histogram,
+    pub adaptive_iters: Histogram,
+
+    pub requests_rejected_too_large_total: Counter,
+
+    /// 非流式响应解析 `tool_use.input` JSON 失败时递增（流式路径的
+    /// contextUsageEvent 校正逻辑位于 `StreamContext` 内部，暂未接入）
+    pub tool_input_parse_failures_total: Counter,
+    /// `crate::anthropic::truncation::detect_truncation` 命中截断时递增
+    pub tool_input_truncations_detected_total: Counter,
+
+    pub context_usage_input_tokens: Histogram,
+    pub estimated_input_tokens: Histogram,
+
+    pub ping_keepalives_total: Counter,
+    pub upstream_stream_errors_total: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            messages_requests_total: Counter::default(),
+            messages_stream_requests_total: Counter::default(),
+            messages_non_stream_requests_total: Counter::default(),
+            cc_messages_requests_total: Counter::default(),
+            cc_messages_stream_requests_total: Counter::default(),
+            cc_messages_non_stream_requests_total: Counter::default(),
+            adaptive_compressions_total: Counter::default(),
+            adaptive_initial_bytes: Histogram::new(BYTES_BUCKETS),
+            adaptive_final_bytes: Histogram::new(BYTES_BUCKETS),
+            adaptive_iters: Histogram::new(ITERS_BUCKETS),
+            requests_rejected_too_large_total: Counter::default(),
+            tool_input_parse_failures_total: Counter::default(),
+            tool_input_truncations_detected_total: Counter::default(),
+            context_usage_input_tokens: Histogram::new(TOKENS_BUCKETS),
+            estimated_input_tokens: Histogram::new(TOKENS_BUCKETS),
+            ping_keepalives_total: Counter::default(),
+            upstream_stream_errors_total: Counter::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一次 `/v1/messages`（`is_cc = false`）或 `/cc/v1/messages`
+    /// （`is_cc = true`）请求，按是否为流式请求分别计数
+    pub fn record_messages_request(&self, is_cc: bool, stream: bool) {
+        if is_cc {
+            self.cc_messages_requests_total.inc();
+            if stream {
+                self.cc_messages_stream_requests_total.inc();
+            } else {
+                self.cc_messages_non_stream_requests_total.inc();
+            }
+        } else {
+            self.messages_requests_total.inc();
+            if stream {
+                self.messages_stream_requests_total.inc();
+            } else {
+                self.messages_non_stream_requests_total.inc();
+            }
+        }
+    }
+
+    /// 记录一次 `adaptive_shrink_request_body` 触发的收缩结果
+    pub fn record_adaptive_compression(&self, initial_bytes: usize, final_bytes: usize, iters: usize) {
+        self.adaptive_compressions_total.inc();
+        self.adaptive_initial_bytes.observe(initial_bytes as u64);
+        self.adaptive_final_bytes.observe(final_bytes as u64);
+        self.adaptive_iters.observe(iters as u64);
+    }
+
+    /// 渲染为 Prometheus 文本 exposition 格式（`# HELP`/`# TYPE` + 样本行）
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        macro_rules! counter {
+            ($field:expr, $name:expr, $help:expr) => {
+                let _ = writeln!(out, "# HELP {} {}", $name, $help);
+                let _ = writeln!(out, "# TYPE {} counter", $name);
+                let _ = writeln!(out, "{} {}", $name, $field.get());
+            };
+        }
+
+        counter!(
+            self.messages_requests_total,
+            "kiro_messages_requests_total",
+            "Total /v1/messages requests received"
+        );
+        counter!(
+            self.messages_stream_requests_total,
+            "kiro_messages_stream_requests_total",
+            "Total streaming /v1/messages requests received"
+        );
+        counter!(
+            self.messages_non_stream_requests_total,
+            "kiro_messages_non_stream_requests_total",
+            "Total non-streaming /v1/messages requests received"
+        );
+        counter!(
+            self.cc_messages_requests_total,
+            "kiro_cc_messages_requests_total",
+            "Total /cc/v1/messages requests received"
+        );
+        counter!(
+            self.cc_messages_stream_requests_total,
+            "kiro_cc_messages_stream_requests_total",
+            "Total streaming /cc/v1/messages requests received"
+        );
+        counter!(
+            self.cc_messages_non_stream_requests_total,
+            "kiro_cc_messages_non_stream_requests_total",
+            "Total non-streaming /cc/v1/messages requests received"
+        );
+        counter!(
+            self.adaptive_compressions_total,
+            "kiro_adaptive_compressions_total",
+            "Total times adaptive_shrink_request_body was triggered"
+        );
+        counter!(
+            self.requests_rejected_too_large_total,
+            "kiro_requests_rejected_too_large_total",
+            "Total requests rejected for exceeding max_request_body_bytes after adaptive compression"
+        );
+        counter!(
+            self.tool_input_parse_failures_total,
+            "kiro_tool_input_parse_failures_total",
+            "Total tool_use input JSON parse failures"
+        );
+        counter!(
+            self.tool_input_truncations_detected_total,
+            "kiro_tool_input_truncations_detected_total",
+            "Total tool_use inputs detected as truncated"
+        );
+        counter!(
+            self.ping_keepalives_total,
+            "kiro_ping_keepalives_total",
+            "Total SSE/WebSocket ping keepalives emitted"
+        );
+        counter!(
+            self.upstream_stream_errors_total,
+            "kiro_upstream_stream_errors_total",
+            "Total abnormal upstream stream terminations observed"
+        );
+
+        self.adaptive_initial_bytes.render(
+            &mut out,
+            "kiro_adaptive_initial_bytes",
+            "Request body size in bytes before adaptive compression",
+        );
+        self.adaptive_final_bytes.render(
+            &mut out,
+            "kiro_adaptive_final_bytes",
+            "Request body size in bytes after adaptive compression",
+        );
+        self.adaptive_iters.render(
+            &mut out,
+            "kiro_adaptive_iters",
+            "Number of iterations adaptive_shrink_request_body performed",
+        );
+        self.context_usage_input_tokens.render(
+            &mut out,
+            "kiro_context_usage_input_tokens",
+            "Input tokens reported by upstream contextUsageEvent",
+        );
+        self.estimated_input_tokens.render(
+            &mut out,
+            "kiro_estimated_input_tokens",
+            "Input tokens estimated locally via token::count_all_tokens before conversion",
+        );
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_increments() {
+        let c = Counter::default();
+        c.inc();
+        c.inc();
+        assert_eq!(c.get(), 2);
+    }
+
+    #[test]
+    fn test_histogram_cumulative_buckets() {
+        let h = Histogram::new(&[10.0, 100.0]);
+        h.observe(5);
+        h.observe(50);
+        h.observe(500);
+        let mut out = String::new();
+        h.render(&mut out, "test_hist", "test histogram");
+        assert!(out.contains("test_hist_bucket{le=\"10\"} 1"));
+        assert!(out.contains("test_hist_bucket{le=\"100\"} 2"));
+        assert!(out.contains("test_hist_bucket{le=\"+Inf\"} 3"));
+        assert!(out.contains("test_hist_sum 555"));
+        assert!(out.contains("test_hist_count 3"));
+    }
+
+    #[test]
+    fn test_record_messages_request_splits_by_endpoint_and_stream() {
+        let metrics = Metrics::new();
+        metrics.record_messages_request(false, true);
+        metrics.record_messages_request(false, false);
+        metrics.record_messages_request(true, true);
+        assert_eq!(metrics.messages_requests_total.get(), 2);
+        assert_eq!(metrics.messages_stream_requests_total.get(), 1);
+        assert_eq!(metrics.messages_non_stream_requests_total.get(), 1);
+        assert_eq!(metrics.cc_messages_requests_total.get(), 1);
+        assert_eq!(metrics.cc_messages_stream_requests_total.get(), 1);
+    }
+}
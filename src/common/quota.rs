@@ -0,0 +1,189 @@
+//! 凭据配额耗尽追踪与按用户请求预算
+//!
+//! 两套独立的状态：
+//! - `QuotaTracker` 记录各凭据最近一次被观测到限流/配额耗尽的预估重置时间，
+//!   供"所有凭据已用尽"场景下计算真实的 `Retry-After`，而不是返回裸 429。
+//! - `UserBudgetTracker` 在真正派发到上游前，对同一个 `user_id`（与凭据亲和性
+//!   使用的同一标识）施加固定窗口内的请求数/token 数上限，提前拒绝而非等到
+//!   全部凭据配额耗尽才发现。
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::model::config::UserQuotaConfig;
+
+/// 单个凭据被观测到限流/配额耗尽时记录的状态
+#[derive(Debug, Clone, Copy)]
+struct CredentialQuotaState {
+    /// 预估的配额重置时刻
+    reset_at: Instant,
+}
+
+/// 跨凭据的配额耗尽追踪器，按凭据标识（与 `MultiTokenManager` 选择凭据时
+/// 使用的同一 key）记录最近一次耗尽事件的预估重置时间
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    credentials: RwLock<HashMap<String, CredentialQuotaState>>,
+}
+
+impl QuotaTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录某个凭据观测到限流/配额耗尽，预估在 `retry_after` 之后恢复
+    ///
+    /// 同一凭据短时间内被多次记录时，取更晚的重置时间（以最悲观估计为准）。
+    pub fn record_exhausted(&self, credential_key: &str, retry_after: Duration) {
+        let reset_at = Instant::now() + retry_after;
+        let mut credentials = self.credentials.write().unwrap_or_else(|p| p.into_inner());
+        credentials
+            .entry(credential_key.to_string())
+            .and_modify(|s| {
+                if reset_at > s.reset_at {
+                    s.reset_at = reset_at;
+                }
+            })
+            .or_insert(CredentialQuotaState { reset_at });
+    }
+
+    /// 所有已记录凭据中最早的预估重置剩余时长
+    ///
+    /// 尚无记录时返回 `None`（调用方应退回一个保守的固定 `Retry-After`）。
+    pub fn earliest_reset_remaining(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.credentials
+            .read()
+            .unwrap_or_else(|p| p.into_inner())
+            .values()
+            .map(|s| s.reset_at.saturating_duration_since(now))
+            .min()
+    }
+}
+
+/// 单个 user_id 在当前预算窗口内的用量
+#[derive(Debug, Clone, Copy)]
+struct UserBudgetState {
+    window_start: Instant,
+    requests_used: u32,
+    tokens_used: u64,
+}
+
+/// 按 user_id 的请求/token 预算控制器
+///
+/// 与凭据级 `QuotaTracker` 是两回事：这里限制的是"同一个下游用户"在固定
+/// 窗口内能发起的请求数/消耗的 token 数，窗口过期后自动重置。
+#[derive(Debug, Default)]
+pub struct UserBudgetTracker {
+    users: RwLock<HashMap<String, UserBudgetState>>,
+}
+
+impl UserBudgetTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 检查并登记一次请求；超过 `config` 预算时返回 `false`，调用方应提前拒绝
+    ///
+    /// `estimated_tokens` 为本次请求的预估 token 消耗（允许粗略估算，预算
+    /// 只需要"足够接近"，不要求逐字节精确）。
+    pub fn try_consume(
+        &self,
+        user_id: &str,
+        config: &UserQuotaConfig,
+        estimated_tokens: u64,
+    ) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(config.window_secs);
+        let mut users = self.users.write().unwrap_or_else(|p| p.into_inner());
+        let state = users.entry(user_id.to_string()).or_insert(UserBudgetState {
+            window_start: now,
+            requests_used: 0,
+            tokens_used: 0,
+        });
+
+        if now.saturating_duration_since(state.window_start) >= window {
+            state.window_start = now;
+            state.requests_used = 0;
+            state.tokens_used = 0;
+        }
+
+        if state.requests_used >= config.max_requests
+            || state.tokens_used.saturating_add(estimated_tokens) > config.max_tokens
+        {
+            return false;
+        }
+
+        state.requests_used += 1;
+        state.tokens_used += estimated_tokens;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_tracker_reports_earliest_reset() {
+        let tracker = QuotaTracker::new();
+        tracker.record_exhausted("cred-a", Duration::from_secs(30));
+        tracker.record_exhausted("cred-b", Duration::from_secs(5));
+        let remaining = tracker.earliest_reset_remaining().unwrap();
+        assert!(remaining <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_quota_tracker_keeps_later_reset_for_same_credential() {
+        let tracker = QuotaTracker::new();
+        tracker.record_exhausted("cred-a", Duration::from_secs(5));
+        tracker.record_exhausted("cred-a", Duration::from_secs(60));
+        let remaining = tracker.earliest_reset_remaining().unwrap();
+        assert!(remaining > Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_quota_tracker_no_records_returns_none() {
+        let tracker = QuotaTracker::new();
+        assert!(tracker.earliest_reset_remaining().is_none());
+    }
+
+    #[test]
+    fn test_user_budget_rejects_after_max_requests() {
+        let tracker = UserBudgetTracker::new();
+        let config = UserQuotaConfig {
+            window_secs: 60,
+            max_requests: 2,
+            max_tokens: 1_000_000,
+        };
+        assert!(tracker.try_consume("user-1", &config, 0));
+        assert!(tracker.try_consume("user-1", &config, 0));
+        assert!(!tracker.try_consume("user-1", &config, 0));
+    }
+
+    #[test]
+    fn test_user_budget_rejects_after_max_tokens() {
+        let tracker = UserBudgetTracker::new();
+        let config = UserQuotaConfig {
+            window_secs: 60,
+            max_requests: 100,
+            max_tokens: 100,
+        };
+        assert!(tracker.try_consume("user-1", &config, 80));
+        assert!(!tracker.try_consume("user-1", &config, 30));
+    }
+
+    #[test]
+    fn test_user_budget_tracks_users_independently() {
+        let tracker = UserBudgetTracker::new();
+        let config = UserQuotaConfig {
+            window_secs: 60,
+            max_requests: 1,
+            max_tokens: 1_000_000,
+        };
+        assert!(tracker.try_consume("user-1", &config, 0));
+        assert!(tracker.try_consume("user-2", &config, 0));
+        assert!(!tracker.try_consume("user-1", &config, 0));
+    }
+}
@@ -0,0 +1,116 @@
+//! 多因子加权凭据调度
+//!
+//! 将凭据选择从硬编码的 "priority" / "balanced" 两种模式，抽象为基于
+//! `SchedulerWeights` 的可配置加权评分，供 `MultiTokenManager` 在凭据池中选择。
+
+use crate::model::config::SchedulerWeights;
+
+/// 参与评分的单个凭据候选信息
+///
+/// 字段均为调度评分所需的原始观测值，由调用方（`MultiTokenManager`）从
+/// 凭据快照、令牌桶状态、`get_balance` 结果中收集后构造。
+#[derive(Debug, Clone, Copy)]
+pub struct CredentialCandidate {
+    pub index: usize,
+    /// 剩余额度占比 [0, 1]（1 表示额度充裕）
+    pub balance_fraction: f64,
+    /// 累计失败次数
+    pub failure_count: u32,
+    /// 令牌桶当前可用令牌数
+    pub rpm_tokens_available: f64,
+    /// 令牌桶容量
+    pub rpm_capacity: f64,
+    /// 距离过期的剩余时间占比 [0, 1]（0 表示已过期或即将过期）
+    pub time_to_expiry_fraction: f64,
+}
+
+impl CredentialCandidate {
+    fn inverse_failure(&self) -> f64 {
+        1.0 / (1.0 + self.failure_count as f64)
+    }
+
+    fn rpm_headroom(&self) -> f64 {
+        if self.rpm_capacity <= 0.0 {
+            1.0
+        } else {
+            (self.rpm_tokens_available / self.rpm_capacity).clamp(0.0, 1.0)
+        }
+    }
+}
+
+/// 对单个候选凭据计算加权评分（越高越优先）
+pub fn score_candidate(weights: &SchedulerWeights, candidate: &CredentialCandidate) -> f64 {
+    weights.score(
+        candidate.balance_fraction.clamp(0.0, 1.0),
+        candidate.inverse_failure(),
+        candidate.rpm_headroom(),
+        candidate.time_to_expiry_fraction.clamp(0.0, 1.0),
+    )
+}
+
+/// 从一组候选凭据中选出评分最高者
+///
+/// 已在上层被过滤为"未禁用且未超出限流"的候选列表；空列表返回 `None`。
+pub fn select_best(
+    weights: &SchedulerWeights,
+    candidates: &[CredentialCandidate],
+) -> Option<usize> {
+    candidates
+        .iter()
+        .map(|c| (c.index, score_candidate(weights, c)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_preset_prefers_fewer_failures() {
+        let weights = SchedulerWeights::from_mode("priority");
+        let candidates = vec![
+            CredentialCandidate {
+                index: 0,
+                balance_fraction: 0.1,
+                failure_count: 5,
+                rpm_tokens_available: 0.0,
+                rpm_capacity: 10.0,
+                time_to_expiry_fraction: 0.1,
+            },
+            CredentialCandidate {
+                index: 1,
+                balance_fraction: 0.0,
+                failure_count: 0,
+                rpm_tokens_available: 0.0,
+                rpm_capacity: 10.0,
+                time_to_expiry_fraction: 0.0,
+            },
+        ];
+        assert_eq!(select_best(&weights, &candidates), Some(1));
+    }
+
+    #[test]
+    fn test_balanced_preset_considers_balance() {
+        let weights = SchedulerWeights::from_mode("balanced");
+        let candidates = vec![
+            CredentialCandidate {
+                index: 0,
+                balance_fraction: 1.0,
+                failure_count: 0,
+                rpm_tokens_available: 10.0,
+                rpm_capacity: 10.0,
+                time_to_expiry_fraction: 1.0,
+            },
+            CredentialCandidate {
+                index: 1,
+                balance_fraction: 0.0,
+                failure_count: 3,
+                rpm_tokens_available: 0.0,
+                rpm_capacity: 10.0,
+                time_to_expiry_fraction: 0.0,
+            },
+        ];
+        assert_eq!(select_best(&weights, &candidates), Some(0));
+    }
+}
@@ -0,0 +1,150 @@
+//! 优雅关闭协调器
+//!
+//! 跟踪仍在收尾排空的 SSE/WebSocket 流式连接数，关闭信号发出后给已有连接
+//! 一个有限的收尾窗口（生成正常的终止事件后结束），而不是直接断开 socket，
+//! 这样滚动重启对正在进行的长连接流式会话是安全的。
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// 单条流注册进协调器时持有的句柄；Drop 时自动从排空计数里移除，调用方
+/// 不需要手动配对一次 `register_stream`/反注册
+pub struct DrainGuard {
+    draining: Arc<AtomicUsize>,
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        self.draining.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 全局共享的优雅关闭协调器，随 `AppState` clone
+#[derive(Debug)]
+pub struct ShutdownCoordinator {
+    tx: watch::Sender<bool>,
+    draining: Arc<AtomicUsize>,
+}
+
+impl Default for ShutdownCoordinator {
+    fn default() -> Self {
+        let (tx, _rx) = watch::channel(false);
+        Self {
+            tx,
+            draining: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 订阅关闭信号；每条 SSE/WS 流在建立时各自持有一份接收端
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+
+    /// 登记一条新开始的流，返回的守卫在流结束（正常/异常/收尾完成）时
+    /// Drop 自动递减排空计数
+    pub fn register_stream(&self) -> DrainGuard {
+        self.draining.fetch_add(1, Ordering::SeqCst);
+        DrainGuard {
+            draining: self.draining.clone(),
+        }
+    }
+
+    /// 当前仍在收尾排空的流数量
+    pub fn draining_count(&self) -> usize {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// 是否已发出过关闭信号
+    pub fn is_shutting_down(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// 广播关闭信号：所有订阅者的下一次 `changed()` 会观察到 `true`
+    pub fn signal_shutdown(&self) {
+        let _ = self.tx.send(true);
+    }
+
+    /// 等待所有已登记的流排空，超过 `deadline` 仍未排空时放弃等待并返回
+    /// `false`（调用方——进程退出前的收尾逻辑——应据此决定是否需要强制中止
+    /// 剩余连接而不是无限期阻塞退出）
+    pub async fn wait_for_drain(&self, deadline: Duration) -> bool {
+        let start = tokio::time::Instant::now();
+        let poll_period = Duration::from_millis(200);
+        loop {
+            let remaining = self.draining_count();
+            if remaining == 0 {
+                tracing::info!("优雅关闭：所有流式连接已排空");
+                return true;
+            }
+            if start.elapsed() >= deadline {
+                tracing::warn!(
+                    remaining_streams = remaining,
+                    "优雅关闭：等待排空超时，放弃等待并继续退出"
+                );
+                return false;
+            }
+            tracing::debug!(remaining_streams = remaining, "优雅关闭：等待流式连接排空");
+            tokio::time::sleep(poll_period).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_stream_increments_and_drop_decrements() {
+        let coordinator = ShutdownCoordinator::new();
+        assert_eq!(coordinator.draining_count(), 0);
+        let guard_a = coordinator.register_stream();
+        let guard_b = coordinator.register_stream();
+        assert_eq!(coordinator.draining_count(), 2);
+        drop(guard_a);
+        assert_eq!(coordinator.draining_count(), 1);
+        drop(guard_b);
+        assert_eq!(coordinator.draining_count(), 0);
+    }
+
+    #[test]
+    fn test_signal_shutdown_marks_subscribers() {
+        let coordinator = ShutdownCoordinator::new();
+        let rx = coordinator.subscribe();
+        assert!(!*rx.borrow());
+        assert!(!coordinator.is_shutting_down());
+        coordinator.signal_shutdown();
+        assert!(*rx.borrow());
+        assert!(coordinator.is_shutting_down());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_drain_returns_true_once_all_guards_dropped() {
+        let coordinator = ShutdownCoordinator::new();
+        let guard = coordinator.register_stream();
+        let coordinator = Arc::new(coordinator);
+        let waiter = {
+            let coordinator = coordinator.clone();
+            tokio::spawn(async move { coordinator.wait_for_drain(Duration::from_secs(5)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(guard);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_drain_times_out_when_stream_never_finishes() {
+        let coordinator = ShutdownCoordinator::new();
+        let _guard = coordinator.register_stream();
+        let drained = coordinator.wait_for_drain(Duration::from_millis(50)).await;
+        assert!(!drained);
+    }
+}
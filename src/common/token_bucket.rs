@@ -0,0 +1,88 @@
+//! 令牌桶限流器
+//!
+//! 用于凭据级限流：允许短时突发（最多 `capacity` 个请求），长期仍收敛到
+//! `rate`（每分钟令牌数）。相比固定最小请求间隔，突发容忍度更贴近上游真实限流策略。
+
+use std::time::Instant;
+
+use crate::model::config::RateLimitConfig;
+
+/// 单个凭据的令牌桶状态
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// 每分钟补充的令牌数
+    rate_per_min: f64,
+    /// 桶容量（突发上限）
+    capacity: f64,
+    /// 当前令牌数
+    tokens: f64,
+    /// 上次补充时间
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// 使用配置创建令牌桶，初始令牌数等于容量（允许启动即突发）
+    pub fn new(config: RateLimitConfig) -> Self {
+        let capacity = config.burst.max(1) as f64;
+        Self {
+            rate_per_min: config.rate as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// 补充令牌（按流逝时间线性补充，不超过容量）
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let refilled = elapsed.as_secs_f64() * self.rate_per_min / 60.0;
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// 尝试消费一个令牌
+    ///
+    /// 返回 `true` 表示本次请求可放行；返回 `false` 表示凭据暂时超限，
+    /// 调用方应优先将流量分配到其他可用凭据。
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 当前可用令牌数（用于调度器的 RPM headroom 因子）
+    pub fn available_tokens(&mut self) -> f64 {
+        self.refill(Instant::now());
+        self.tokens
+    }
+
+    /// 桶容量
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_throttle() {
+        let mut bucket = TokenBucket::new(RateLimitConfig { rate: 60, burst: 3 });
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        // 容量耗尽，短时间内无法再次获取
+        assert!(!bucket.try_acquire());
+    }
+
+    #[test]
+    fn test_available_tokens_capped_at_capacity() {
+        let mut bucket = TokenBucket::new(RateLimitConfig { rate: 120, burst: 5 });
+        assert_eq!(bucket.available_tokens(), 5.0);
+    }
+}
@@ -0,0 +1,110 @@
+//! BPE 分词器封装
+//!
+//! 为 `CompressionConfig.budget = Tokens` 模式下的压缩 pass 提供统一的
+//! 编码/解码入口。按模型名选择近似的 BPE 编码表，构造一次后可在同一次
+//! 压缩管道内的所有 pass 间复用，避免每个 pass 各自重新加载词表。
+
+use tiktoken_rs::CoreBPE;
+
+/// 已构造好的 BPE 分词器
+///
+/// Kiro/Claude 并未公开自己的 BPE 词表，这里用 `o200k_base`（GPT-4o 系列，
+/// 词表更新、对多语言覆盖更好）近似较新的模型，`cl100k_base` 兜底覆盖
+/// 早期模型名；压缩预算只需要"足够接近"的 token 计数，不要求逐字节精确。
+pub struct BpeTokenizer {
+    bpe: CoreBPE,
+}
+
+impl BpeTokenizer {
+    /// 根据模型名选择编码表并构造分词器
+    pub fn for_model(model_name: &str) -> anyhow::Result<Self> {
+        let lower = model_name.to_lowercase();
+        let bpe = if lower.contains("sonnet-4") || lower.contains("opus-4") || lower.contains("haiku-4")
+        {
+            tiktoken_rs::o200k_base()?
+        } else {
+            tiktoken_rs::cl100k_base()?
+        };
+        Ok(Self { bpe })
+    }
+
+    /// 统计字符串的 token 数
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+
+    /// 截断到前 `max_tokens` 个 token，返回 (截断后的字符串, 省略的 token 数)
+    ///
+    /// decode 在 token 边界处可能切分了一个多字节字符，产生非法 UTF-8；
+    /// 此时逐步回退 token 数直到得到合法字符串，镜像字符截断模式下
+    /// "不得切分多字节字符"的不变量。
+    pub fn truncate_to_tokens(&self, text: &str, max_tokens: usize) -> (String, usize) {
+        let tokens = self.bpe.encode_with_special_tokens(text);
+        if tokens.len() <= max_tokens {
+            return (text.to_string(), 0);
+        }
+
+        let mut end = max_tokens;
+        while end > 0 {
+            if let Ok(decoded) = self.bpe.decode(tokens[..end].to_vec()) {
+                return (decoded, tokens.len() - end);
+            }
+            end -= 1;
+        }
+        (String::new(), tokens.len())
+    }
+
+    /// 截断到前 `head_tokens` 与后 `tail_tokens` 个 token，用于智能头尾截断
+    ///
+    /// 返回 (head 字符串, tail 字符串, 省略的 token 数)。
+    pub fn truncate_head_tail(
+        &self,
+        text: &str,
+        head_tokens: usize,
+        tail_tokens: usize,
+    ) -> (String, String, usize) {
+        let tokens = self.bpe.encode_with_special_tokens(text);
+        let total = tokens.len();
+        if total <= head_tokens + tail_tokens {
+            let (head, _) = self.truncate_to_tokens(text, total);
+            return (head, String::new(), 0);
+        }
+
+        let (head, _) = self.truncate_to_tokens(text, head_tokens);
+
+        let tail_start = total - tail_tokens;
+        let mut start = tail_start;
+        let tail = loop {
+            match self.bpe.decode(tokens[start..].to_vec()) {
+                Ok(decoded) => break decoded,
+                Err(_) if start + 1 < total => start += 1,
+                Err(_) => break String::new(),
+            }
+        };
+
+        let omitted = total - head_tokens - tail_tokens;
+        (head, tail, omitted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_to_tokens_short_text_unchanged() {
+        let tokenizer = BpeTokenizer::for_model("claude-sonnet-4.5").unwrap();
+        let (text, omitted) = tokenizer.truncate_to_tokens("hello world", 100);
+        assert_eq!(text, "hello world");
+        assert_eq!(omitted, 0);
+    }
+
+    #[test]
+    fn test_truncate_to_tokens_respects_budget() {
+        let tokenizer = BpeTokenizer::for_model("claude-sonnet-4.5").unwrap();
+        let long_text = "word ".repeat(1000);
+        let (text, omitted) = tokenizer.truncate_to_tokens(&long_text, 10);
+        assert!(omitted > 0);
+        assert!(tokenizer.count(&text) <= 10);
+    }
+}
@@ -11,30 +11,157 @@
 //! 1. 长边超过 max_long_edge 时，等比缩放
 //! 2. 总像素超过 max_pixels 时，等比缩放
 //! 3. 多图模式（图片数 >= threshold）使用独立的像素限制配置
+//!
+//! `process_gif_frames` 的抽帧分两个阶段：选帧（LZW 解码，顺序执行，解码
+//! 成本固定在 `GifDecoder` 内部无法并行）与处理（Lanczos3 缩放 + JPEG
+//! 编码，CPU 密集）。启用 `rayon` feature 时处理阶段按 `par_iter` 并发执行；
+//! 未启用时退化为顺序执行，两种情况下输出的帧顺序与内容完全一致。
+//!
+//! TIFF 走另一条路径：上游不接受 TIFF，单页 TIFF 在 `process_image` 里强制
+//! 重编码为 `TIFF_REENCODE_OUTPUT_FORMAT`；多页 TIFF（扫描件常见）需要
+//! `process_tiff_pages` 显式抽页——`image` crate 的 `TiffDecoder` 只暴露首个
+//! IFD，因此这里直接使用其底层依赖的 `tiff` crate 按页遍历。
 
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use image::AnimationDecoder;
 use image::codecs::gif::GifDecoder;
-use image::{DynamicImage, ImageFormat, ImageReader};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
 use std::io::{BufReader, Cursor};
 use std::time::Duration;
 
-use crate::model::config::CompressionConfig;
+use crate::model::config::{CompressionConfig, ImageResizeFilter};
 
 const GIF_MAX_OUTPUT_FRAMES: usize = 20;
 const GIF_MAX_FPS: usize = 5;
 const GIF_MIN_FRAME_DELAY: Duration = Duration::from_millis(10);
 const GIF_FRAME_OUTPUT_FORMAT: &str = "jpeg";
 
+/// TIFF 不被上游接受，单页 TIFF 强制重编码时固定转换为此格式（与 GIF 的
+/// “重编码但保留原格式”不同：TIFF 没有稳定的上游可接受落点，直接定死 JPEG）
+const TIFF_REENCODE_OUTPUT_FORMAT: &str = "jpeg";
+
+/// 根据配置构造解码阶段的资源上限，防御“解压缩炸弹”：攻击者构造一个体积很小
+/// 但声明巨幅画布（如 100000×100000）的图片，若不加限制会在 `into_dimensions()`/
+/// 完整解码阶段触发数 GB 级内存分配。
+///
+/// `image` crate 的 [`Limits`] 按宽、高分别限制，而配置里 `image_decode_max_pixels`
+/// 是一个总像素预算，这里按等边正方形换算出单边上限（`sqrt(max_pixels)`）——
+/// 足以拦住总像素超标的画布，不追求对任意宽高比的精确额度。
+fn build_decode_limits(config: &CompressionConfig) -> Limits {
+    let mut limits = Limits::default();
+    if config.image_decode_max_pixels > 0 {
+        let max_edge = (config.image_decode_max_pixels as f64).sqrt().ceil() as u32;
+        limits.max_image_width = Some(max_edge.max(1));
+        limits.max_image_height = Some(max_edge.max(1));
+    }
+    if config.image_decode_max_bytes > 0 {
+        limits.max_alloc = Some(config.image_decode_max_bytes as u64);
+    }
+    limits
+}
+
+/// 构造一个已应用解码资源上限的 `ImageReader`（格式识别之后、`into_dimensions()`/
+/// 完整解码之前）
+fn guessed_reader_with_limits(
+    bytes: &[u8],
+    config: &CompressionConfig,
+) -> Result<ImageReader<Cursor<&[u8]>>, String> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("图片格式识别失败: {}", e))?;
+    reader.limits(build_decode_limits(config));
+    Ok(reader)
+}
+
+/// 在已应用解码资源上限的前提下完整解码图片
+fn decode_with_limits(bytes: &[u8], config: &CompressionConfig) -> Result<DynamicImage, String> {
+    guessed_reader_with_limits(bytes, config)?
+        .decode()
+        .map_err(|e| format!("image exceeds decode limits: {}", e))
+}
+
+/// 把配置里的滤波器选项映射为 `image` crate 的 `FilterType`
+fn resize_filter_type(filter: ImageResizeFilter) -> image::imageops::FilterType {
+    match filter {
+        ImageResizeFilter::Nearest => image::imageops::FilterType::Nearest,
+        ImageResizeFilter::Triangle => image::imageops::FilterType::Triangle,
+        ImageResizeFilter::CatmullRom => image::imageops::FilterType::CatmullRom,
+        ImageResizeFilter::Lanczos3 => image::imageops::FilterType::Lanczos3,
+    }
+}
+
+/// 固定质量下限：`image_max_final_bytes` 二分降质时不会低于此质量，
+/// 即便仍未命中预算也在此处止步，作为"尽力而为"的兜底
+const JPEG_BUDGET_MIN_QUALITY: u8 = 40;
+
+/// 若设置了单张图片字节预算且当前输出是 JPEG，在初次编码超预算时按
+/// `config.image_jpeg_quality` 与 `JPEG_BUDGET_MIN_QUALITY` 之间二分降质
+/// 重编码；非 JPEG 输出、未设置预算、或初次编码已达标时原样返回 `initial`。
+fn apply_jpeg_byte_budget(
+    img: &DynamicImage,
+    output_format: &str,
+    config: &CompressionConfig,
+    initial: (String, usize),
+) -> Result<(String, usize), String> {
+    let Some(target_bytes) = config.image_max_final_bytes else {
+        return Ok(initial);
+    };
+    if !output_format.eq_ignore_ascii_case("jpeg") && !output_format.eq_ignore_ascii_case("jpg") {
+        return Ok(initial);
+    }
+    if initial.1 <= target_bytes {
+        return Ok(initial);
+    }
+    encode_jpeg_within_budget(img, config.image_jpeg_quality, target_bytes)
+}
+
+/// 在 `[JPEG_BUDGET_MIN_QUALITY, initial_quality]` 区间二分查找能让 JPEG 编码
+/// 体积不超过 `target_bytes` 的最高质量（假设质量越低体积越小这一 JPEG 编码
+/// 的典型单调性）。调用方已确认按 `initial_quality` 编码过的结果超预算，
+/// 区间内始终没有命中预算时，退化为质量下限的编码结果（尽力而为，不报错）。
+fn encode_jpeg_within_budget(
+    img: &DynamicImage,
+    initial_quality: u8,
+    target_bytes: usize,
+) -> Result<(String, usize), String> {
+    let initial_quality = initial_quality.max(JPEG_BUDGET_MIN_QUALITY);
+    let mut lo = JPEG_BUDGET_MIN_QUALITY;
+    let mut hi = initial_quality;
+    let mut best = encode_image(img, "jpeg", JPEG_BUDGET_MIN_QUALITY)?;
+
+    while lo + 1 < hi {
+        let mid = lo + (hi - lo) / 2;
+        let candidate = encode_image(img, "jpeg", mid)?;
+        if candidate.1 <= target_bytes {
+            best = candidate;
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(best)
+}
+
 #[derive(Debug)]
 pub struct GifSamplingResult {
-    pub frames: Vec<ImageProcessResult>,
+    pub output: GifOutput,
     pub duration_ms: u64,
     pub source_frames: usize,
     pub sampling_interval_ms: u64,
     pub output_format: &'static str,
 }
 
+/// `process_gif_frames` 的两种输出形态，由 `CompressionConfig::gif_animated_output`
+/// 选择：
+/// - `Frames`：默认行为，采样帧各自独立重编码为静态 JPEG（保留原有行为/测试）
+/// - `Animated`：采样帧重组为一张共享调色板的动图 GIF，保留动态语义、
+///   体积通常远小于多张 JPEG 之和
+#[derive(Debug)]
+pub enum GifOutput {
+    Frames(Vec<ImageProcessResult>),
+    Animated(ImageProcessResult),
+}
+
 /// 图片处理结果
 #[derive(Debug)]
 pub struct ImageProcessResult {
@@ -77,8 +204,11 @@ pub fn process_gif_frames(
 
     // Pass 1：计算时长（ms）与源帧数，用于确定采样间隔
     let (duration_ms, source_frames) = {
-        let decoder = GifDecoder::new(BufReader::new(Cursor::new(&gif_bytes)))
+        let mut decoder = GifDecoder::new(BufReader::new(Cursor::new(&gif_bytes)))
             .map_err(|e| format!("GIF 解码失败: {}", e))?;
+        decoder
+            .set_limits(build_decode_limits(config))
+            .map_err(|e| format!("image exceeds decode limits: {}", e))?;
         let mut total = 0u64;
         let mut n = 0usize;
         for frame in decoder.into_frames() {
@@ -114,15 +244,21 @@ pub fn process_gif_frames(
     };
 
     // Pass 2：按采样间隔选择帧并重编码
-    let decoder = GifDecoder::new(BufReader::new(Cursor::new(&gif_bytes)))
+    let mut decoder = GifDecoder::new(BufReader::new(Cursor::new(&gif_bytes)))
         .map_err(|e| format!("GIF 解码失败: {}", e))?;
-
-    let mut frames_out = Vec::new();
+    decoder
+        .set_limits(build_decode_limits(config))
+        .map_err(|e| format!("image exceeds decode limits: {}", e))?;
+
+    // 选帧仅做 LZW 解码（GifDecoder 自身的成本，无法并行化），挑中的帧先只
+    // 存原始像素缓冲；Lanczos3 缩放 + JPEG 编码这类 CPU 密集工作挪到下面的
+    // 并行阶段统一处理
+    let mut sampled = Vec::new();
     let mut elapsed_ms = 0u64; // 当前帧起始时间
     let mut next_sample_ms = 0u64;
 
     for frame in decoder.into_frames() {
-        if frames_out.len() >= GIF_MAX_OUTPUT_FRAMES {
+        if sampled.len() >= GIF_MAX_OUTPUT_FRAMES {
             break;
         }
 
@@ -134,48 +270,36 @@ pub fn process_gif_frames(
         if frame_start_ms >= next_sample_ms {
             let buffer = frame.into_buffer();
             let original_size = (buffer.width(), buffer.height());
-
-            let (target_w, target_h) = apply_scaling_rules(
-                original_size.0,
-                original_size.1,
-                config.image_max_long_edge,
-                max_pixels,
-            );
-            let needs_resize = target_w != original_size.0 || target_h != original_size.1;
-
-            let img = DynamicImage::ImageRgba8(buffer);
-            let processed = if needs_resize {
-                img.resize(target_w, target_h, image::imageops::FilterType::Lanczos3)
-            } else {
-                img
-            };
-
-            let final_size = (processed.width(), processed.height());
-            let (data, final_bytes_len) = encode_image(&processed, GIF_FRAME_OUTPUT_FORMAT)?;
-
-            frames_out.push(ImageProcessResult {
-                data,
-                original_size,
-                final_size,
-                tokens: calculate_tokens(final_size.0, final_size.1),
-                was_resized: needs_resize,
-                was_reencoded: true,
-                original_bytes_len,
-                final_bytes_len,
-            });
-
+            sampled.push(SampledGifFrame { original_size, buffer });
             next_sample_ms = frame_start_ms.saturating_add(sampling_interval_ms);
         }
 
         elapsed_ms = elapsed_ms.saturating_add(delay.as_millis().min(u128::from(u64::MAX)) as u64);
     }
 
-    if frames_out.is_empty() {
+    if sampled.is_empty() {
         return Err("GIF 抽帧结果为空".to_string());
     }
 
+    let output = if config.gif_animated_output {
+        GifOutput::Animated(build_animated_gif_output(
+            sampled,
+            config,
+            max_pixels,
+            sampling_interval_ms,
+            original_bytes_len,
+        )?)
+    } else {
+        GifOutput::Frames(process_sampled_gif_frames(
+            sampled,
+            config,
+            max_pixels,
+            original_bytes_len,
+        )?)
+    };
+
     Ok(GifSamplingResult {
-        frames: frames_out,
+        output,
         duration_ms,
         source_frames,
         sampling_interval_ms,
@@ -183,6 +307,345 @@ pub fn process_gif_frames(
     })
 }
 
+/// Pass 2 选中的一帧：原始尺寸 + 解码后的像素缓冲，缩放/编码延后到并行阶段处理
+struct SampledGifFrame {
+    original_size: (u32, u32),
+    buffer: image::RgbaImage,
+}
+
+/// 按原始顺序处理已选中的帧（缩放 + 编码），启用 `rayon` feature 时并发执行
+#[cfg(feature = "rayon")]
+fn process_sampled_gif_frames(
+    sampled: Vec<SampledGifFrame>,
+    config: &CompressionConfig,
+    max_pixels: u32,
+    original_bytes_len: usize,
+) -> Result<Vec<ImageProcessResult>, String> {
+    use rayon::prelude::*;
+    sampled
+        .into_par_iter()
+        .map(|frame| encode_sampled_gif_frame(frame, config, max_pixels, original_bytes_len))
+        .collect()
+}
+
+/// 按原始顺序处理已选中的帧（缩放 + 编码），未启用 `rayon` feature 时退化为
+/// 原先的顺序执行，行为与开启前完全一致
+#[cfg(not(feature = "rayon"))]
+fn process_sampled_gif_frames(
+    sampled: Vec<SampledGifFrame>,
+    config: &CompressionConfig,
+    max_pixels: u32,
+    original_bytes_len: usize,
+) -> Result<Vec<ImageProcessResult>, String> {
+    sampled
+        .into_iter()
+        .map(|frame| encode_sampled_gif_frame(frame, config, max_pixels, original_bytes_len))
+        .collect()
+}
+
+/// 对单个已选中帧做缩放 + 重编码，产出最终 `ImageProcessResult`
+fn encode_sampled_gif_frame(
+    frame: SampledGifFrame,
+    config: &CompressionConfig,
+    max_pixels: u32,
+    original_bytes_len: usize,
+) -> Result<ImageProcessResult, String> {
+    let original_size = frame.original_size;
+    let (target_w, target_h) = apply_scaling_rules(
+        original_size.0,
+        original_size.1,
+        config.image_max_long_edge,
+        max_pixels,
+    );
+    let needs_resize = target_w != original_size.0 || target_h != original_size.1;
+
+    let img = DynamicImage::ImageRgba8(frame.buffer);
+    let processed = if needs_resize {
+        img.resize(target_w, target_h, resize_filter_type(config.image_resize_filter))
+    } else {
+        img
+    };
+
+    let final_size = (processed.width(), processed.height());
+    let encoded = encode_image(&processed, GIF_FRAME_OUTPUT_FORMAT, config.image_jpeg_quality)?;
+    let (data, final_bytes_len) = apply_jpeg_byte_budget(&processed, GIF_FRAME_OUTPUT_FORMAT, config, encoded)?;
+
+    Ok(ImageProcessResult {
+        data,
+        original_size,
+        final_size,
+        tokens: calculate_tokens(final_size.0, final_size.1),
+        was_resized: needs_resize,
+        was_reencoded: true,
+        original_bytes_len,
+        final_bytes_len,
+    })
+}
+
+/// 采样帧去重阈值：相邻两帧平均每通道归一化差异低于此比例（0~1）视为
+/// “近似相同”，丢弃靠后的那一帧以缩小共享调色板压力与最终体积
+const GIF_TEMPORAL_DEDUP_MAX_DIFF_RATIO: f64 = 0.02;
+
+/// NeuQuant 调色板量化的采样质量：数值越小质量越高、计算越慢，1~30 常用区间
+const GIF_PALETTE_QUANTIZE_QUALITY: i32 = 10;
+
+/// 把采样帧重组为一张共享 ≤256 色调色板的动图 GIF（`GifOutput::Animated`）
+///
+/// 步骤：
+/// 1. 所有帧统一缩放到同一目标画布（按第一帧原始尺寸套用现有缩放规则；
+///    动图的每一帧画布尺寸必须一致）
+/// 2. 丢弃与前一帧近似相同的帧（轻量时间维度去重），降低调色板压力与体积
+/// 3. 用 `color_quant::NeuQuant` 在所有剩余帧像素上统一建一份共享调色板，
+///    把每帧像素就地映射为调色板中最接近的颜色——编码时这些帧天然落在
+///    同一套颜色上，不会出现逐帧独立调色板导致的体积浪费
+/// 4. 用已经算好的降采样间隔作为每帧延时，编码为一张无限循环的动图 GIF
+fn build_animated_gif_output(
+    sampled: Vec<SampledGifFrame>,
+    config: &CompressionConfig,
+    max_pixels: u32,
+    sampling_interval_ms: u64,
+    original_bytes_len: usize,
+) -> Result<ImageProcessResult, String> {
+    let original_size = sampled
+        .first()
+        .expect("sampled 非空（调用方已检查）")
+        .original_size;
+    let (target_w, target_h) = apply_scaling_rules(
+        original_size.0,
+        original_size.1,
+        config.image_max_long_edge,
+        max_pixels,
+    );
+    let needs_resize = target_w != original_size.0 || target_h != original_size.1;
+
+    // 第 1 步：统一缩放到同一画布尺寸
+    let filter = resize_filter_type(config.image_resize_filter);
+    let mut buffers: Vec<image::RgbaImage> = sampled
+        .into_iter()
+        .map(|frame| {
+            DynamicImage::ImageRgba8(frame.buffer)
+                .resize_exact(target_w, target_h, filter)
+                .to_rgba8()
+        })
+        .collect();
+
+    // 第 2 步：丢弃与前一帧近似相同的帧
+    dedup_near_identical_frames(&mut buffers);
+
+    // 第 3 步：在所有剩余帧上统一建一份共享调色板，就地把像素映射到最近颜色
+    let all_pixels: Vec<u8> = buffers.iter().flat_map(|b| b.as_raw().iter().copied()).collect();
+    let palette = color_quant::NeuQuant::new(GIF_PALETTE_QUANTIZE_QUALITY, 256, &all_pixels);
+    for buffer in &mut buffers {
+        for pixel in buffer.pixels_mut() {
+            palette.map_pixel(&mut pixel.0);
+        }
+    }
+
+    // 第 4 步：编码为一张无限循环的动图 GIF
+    let delay = image::Delay::from_numer_denom_ms(sampling_interval_ms.min(u32::MAX as u64) as u32, 1);
+    let frame_count = buffers.len() as u64;
+    let frames: Vec<image::Frame> = buffers
+        .into_iter()
+        .map(|buffer| image::Frame::from_parts(buffer, 0, 0, delay))
+        .collect();
+
+    let mut encoded = Vec::new();
+    {
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut encoded);
+        encoder
+            .set_repeat(image::codecs::gif::Repeat::Infinite)
+            .map_err(|e| format!("动图 GIF 编码失败: {}", e))?;
+        encoder
+            .encode_frames(frames)
+            .map_err(|e| format!("动图 GIF 编码失败: {}", e))?;
+    }
+    let final_bytes_len = encoded.len();
+    let data = BASE64.encode(&encoded);
+
+    Ok(ImageProcessResult {
+        data,
+        original_size,
+        final_size: (target_w, target_h),
+        // 动图的 token 估算按最终单帧尺寸 × 帧数计（见请求原文），
+        // 与官方单图公式保持一致的同时反映动图的多帧成本
+        tokens: calculate_tokens(target_w, target_h) * frame_count,
+        was_resized: needs_resize,
+        was_reencoded: true,
+        original_bytes_len,
+        final_bytes_len,
+    })
+}
+
+/// 丢弃与前一帧“近似相同”的帧，第一帧始终保留
+fn dedup_near_identical_frames(buffers: &mut Vec<image::RgbaImage>) {
+    if buffers.len() <= 1 {
+        return;
+    }
+    let mut kept: Vec<image::RgbaImage> = Vec::with_capacity(buffers.len());
+    for buffer in buffers.drain(..) {
+        let is_redundant = kept
+            .last()
+            .map(|prev| frame_diff_ratio(prev, &buffer) < GIF_TEMPORAL_DEDUP_MAX_DIFF_RATIO)
+            .unwrap_or(false);
+        if !is_redundant {
+            kept.push(buffer);
+        }
+    }
+    *buffers = kept;
+}
+
+/// 两帧（尺寸相同）之间的平均每通道归一化差异，取值 [0, 1]
+fn frame_diff_ratio(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+    let a_raw = a.as_raw();
+    let b_raw = b.as_raw();
+    if a_raw.len() != b_raw.len() || a_raw.is_empty() {
+        return 1.0;
+    }
+    let total_diff: u64 = a_raw
+        .iter()
+        .zip(b_raw.iter())
+        .map(|(x, y)| (*x as i32 - *y as i32).unsigned_abs() as u64)
+        .sum();
+    total_diff as f64 / (a_raw.len() as f64 * 255.0)
+}
+
+/// 把一页 TIFF 解码为 RGBA 像素缓冲
+///
+/// `tiff` crate 的 `Decoder` 只暴露 8 位通道的常见色彩类型（Gray/RGB/RGBA），
+/// 与仓库其余解码路径一致，遇到不支持的色彩类型直接报错而非强行猜测转换。
+///
+/// 解码前依次校验：
+/// 1. `image_decode_max_pixels`：单页画布尺寸（与其他格式的单图上限语义一致）
+/// 2. `image_decode_max_bytes`：`read_image()` 即将分配的内存（按 RGBA 最坏情况
+///    估算），累加到调用方传入的 `cumulative_alloc_bytes` 后再比较——与
+///    `GifDecoder::set_limits` 的 `max_alloc` 覆盖整个解码会话（所有帧）同理，
+///    避免单页尺寸刚好卡在上限内、但乘以 `tiff_max_pages` 页数后总分配量
+///    远超预算的情况
+///
+/// `tiff` crate 是直接依赖，不经过 `image::ImageDecoder::set_limits`，这里
+/// 手动补上与 `build_decode_limits` 等效的防御。
+fn decode_tiff_page(
+    decoder: &mut tiff::decoder::Decoder<Cursor<&[u8]>>,
+    config: &CompressionConfig,
+    cumulative_alloc_bytes: &mut u64,
+) -> Result<image::RgbaImage, String> {
+    let (width, height) = decoder
+        .dimensions()
+        .map_err(|e| format!("读取 TIFF 尺寸失败: {}", e))?;
+
+    if config.image_decode_max_pixels > 0
+        && (width as u64) * (height as u64) > config.image_decode_max_pixels
+    {
+        return Err("image exceeds decode limits: TIFF 画布尺寸超出上限".to_string());
+    }
+
+    let page_alloc_bytes = (width as u64) * (height as u64) * 4;
+    *cumulative_alloc_bytes = cumulative_alloc_bytes.saturating_add(page_alloc_bytes);
+    if config.image_decode_max_bytes > 0 && *cumulative_alloc_bytes > config.image_decode_max_bytes as u64 {
+        return Err("image exceeds decode limits: TIFF 多页累计解码内存超出上限".to_string());
+    }
+
+    let color_type = decoder
+        .colortype()
+        .map_err(|e| format!("读取 TIFF 色彩类型失败: {}", e))?;
+    let result = decoder
+        .read_image()
+        .map_err(|e| format!("TIFF 页解码失败: {}", e))?;
+
+    let raw = match result {
+        tiff::decoder::DecodingResult::U8(buf) => buf,
+        _ => return Err("仅支持 8 位通道的 TIFF".to_string()),
+    };
+
+    let rgba = match color_type {
+        tiff::ColorType::RGBA(8) => raw,
+        tiff::ColorType::RGB(8) => raw.chunks_exact(3).flat_map(|c| [c[0], c[1], c[2], 255]).collect(),
+        tiff::ColorType::Gray(8) => raw.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        other => return Err(format!("不支持的 TIFF 色彩类型: {:?}", other)),
+    };
+
+    image::RgbaImage::from_raw(width, height, rgba)
+        .ok_or_else(|| "TIFF 像素数据与画布尺寸不匹配".to_string())
+}
+
+/// 多页 TIFF 抽页：按页解码并各自重编码为静态图（用于扫描件/多页文档场景）
+///
+/// 与 `process_gif_frames` 的抽帧思路一致，但 TIFF 页之间没有时间轴，因此
+/// 没有采样间隔的概念——按顺序取前 `config.tiff_max_pages` 页即可，每页独立
+/// 缩放并重编码为 `TIFF_REENCODE_OUTPUT_FORMAT`。
+pub fn process_tiff_pages(
+    base64_data: &str,
+    config: &CompressionConfig,
+    image_count: usize,
+) -> Result<Vec<ImageProcessResult>, String> {
+    let bytes = BASE64
+        .decode(base64_data)
+        .map_err(|e| format!("base64 解码失败: {}", e))?;
+    let original_bytes_len = bytes.len();
+
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(bytes.as_slice()))
+        .map_err(|e| format!("TIFF 解码失败: {}", e))?;
+
+    let max_pixels = if image_count >= config.image_multi_threshold {
+        config.image_max_pixels_multi
+    } else {
+        config.image_max_pixels_single
+    };
+
+    let mut pages = Vec::new();
+    let mut cumulative_alloc_bytes: u64 = 0;
+    loop {
+        if pages.len() >= config.tiff_max_pages {
+            break;
+        }
+
+        let buffer = decode_tiff_page(&mut decoder, config, &mut cumulative_alloc_bytes)?;
+        let original_size = (buffer.width(), buffer.height());
+        let (target_w, target_h) = apply_scaling_rules(
+            original_size.0,
+            original_size.1,
+            config.image_max_long_edge,
+            max_pixels,
+        );
+        let needs_resize = target_w != original_size.0 || target_h != original_size.1;
+
+        let img = DynamicImage::ImageRgba8(buffer);
+        let processed = if needs_resize {
+            img.resize(target_w, target_h, resize_filter_type(config.image_resize_filter))
+        } else {
+            img
+        };
+        let final_size = (processed.width(), processed.height());
+        let encoded = encode_image(&processed, TIFF_REENCODE_OUTPUT_FORMAT, config.image_jpeg_quality)?;
+        let (data, final_bytes_len) =
+            apply_jpeg_byte_budget(&processed, TIFF_REENCODE_OUTPUT_FORMAT, config, encoded)?;
+
+        pages.push(ImageProcessResult {
+            data,
+            original_size,
+            final_size,
+            tokens: calculate_tokens(final_size.0, final_size.1),
+            was_resized: needs_resize,
+            was_reencoded: true,
+            original_bytes_len,
+            final_bytes_len,
+        });
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder
+            .next_image()
+            .map_err(|e| format!("TIFF 翻页失败: {}", e))?;
+    }
+
+    if pages.is_empty() {
+        return Err("TIFF 不包含任何页面".to_string());
+    }
+
+    Ok(pages)
+}
+
 /// 强制将任意图片重编码为指定格式（可选缩放）
 ///
 /// 用于需要把输入格式（如 GIF）转换为上游更稳定支持的静态格式（如 JPEG）时。
@@ -197,10 +660,7 @@ pub fn process_image_to_format(
         .map_err(|e| format!("base64 解码失败: {}", e))?;
     let original_bytes_len = bytes.len();
 
-    let reader = ImageReader::new(Cursor::new(&bytes))
-        .with_guessed_format()
-        .map_err(|e| format!("图片格式识别失败: {}", e))?;
-    let original_size = reader
+    let original_size = guessed_reader_with_limits(&bytes, config)?
         .into_dimensions()
         .map_err(|e| format!("读取图片尺寸失败: {}", e))?;
 
@@ -218,15 +678,16 @@ pub fn process_image_to_format(
     );
     let needs_resize = target_w != original_size.0 || target_h != original_size.1;
 
-    let img = image::load_from_memory(&bytes).map_err(|e| format!("图片加载失败: {}", e))?;
+    let img = decode_with_limits(&bytes, config)?;
     let processed = if needs_resize {
-        img.resize(target_w, target_h, image::imageops::FilterType::Lanczos3)
+        img.resize(target_w, target_h, resize_filter_type(config.image_resize_filter))
     } else {
         img
     };
 
     let final_size = (processed.width(), processed.height());
-    let (data, final_bytes_len) = encode_image(&processed, output_format)?;
+    let encoded = encode_image(&processed, output_format, config.image_jpeg_quality)?;
+    let (data, final_bytes_len) = apply_jpeg_byte_budget(&processed, output_format, config, encoded)?;
 
     Ok(ImageProcessResult {
         data,
@@ -242,13 +703,15 @@ pub fn process_image_to_format(
 
 /// 从 base64 数据计算图片 token（不缩放）
 ///
-/// 返回 (tokens, width, height)，解析失败返回 None
-pub fn estimate_image_tokens(base64_data: &str) -> Option<(u64, u32, u32)> {
+/// 返回 (tokens, width, height)，解析失败返回 None。`config` 用于限定解码阶段
+/// 允许读取的画布尺寸/内存分配上限，防止声明巨幅画布的图片在这里就触发
+/// 过量内存分配。
+pub fn estimate_image_tokens(base64_data: &str, config: &CompressionConfig) -> Option<(u64, u32, u32)> {
     let bytes = BASE64.decode(base64_data).ok()?;
-    let reader = ImageReader::new(Cursor::new(&bytes))
-        .with_guessed_format()
+    let (width, height) = guessed_reader_with_limits(&bytes, config)
+        .ok()?
+        .into_dimensions()
         .ok()?;
-    let (width, height) = reader.into_dimensions().ok()?;
 
     // 应用 Anthropic 缩放规则计算 token
     let (scaled_w, scaled_h) = apply_scaling_rules(width, height, 1568, 1_150_000);
@@ -261,7 +724,7 @@ pub fn estimate_image_tokens(base64_data: &str) -> Option<(u64, u32, u32)> {
 ///
 /// # 参数
 /// - `base64_data`: 原始 base64 编码的图片数据
-/// - `format`: 图片格式（"jpeg", "png", "gif", "webp"）
+/// - `format`: 图片格式（"jpeg", "png", "gif", "webp", "tiff"/"tif"）
 /// - `config`: 压缩配置
 /// - `image_count`: 当前请求中的图片总数（用于判断是否启用多图模式）
 pub fn process_image(
@@ -276,11 +739,8 @@ pub fn process_image(
         .map_err(|e| format!("base64 解码失败: {}", e))?;
     let original_bytes_len = bytes.len();
 
-    // 先只读取图片头获取尺寸（避免不必要的全量解码）
-    let reader = ImageReader::new(Cursor::new(&bytes))
-        .with_guessed_format()
-        .map_err(|e| format!("图片格式识别失败: {}", e))?;
-    let original_size = reader
+    // 先只读取图片头获取尺寸（避免不必要的全量解码），已应用解码资源上限
+    let original_size = guessed_reader_with_limits(&bytes, config)?
         .into_dimensions()
         .map_err(|e| format!("读取图片尺寸失败: {}", e))?;
 
@@ -304,20 +764,30 @@ pub fn process_image(
     // GIF 特殊处理：即使不需要缩放，也强制重新编码为静态帧。
     // 原因：动图通常“像素不大但字节巨大”，直接透传 base64 会显著放大请求体，
     // 进而触发上游 400 Improperly formed request。
-    let force_reencode = format.eq_ignore_ascii_case("gif");
-    let should_decode_and_encode = needs_resize || force_reencode;
-
-    // 仅在需要缩放或强制重编码时才全量解码图片
+    //
+    // TIFF 特殊处理：上游不接受 TIFF，无论是否需要缩放都强制重新编码到
+    // `TIFF_REENCODE_OUTPUT_FORMAT`（与 GIF 不同，GIF 重编码后仍是 GIF，
+    // TIFF 没有稳定的上游可接受落点，必须换格式）。
+    let is_tiff = format.eq_ignore_ascii_case("tiff") || format.eq_ignore_ascii_case("tif");
+    let force_reencode = format.eq_ignore_ascii_case("gif") || is_tiff;
+    let output_format = if is_tiff { TIFF_REENCODE_OUTPUT_FORMAT } else { format };
+    // 设置了字节预算且输出是 JPEG 时，即便无需缩放也要解码重编码，才能应用预算
+    let needs_budget_check = config.image_max_final_bytes.is_some()
+        && (output_format.eq_ignore_ascii_case("jpeg") || output_format.eq_ignore_ascii_case("jpg"));
+    let should_decode_and_encode = needs_resize || force_reencode || needs_budget_check;
+
+    // 仅在需要缩放、强制重编码、或需要应用字节预算时才全量解码图片
     let (output_data, final_size, final_bytes_len, was_reencoded) = if should_decode_and_encode {
-        let img = image::load_from_memory(&bytes).map_err(|e| format!("图片加载失败: {}", e))?;
+        let img = decode_with_limits(&bytes, config)?;
         let processed = if needs_resize {
-            img.resize(target_w, target_h, image::imageops::FilterType::Lanczos3)
+            img.resize(target_w, target_h, resize_filter_type(config.image_resize_filter))
         } else {
             img
         };
         let size = (processed.width(), processed.height());
-        let (data, bytes_len) = encode_image(&processed, format)?;
-        (data, size, bytes_len, force_reencode && !needs_resize)
+        let encoded = encode_image(&processed, output_format, config.image_jpeg_quality)?;
+        let (data, bytes_len) = apply_jpeg_byte_budget(&processed, output_format, config, encoded)?;
+        (data, size, bytes_len, (force_reencode || needs_budget_check) && !needs_resize)
     } else {
         (base64_data.to_string(), original_size, original_bytes_len, false)
     };
@@ -370,25 +840,76 @@ fn calculate_tokens(width: u32, height: u32) -> u64 {
 }
 
 /// 将图片编码为 base64
-fn encode_image(img: &DynamicImage, format: &str) -> Result<(String, usize), String> {
+///
+/// `jpeg_quality`（1~100）仅在编码为 JPEG 时生效，其余格式忽略此参数。
+fn encode_image(img: &DynamicImage, format: &str, jpeg_quality: u8) -> Result<(String, usize), String> {
     let mut buffer = Cursor::new(Vec::new());
 
-    let image_format = match format {
-        "jpeg" | "jpg" => ImageFormat::Jpeg,
-        "png" => ImageFormat::Png,
-        "gif" => ImageFormat::Gif,
-        "webp" => ImageFormat::WebP,
+    match format {
+        "jpeg" | "jpg" => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, jpeg_quality);
+            img.write_with_encoder(encoder)
+                .map_err(|e| format!("图片编码失败: {}", e))?;
+        }
+        "png" => img
+            .write_to(&mut buffer, ImageFormat::Png)
+            .map_err(|e| format!("图片编码失败: {}", e))?,
+        "gif" => img
+            .write_to(&mut buffer, ImageFormat::Gif)
+            .map_err(|e| format!("图片编码失败: {}", e))?,
+        "webp" => img
+            .write_to(&mut buffer, ImageFormat::WebP)
+            .map_err(|e| format!("图片编码失败: {}", e))?,
         _ => return Err(format!("不支持的图片格式: {}", format)),
-    };
-
-    img.write_to(&mut buffer, image_format)
-        .map_err(|e| format!("图片编码失败: {}", e))?;
+    }
 
     let encoded = buffer.into_inner();
     let bytes_len = encoded.len();
     Ok((BASE64.encode(encoded), bytes_len))
 }
 
+/// 按像素预算与质量阶梯重新编码单张图片原始字节，用于自适应压缩的图片收缩层
+///
+/// 先按 `max_pixels` 等比缩小（独立于 `image_max_long_edge`，仅用于紧急收缩），
+/// 再按 `quality_ladder` 从高到低依次尝试编码为 JPEG，命中 `target_bytes` 或
+/// 质量降到阶梯最低点即停止。返回 (编码后字节, 实际使用的质量)。
+pub fn recompress_image_to_budget(
+    bytes: &[u8],
+    max_pixels: u32,
+    quality_ladder: &[u8],
+    target_bytes: usize,
+) -> Result<(Vec<u8>, u8), String> {
+    if quality_ladder.is_empty() {
+        return Err("quality_ladder 不能为空".to_string());
+    }
+
+    let img = image::load_from_memory(bytes).map_err(|e| format!("图片加载失败: {}", e))?;
+    let pixels = img.width() as u64 * img.height() as u64;
+    let img = if pixels > max_pixels as u64 {
+        let scale = (max_pixels as f64 / pixels as f64).sqrt();
+        let target_w = ((img.width() as f64 * scale).floor().max(1.0)) as u32;
+        let target_h = ((img.height() as f64 * scale).floor().max(1.0)) as u32;
+        img.resize(target_w, target_h, image::imageops::FilterType::Lanczos3)
+    } else {
+        img
+    };
+
+    let mut best: Option<(Vec<u8>, u8)> = None;
+    for &quality in quality_ladder {
+        let mut buffer = Vec::new();
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+        img.write_with_encoder(encoder)
+            .map_err(|e| format!("图片编码失败: {}", e))?;
+        let fits = buffer.len() <= target_bytes;
+        best = Some((buffer, quality));
+        if fits {
+            break;
+        }
+    }
+
+    best.ok_or_else(|| "质量阶梯为空".to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -483,8 +1004,11 @@ mod tests {
 
         assert_eq!(res.duration_ms, 8000);
         assert_eq!(res.sampling_interval_ms, 500);
-        assert_eq!(res.frames.len(), 16);
-        assert!(res.frames.len() <= GIF_MAX_OUTPUT_FRAMES);
+        let GifOutput::Frames(frames) = &res.output else {
+            panic!("默认配置应产出 GifOutput::Frames");
+        };
+        assert_eq!(frames.len(), 16);
+        assert!(frames.len() <= GIF_MAX_OUTPUT_FRAMES);
         assert_eq!(res.output_format, GIF_FRAME_OUTPUT_FORMAT);
     }
 
@@ -517,8 +1041,120 @@ mod tests {
 
         assert_eq!(res.duration_ms, 4000);
         assert_eq!(res.sampling_interval_ms, 200);
-        assert_eq!(res.frames.len(), 20);
-        assert!(res.frames.len() <= GIF_MAX_OUTPUT_FRAMES);
+        let GifOutput::Frames(frames) = &res.output else {
+            panic!("默认配置应产出 GifOutput::Frames");
+        };
+        assert_eq!(frames.len(), 20);
+        assert!(frames.len() <= GIF_MAX_OUTPUT_FRAMES);
         assert_eq!(res.output_format, GIF_FRAME_OUTPUT_FORMAT);
     }
+
+    #[test]
+    fn test_process_gif_frames_animated_output_mode() {
+        use image::codecs::gif::{GifEncoder, Repeat};
+        use image::{Delay, Frame, Rgba, RgbaImage};
+
+        let frame_delay = Delay::from_numer_denom_ms(100, 1);
+        let mut frames = Vec::new();
+        for i in 0..20u8 {
+            let mut img = RgbaImage::new(32, 32);
+            for p in img.pixels_mut() {
+                *p = Rgba([i * 10, 0, 255u8.saturating_sub(i * 10), 255]);
+            }
+            frames.push(Frame::from_parts(img, 0, 0, frame_delay));
+        }
+
+        let mut buf = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut buf);
+            encoder.set_repeat(Repeat::Infinite).unwrap();
+            encoder.encode_frames(frames).unwrap();
+        }
+
+        let base64_data = BASE64.encode(&buf);
+        let mut config = CompressionConfig::default();
+        config.gif_animated_output = true;
+        let res = process_gif_frames(&base64_data, &config, 1).unwrap();
+
+        let GifOutput::Animated(result) = &res.output else {
+            panic!("gif_animated_output = true 应产出 GifOutput::Animated");
+        };
+        assert!(result.was_reencoded);
+        assert!(!result.data.is_empty());
+        assert_eq!(result.final_bytes_len, BASE64.decode(&result.data).unwrap().len());
+    }
+
+    #[test]
+    fn test_process_image_lower_jpeg_quality_shrinks_output() {
+        use image::{Rgba, RgbaImage};
+
+        // 带噪点的图片让 JPEG 体积对质量足够敏感
+        let mut img = RgbaImage::new(200, 200);
+        for (i, p) in img.pixels_mut().enumerate() {
+            let v = ((i * 2654435761) % 256) as u8;
+            *p = Rgba([v, v.wrapping_add(50), v.wrapping_add(100), 255]);
+        }
+        let mut buf = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .unwrap();
+        let base64_data = BASE64.encode(&buf);
+
+        // 图片无需缩放时 process_image 原样透传；设置一个不会触发降质的字节预算，
+        // 让两次调用都按各自的 image_jpeg_quality 重编码为 JPEG
+        let mut high_q_config = CompressionConfig::default();
+        high_q_config.image_jpeg_quality = 95;
+        high_q_config.image_max_final_bytes = Some(usize::MAX);
+        let high_q = process_image(&base64_data, "jpeg", &high_q_config, 1).unwrap();
+
+        let mut low_q_config = CompressionConfig::default();
+        low_q_config.image_jpeg_quality = 40;
+        low_q_config.image_max_final_bytes = Some(usize::MAX);
+        let low_q = process_image(&base64_data, "jpeg", &low_q_config, 1).unwrap();
+
+        assert!(high_q.was_reencoded);
+        assert!(low_q.was_reencoded);
+        assert!(low_q.final_bytes_len < high_q.final_bytes_len);
+    }
+
+    #[test]
+    fn test_process_image_max_final_bytes_shrinks_output() {
+        use image::{Rgba, RgbaImage};
+
+        let mut img = RgbaImage::new(300, 300);
+        for (i, p) in img.pixels_mut().enumerate() {
+            let v = ((i * 2654435761) % 256) as u8;
+            *p = Rgba([v, v.wrapping_add(50), v.wrapping_add(100), 255]);
+        }
+        let mut buf = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .unwrap();
+        let base64_data = BASE64.encode(&buf);
+
+        let mut config = CompressionConfig::default();
+        config.image_jpeg_quality = 95;
+        // 不会触发降质的预算：让基准结果也是按质量 95 重编码的 JPEG，而非原样透传的 PNG
+        config.image_max_final_bytes = Some(usize::MAX);
+        let unbudgeted = process_image(&base64_data, "jpeg", &config, 1).unwrap();
+
+        let target_bytes = unbudgeted.final_bytes_len / 2;
+        config.image_max_final_bytes = Some(target_bytes);
+        let budgeted = process_image(&base64_data, "jpeg", &config, 1).unwrap();
+
+        assert!(budgeted.was_reencoded);
+        assert!(budgeted.final_bytes_len < unbudgeted.final_bytes_len);
+        assert_eq!(budgeted.final_bytes_len, BASE64.decode(&budgeted.data).unwrap().len());
+    }
+
+    #[test]
+    fn test_process_tiff_pages_rejects_invalid_input() {
+        // 构造一个简单的单页 TIFF 原始字节不现实（无便捷编码器可用），
+        // 改为直接验证 `decode_tiff_page`/`process_tiff_pages` 对非法输入的
+        // 错误路径，确保尚未接入真实 TIFF 编码依赖前不会 panic
+        let config = CompressionConfig::default();
+        let base64_data = BASE64.encode(b"not a real tiff file");
+        let result = process_tiff_pages(&base64_data, &config, 1);
+        assert!(result.is_err());
+    }
 }
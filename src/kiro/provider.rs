@@ -0,0 +1,279 @@
+//! Kiro 上游调用的 provider 抽象
+//!
+//! `handle_stream_request`/`handle_non_stream_request`/`run_ws_event_loop`
+//! （见 [`crate::anthropic::handlers`]）持有的是 `Arc<dyn KiroProviderApi>`，
+//! 而不是具体的 `KiroProvider`，使解码循环/截断检测/断线重连逻辑可以在没有
+//! 真实上游的情况下用本文件的 [`MockProvider`] 做确定性测试。
+//!
+//! `KiroProvider` 背后是真实的多凭据故障转移逻辑，不在本次可见的代码树范围
+//! 内；它对 `KiroProviderApi` 的实现（把现有 `reqwest::Response` 适配成
+//! `ProviderByteStream`/`Bytes`）随具体类型一起定义，不在此文件中。
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use futures::Stream;
+
+/// 上游原始字节流；流式路径的 `EventStreamDecoder` 以此为输入驱动解码
+pub type ProviderByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// `KiroProvider` 背后真正的调用能力，抽出来是为了能在测试里换成
+/// [`MockProvider`]
+pub trait KiroProviderApi: Send + Sync {
+    /// 发起一次流式请求，返回原始 EventStream 字节流
+    fn call_api_stream<'a>(
+        &'a self,
+        request_body: &'a str,
+        user_id: Option<&'a str>,
+    ) -> Pin<Box<dyn Future<Output = Result<ProviderByteStream>> + Send + 'a>>;
+
+    /// 发起一次非流式请求，返回完整响应体
+    fn call_api<'a>(
+        &'a self,
+        request_body: &'a str,
+        user_id: Option<&'a str>,
+    ) -> Pin<Box<dyn Future<Output = Result<Bytes>> + Send + 'a>>;
+}
+
+/// 预先录制好的一次性故障注入：在累计输出达到 `at_byte` 字节后，
+/// 下一个流 item 返回 `Err` 而不是正常帧
+#[derive(Debug, Clone)]
+pub struct FailOnceAt {
+    pub at_byte: usize,
+    pub message: String,
+}
+
+/// 测试用的脚本化 `KiroProviderApi` 实现
+///
+/// - `frames` 按顺序作为 EventStream 字节块依次产出；
+/// - 配置了 `fail_once` 时，在累计字节数越过 `at_byte` 的那一帧之后追加一个
+///   `Err` item（模拟上游连接中途异常断开，而不是正常结束）；
+/// - `close_early` 为 true 时只产出前一半帧就直接结束流且不追加任何终止
+///   事件帧，模拟"没有终止事件的提前关闭"，用于触发
+///   [`crate::anthropic::handlers`] 里 `abnormal_end` 的 `None` 分支。
+///
+/// `fail_once` 只会触发一次：同一个 `MockProvider` 实例被 resume 逻辑
+/// 重新调用 `call_api_stream`（断线重连）时，第二次调用会正常放完剩余脚本，
+/// 这样才能测出"重试后成功"的路径而不是无限失败。
+#[derive(Debug, Default)]
+pub struct MockProvider {
+    frames: Vec<Bytes>,
+    fail_once: Option<FailOnceAt>,
+    close_early: bool,
+    non_stream_body: Bytes,
+    fail_once_consumed: Mutex<bool>,
+}
+
+impl MockProvider {
+    pub fn new(frames: Vec<Bytes>) -> Self {
+        Self {
+            frames,
+            ..Default::default()
+        }
+    }
+
+    /// 非流式响应体（`call_api` 返回的完整 body）
+    pub fn with_non_stream_body(mut self, body: impl Into<Bytes>) -> Self {
+        self.non_stream_body = body.into();
+        self
+    }
+
+    /// 在累计产出达到 `at_byte` 字节后，下一次 poll 返回一次性的 `Err`
+    pub fn with_fail_once(mut self, at_byte: usize, message: impl Into<String>) -> Self {
+        self.fail_once = Some(FailOnceAt {
+            at_byte,
+            message: message.into(),
+        });
+        self
+    }
+
+    /// 只产出前一半脚本帧就提前结束流，不带终止事件
+    pub fn with_close_early(mut self) -> Self {
+        self.close_early = true;
+        self
+    }
+
+    fn build_stream(&self) -> ProviderByteStream {
+        let frames: Vec<Bytes> = if self.close_early {
+            frames_half(&self.frames)
+        } else {
+            self.frames.clone()
+        };
+
+        // 一次性故障只在第一次调用时装填；断线重连后的第二次调用拿到的是
+        // `None`，脚本帧会正常放完，这样才能测出"重试后成功"的路径
+        let fail_once = {
+            let mut consumed = self
+                .fail_once_consumed
+                .lock()
+                .unwrap_or_else(|p| p.into_inner());
+            if *consumed {
+                None
+            } else {
+                *consumed = true;
+                self.fail_once.clone()
+            }
+        };
+
+        Box::pin(futures::stream::unfold(
+            (frames.into_iter(), 0usize, fail_once, false),
+            |(mut remaining, emitted_bytes, fail_once, terminated)| async move {
+                if terminated {
+                    return None;
+                }
+                // 已产出的字节数达到 at_byte（越过边界的那一帧已正常产出）后，
+                // 下一个 item 返回 Err
+                if let Some(fail) = fail_once.as_ref().filter(|fail| emitted_bytes >= fail.at_byte) {
+                    let message = fail.message.clone();
+                    // 触发后流即告终，模拟连接中途异常断开（调用方应视作
+                    // abnormal_end 并走重连/重试路径，而不是继续读剩余脚本帧）
+                    return Some((Err(anyhow::anyhow!(message)), (remaining, emitted_bytes, None, true)));
+                }
+                let next = remaining.next()?;
+                let emitted_bytes = emitted_bytes + next.len();
+                Some((Ok(next), (remaining, emitted_bytes, fail_once, false)))
+            },
+        ))
+    }
+}
+
+/// 取脚本帧的前一半（向下取整），用于模拟提前关闭
+fn frames_half(frames: &[Bytes]) -> Vec<Bytes> {
+    frames[..frames.len() / 2].to_vec()
+}
+
+impl KiroProviderApi for MockProvider {
+    fn call_api_stream<'a>(
+        &'a self,
+        _request_body: &'a str,
+        _user_id: Option<&'a str>,
+    ) -> Pin<Box<dyn Future<Output = Result<ProviderByteStream>> + Send + 'a>> {
+        Box::pin(async move { Ok(self.build_stream()) })
+    }
+
+    fn call_api<'a>(
+        &'a self,
+        _request_body: &'a str,
+        _user_id: Option<&'a str>,
+    ) -> Pin<Box<dyn Future<Output = Result<Bytes>> + Send + 'a>> {
+        Box::pin(async move {
+            if self.non_stream_body.is_empty() {
+                bail!("MockProvider: non_stream_body 未配置");
+            }
+            Ok(self.non_stream_body.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_mock_provider_replays_scripted_frames_in_order() {
+        let provider = MockProvider::new(vec![Bytes::from_static(b"frame-1"), Bytes::from_static(b"frame-2")]);
+        let mut stream = provider.call_api_stream("{}", None).await.unwrap();
+        let mut collected = Vec::new();
+        while let Some(item) = stream.next().await {
+            collected.push(item.unwrap());
+        }
+        assert_eq!(collected, vec![Bytes::from_static(b"frame-1"), Bytes::from_static(b"frame-2")]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_close_early_yields_fewer_frames_than_scripted() {
+        let provider = MockProvider::new(vec![
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"c"),
+            Bytes::from_static(b"d"),
+        ])
+        .with_close_early();
+        let mut stream = provider.call_api_stream("{}", None).await.unwrap();
+        let mut collected = Vec::new();
+        while let Some(item) = stream.next().await {
+            collected.push(item.unwrap());
+        }
+        assert_eq!(collected.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_non_stream_returns_configured_body() {
+        let provider = MockProvider::default().with_non_stream_body(Bytes::from_static(b"{\"ok\":true}"));
+        let body = provider.call_api("{}", Some("user-1")).await.unwrap();
+        assert_eq!(&body[..], b"{\"ok\":true}");
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_non_stream_without_body_errors() {
+        let provider = MockProvider::default();
+        assert!(provider.call_api("{}", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_fails_once_mid_stream_then_succeeds_on_reconnect() {
+        let provider = MockProvider::new(vec![
+            Bytes::from_static(b"frame-1"),
+            Bytes::from_static(b"frame-2"),
+            Bytes::from_static(b"frame-3"),
+        ])
+        .with_fail_once(10, "simulated connection reset");
+
+        // 第一次调用：在累计超过 10 字节（frame-1 + frame-2 = 14）处中断
+        let mut first = provider.call_api_stream("{}", None).await.unwrap();
+        let mut ok_frames = Vec::new();
+        let mut saw_error = false;
+        while let Some(item) = first.next().await {
+            match item {
+                Ok(b) => ok_frames.push(b),
+                Err(_) => {
+                    saw_error = true;
+                    break;
+                }
+            }
+        }
+        assert!(saw_error);
+        assert_eq!(ok_frames, vec![Bytes::from_static(b"frame-1"), Bytes::from_static(b"frame-2")]);
+
+        // 重连后的第二次调用：一次性故障已消耗，脚本帧正常放完
+        let mut second = provider.call_api_stream("{}", None).await.unwrap();
+        let mut collected = Vec::new();
+        while let Some(item) = second.next().await {
+            collected.push(item.unwrap());
+        }
+        assert_eq!(collected.len(), 3);
+    }
+
+    /// `handle_stream_request`/`handle_non_stream_request`/`run_ws_event_loop`
+    /// 持有的字段类型是 `Arc<dyn KiroProviderApi>`，不是具体的 `MockProvider`。
+    /// 这里显式通过 trait object 调用，验证该类型擦除路径本身是可用的
+    /// （而不仅仅是 `MockProvider` 的具体方法被测过）。
+    #[tokio::test]
+    async fn test_mock_provider_usable_as_trait_object() {
+        let provider: Arc<dyn KiroProviderApi> = Arc::new(MockProvider::new(vec![
+            Bytes::from_static(b"frame-1"),
+            Bytes::from_static(b"frame-2"),
+        ]));
+
+        let mut stream = provider.call_api_stream("{}", Some("user-1")).await.unwrap();
+        let mut collected = Vec::new();
+        while let Some(item) = stream.next().await {
+            collected.push(item.unwrap());
+        }
+        assert_eq!(collected, vec![Bytes::from_static(b"frame-1"), Bytes::from_static(b"frame-2")]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_trait_object_non_stream_call() {
+        let provider: Arc<dyn KiroProviderApi> =
+            Arc::new(MockProvider::default().with_non_stream_body(Bytes::from_static(b"{\"ok\":true}")));
+
+        let body = provider.call_api("{}", None).await.unwrap();
+        assert_eq!(&body[..], b"{\"ok\":true}");
+    }
+}
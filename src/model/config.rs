@@ -11,6 +11,17 @@ pub enum TlsBackend {
     NativeTls,
 }
 
+/// 压缩预算的计量单位
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Budget {
+    /// 按字符数计量（默认，兼容旧配置）
+    #[default]
+    Chars,
+    /// 按 BPE token 数计量，更贴近上游模型的真实上下文限制
+    Tokens,
+}
+
 /// KNA 应用配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -78,9 +89,16 @@ pub struct Config {
     pub proxy_password: Option<String>,
 
     /// Admin API 密钥（可选，启用 Admin API 功能）
+    ///
+    /// 为兼容旧配置保留：迁移路径上等价于一个拥有全部能力（`AdminCapability::all()`）的
+    /// `admin_keys` 条目。新部署建议直接使用 `admin_keys` 以获得按能力拆分的只读/操作密钥。
     #[serde(default)]
     pub admin_api_key: Option<String>,
 
+    /// 按能力分级的 Admin API 密钥列表
+    #[serde(default)]
+    pub admin_keys: Vec<AdminKeyConfig>,
+
     /// 单个凭据的目标请求速率（RPM，每分钟请求数）
     ///
     /// 用于凭据级节流/分流：当某个凭据短时间内请求过密时，优先将流量分配到其他可用凭据，
@@ -95,13 +113,56 @@ pub struct Config {
     #[serde(default = "default_load_balancing_mode")]
     pub load_balancing_mode: String,
 
+    /// 凭据令牌桶限流配置（可选，未配置时回退到 `credential_rpm` 换算的固定速率）
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+
+    /// 凭据调度权重（多因子加权评分，替代 `load_balancing_mode` 的二选一模式）
+    ///
+    /// 未配置时按 `load_balancing_mode` 派生一个等效预设（见 `SchedulerWeights::from_mode`）。
+    #[serde(default)]
+    pub scheduler_weights: Option<SchedulerWeights>,
+
+    /// 凭据状态变更的 Webhook 通知配置（可选）
+    #[serde(default)]
+    pub notifications: Option<NotificationConfig>,
+
     /// 输入压缩配置
     #[serde(default)]
     pub compression: CompressionConfig,
 
+    /// 按 user_id 的请求/token 预算（可选，未配置时不启用）
+    #[serde(default)]
+    pub user_quota: Option<UserQuotaConfig>,
+
+    /// 单次请求超时与全局并发限制
+    #[serde(default)]
+    pub request_limits: RequestLimitsConfig,
+
+    /// `/cc/v1/messages` 流式响应模式配置
+    #[serde(default)]
+    pub cc_streaming: CcStreamingConfig,
+
+    /// 结构化诊断数据导出配置（可选，未配置时不导出）
+    #[serde(default)]
+    pub diagnostics: Option<DiagnosticsConfig>,
+
+    /// 到 Kiro 上游的连接传输调优（HTTP/2、TCP keepalive、连接池等）
+    #[serde(default)]
+    pub upstream_connection: UpstreamConnectionConfig,
+
     /// 配置文件路径（运行时元数据，不写入 JSON）
     #[serde(skip)]
     config_path: Option<PathBuf>,
+
+    /// 本次加载中被环境变量/`.env` 覆盖过的顶层字段（JSON 字段名，camelCase），
+    /// 运行时元数据，不写入 JSON
+    ///
+    /// `save()` 据此在写回磁盘前把 `ENV_ONLY_SENSITIVE_FIELDS` 里命中的字段置空，
+    /// 避免把本应只存在于环境变量里的凭据被 Admin API 热更新之类的流程
+    /// 意外固化进磁盘上的配置文件。
+    #[serde(skip)]
+    env_overridden_fields: std::collections::HashSet<String>,
 }
 
 fn default_host() -> String {
@@ -141,6 +202,385 @@ fn default_load_balancing_mode() -> String {
     "priority".to_string()
 }
 
+/// 单个凭据的令牌桶限流配置
+///
+/// 与 `credential_rpm` 的"固定最小间隔"不同，令牌桶允许短时突发（最多 `burst` 个请求）
+/// 而长期仍收敛到 `rate`（每分钟令牌数），更贴近上游真实的速率限制策略。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitConfig {
+    /// 每分钟补充的令牌数（即目标 RPM）
+    pub rate: u32,
+    /// 令牌桶容量（允许的突发请求数）
+    pub burst: u32,
+}
+
+impl RateLimitConfig {
+    /// 由 `credential_rpm` 派生一个令牌桶配置：`rate` 取 `rpm`，`burst` 取 `rpm` 与 1
+    /// 的较大者，即长期平均速率与原有的固定间隔相同（每分钟 `rpm` 个请求），但允许
+    /// 最多 `burst` 个请求的短时突发。
+    pub fn from_credential_rpm(rpm: u32) -> Self {
+        Self {
+            rate: rpm,
+            burst: rpm.max(1),
+        }
+    }
+}
+
+/// 按 user_id 的请求/token 预算配置（可选）
+///
+/// 在真正派发到上游前，对同一个 `user_id`（与凭据亲和性使用的同一标识）施加
+/// 固定窗口内的请求数/token 数上限，提前拒绝而不是等到所有凭据配额都耗尽。
+/// token 数允许粗略估算（如序列化后字节数 / 4），预算只需要"足够接近"。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserQuotaConfig {
+    /// 预算窗口时长（秒）
+    pub window_secs: u64,
+    /// 窗口内最大请求数
+    pub max_requests: u32,
+    /// 窗口内最大预估 token 数
+    pub max_tokens: u64,
+}
+
+/// 单次请求的超时与全局并发限制
+///
+/// - `request_timeout_secs` 是整个请求（含流式响应全程）的墙钟超时：
+///   流式路径在超时时以独立的 `stop_reason` 收尾而不是直接断开连接，
+///   非流式路径则直接中止上游调用并返回超时错误。
+/// - `max_concurrent_requests` 是进程内正在处理的上游调用总数上限
+///   （通过一个全局 `tokio::sync::Semaphore` 实现），避免突发请求压垮凭据；
+///   `semaphore_acquire_timeout_secs` 内仍未拿到槽位的请求直接返回 429，
+///   而不是无限排队。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestLimitsConfig {
+    /// 单次请求的墙钟超时（秒），默认 300；0 表示不限制
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// 全局并发上游调用上限，默认 64
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    /// 等待并发槽位的宽限期（秒），超过仍未拿到槽位则拒绝，默认 5
+    #[serde(default = "default_semaphore_acquire_timeout_secs")]
+    pub semaphore_acquire_timeout_secs: u64,
+}
+
+impl Default for RequestLimitsConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout_secs: default_request_timeout_secs(),
+            max_concurrent_requests: default_max_concurrent_requests(),
+            semaphore_acquire_timeout_secs: default_semaphore_acquire_timeout_secs(),
+        }
+    }
+}
+
+fn default_request_timeout_secs() -> u64 {
+    300
+}
+
+fn default_max_concurrent_requests() -> usize {
+    64
+}
+
+fn default_semaphore_acquire_timeout_secs() -> u64 {
+    5
+}
+
+/// `/cc/v1/messages` 流式响应模式配置
+///
+/// `handle_stream_request_buffered` 默认缓冲整个上游流，等 `contextUsageEvent`
+/// 到达后用精确的 `input_tokens` 生成 `message_start`，代价是牺牲首字节延迟。
+/// 增量模式改为立即按估算值发出 `message_start`，随事件解码实时转发，
+/// 并把 `contextUsageEvent` 的精确值补记到收尾 `message_delta` 的 `usage` 里；
+/// 拿不到 `contextUsageEvent` 时直接回退为估算值。
+///
+/// `incremental_by_default` 设置部署级默认值，单次请求可通过
+/// `X-Stream-Mode: incremental` / `buffered` 请求头覆盖。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CcStreamingConfig {
+    /// 是否默认对 `/cc/v1/messages` 使用增量流式模式，默认 false（沿用缓冲模式）
+    #[serde(default)]
+    pub incremental_by_default: bool,
+    /// 缓冲模式下允许累积的上游响应字节数上限，默认 8388608（8 MiB）
+    ///
+    /// 超过此上限时放弃继续缓冲：把已缓冲内容（按估算 `input_tokens` 生成
+    /// `message_start`）立即下发，并将剩余响应降级为与增量模式一致的实时
+    /// 透传，避免超大响应在缓冲模式下无界占用内存。
+    #[serde(default = "default_max_buffered_response_bytes")]
+    pub max_buffered_response_bytes: usize,
+}
+
+impl Default for CcStreamingConfig {
+    fn default() -> Self {
+        Self {
+            incremental_by_default: false,
+            max_buffered_response_bytes: default_max_buffered_response_bytes(),
+        }
+    }
+}
+
+fn default_max_buffered_response_bytes() -> usize {
+    8 * 1024 * 1024
+}
+
+/// 到 Kiro 上游的连接传输调优
+///
+/// `KiroProvider` 内部持有的上游 HTTP 客户端此前按 `reqwest` 默认参数构建，
+/// 对单个长连接足够，但大量并发长时间 SSE 流下，握手开销与连接池行为开始
+/// 影响尾延迟。这里暴露 HTTP/2、TCP keepalive、连接池闲置超时、TCP Fast
+/// Open 等传输层参数，使运维可以按负载特征调整，而不必把这些参数硬编码在
+/// 客户端构造代码里。
+///
+/// 这些参数只在进程启动时构建上游客户端时读取一次，因此与 `tlsBackend` 一样
+/// 被列入 [`Config::restart_required_fields`]，热更新只会记录为待重启。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamConnectionConfig {
+    /// 是否对上游优先按 HTTP/2 协商（多路复用单个连接承载多个并发流），默认 false
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    /// TCP keepalive 探测间隔（秒），默认 30；0 表示不启用
+    #[serde(default = "default_tcp_keepalive_secs")]
+    pub tcp_keepalive_secs: u64,
+    /// 连接池内空闲连接的最大保留时长（秒），默认 90；0 表示不保留空闲连接
+    #[serde(default = "default_pool_idle_timeout_secs")]
+    pub pool_idle_timeout_secs: u64,
+    /// 连接池中每个 host 允许保留的最大空闲连接数，默认 32
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    /// 是否启用 TCP Fast Open（需操作系统支持，不支持的平台由底层 socket 层静默忽略），默认 false
+    #[serde(default)]
+    pub tcp_fast_open: bool,
+}
+
+impl Default for UpstreamConnectionConfig {
+    fn default() -> Self {
+        Self {
+            http2_prior_knowledge: false,
+            tcp_keepalive_secs: default_tcp_keepalive_secs(),
+            pool_idle_timeout_secs: default_pool_idle_timeout_secs(),
+            pool_max_idle_per_host: default_pool_max_idle_per_host(),
+            tcp_fast_open: false,
+        }
+    }
+}
+
+fn default_tcp_keepalive_secs() -> u64 {
+    30
+}
+
+fn default_pool_idle_timeout_secs() -> u64 {
+    90
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    32
+}
+
+/// 结构化诊断数据导出配置（可选，未配置时不导出）
+///
+/// 请求路径产生的结构化 `tracing` 事件（压缩前后字节数、迭代次数、拒绝原因等）
+/// 默认只写本地日志；配置本节后，同样的字段会额外异步批量投递到 `endpoint`
+/// （换行分隔 JSON，ES Bulk 友好），供运维集中观测压缩效果与 400 拒绝原因。
+/// 投递经由一个有界内部通道异步完成，请求路径不会因投递阻塞或失败而受影响。
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticsConfig {
+    /// 批量接收端点 URL（ES Bulk 风格的 NDJSON 接收端点，或兼容的日志/指标网关）
+    pub endpoint: String,
+    /// 认证请求头名（可选，如 "Authorization"）
+    #[serde(default)]
+    pub auth_header_name: Option<String>,
+    /// 认证请求头值（可选）
+    #[serde(default)]
+    pub auth_header_value: Option<String>,
+    /// 刷新间隔（秒），凑不满 `batch_size` 时到点也会强制刷新，默认 5
+    #[serde(default = "default_diagnostics_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+    /// 单批次最大记录数，默认 200
+    #[serde(default = "default_diagnostics_batch_size")]
+    pub batch_size: usize,
+    /// 内部 mpsc 通道容量，超过时新记录被直接丢弃（不阻塞请求路径），默认 4096
+    #[serde(default = "default_diagnostics_channel_capacity")]
+    pub channel_capacity: usize,
+}
+
+fn default_diagnostics_flush_interval_secs() -> u64 {
+    5
+}
+
+fn default_diagnostics_batch_size() -> usize {
+    200
+}
+
+fn default_diagnostics_channel_capacity() -> usize {
+    4096
+}
+
+/// Admin API 能力
+///
+/// 每个 Admin 密钥只被授予其中一部分能力，请求处理函数在分派到
+/// `AdminService` 前会校验当前密钥是否拥有所需能力，未授予则返回 403。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminCapability {
+    /// 查看凭据状态列表
+    ReadStatus,
+    /// 启用/禁用凭据
+    ToggleCredential,
+    /// 调整凭据优先级
+    SetPriority,
+    /// 查看凭据余额
+    ReadBalance,
+    /// 查看/热更新运行时配置
+    ReloadConfig,
+}
+
+impl AdminCapability {
+    /// 全部能力（用于迁移期的单一 `admin_api_key`）
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::ReadStatus,
+            Self::ToggleCredential,
+            Self::SetPriority,
+            Self::ReadBalance,
+            Self::ReloadConfig,
+        ]
+    }
+}
+
+/// 单个 Admin API 密钥及其被授予的能力
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminKeyConfig {
+    pub key: String,
+    pub capabilities: Vec<AdminCapability>,
+}
+
+impl Config {
+    /// 解析某个 Admin API 密钥被授予的能力集合
+    ///
+    /// 优先匹配 `admin_keys` 中的条目；为兼容旧配置，若密钥等于 `admin_api_key`
+    /// 则视为拥有全部能力。密钥不匹配任何条目时返回 `None`（即未授权）。
+    pub fn resolve_admin_capabilities(&self, presented_key: &str) -> Option<Vec<AdminCapability>> {
+        if let Some(entry) = self.admin_keys.iter().find(|k| k.key == presented_key) {
+            return Some(entry.capabilities.clone());
+        }
+        if self.admin_api_key.as_deref() == Some(presented_key) {
+            return Some(AdminCapability::all());
+        }
+        None
+    }
+}
+
+/// 凭据状态变更 Webhook 通知配置
+///
+/// `AdminService`/`MultiTokenManager` 在凭据被自动禁用/重新启用、token 刷新失败、
+/// 或余额低于 `balance_alert_threshold_percent` 时，向 `url` POST 一个签名事件。
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationConfig {
+    /// 接收事件的 Webhook 地址
+    pub url: String,
+    /// 附加的认证请求头名称（如 "Authorization"），可选
+    #[serde(default)]
+    pub auth_header_name: Option<String>,
+    /// 附加的认证请求头值，可选
+    #[serde(default)]
+    pub auth_header_value: Option<String>,
+    /// 用于对请求体做 HMAC-SHA256 签名的密钥（便于接收方校验真实性）
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+    /// 余额低于该百分比时触发低余额告警事件，默认 10%
+    #[serde(default = "default_balance_alert_threshold")]
+    pub balance_alert_threshold_percent: f64,
+}
+
+fn default_balance_alert_threshold() -> f64 {
+    10.0
+}
+
+/// 凭据调度权重：多因子加权评分的权重配置
+///
+/// 评分 = `balance_weight * 剩余额度占比`
+///      + `failure_weight * (1 / (1 + failure_count))`
+///      + `rpm_headroom_weight * (可用令牌数 / 桶容量)`
+///      + `expiry_weight * 距过期时间占比`
+///
+/// 分值越高越优先被选中。四个权重均为非负浮点数，总和不要求恰为 1（内部按总和归一化）。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SchedulerWeights {
+    /// 剩余额度占比权重
+    #[serde(default = "default_weight_zero")]
+    pub balance_weight: f64,
+    /// 失败次数倒数权重（失败越少越优先）
+    #[serde(default = "default_weight_one")]
+    pub failure_weight: f64,
+    /// RPM 余量（令牌桶剩余容量占比）权重
+    #[serde(default = "default_weight_zero")]
+    pub rpm_headroom_weight: f64,
+    /// 临近过期优先权重（用于"优先消耗快过期凭据"的场景）
+    #[serde(default = "default_weight_zero")]
+    pub expiry_weight: f64,
+}
+
+fn default_weight_zero() -> f64 {
+    0.0
+}
+
+fn default_weight_one() -> f64 {
+    1.0
+}
+
+impl SchedulerWeights {
+    /// 由旧的 `load_balancing_mode` 字符串派生一个等效权重预设，
+    /// 保证已有配置（"priority" / "balanced"）在未显式配置权重时行为不变。
+    pub fn from_mode(mode: &str) -> Self {
+        match mode {
+            "balanced" => Self {
+                balance_weight: 0.25,
+                failure_weight: 0.25,
+                rpm_headroom_weight: 0.25,
+                expiry_weight: 0.25,
+            },
+            // "priority" 及其它未知值：仅按失败次数区分（退化为原 priority 行为）
+            _ => Self {
+                balance_weight: 0.0,
+                failure_weight: 1.0,
+                rpm_headroom_weight: 0.0,
+                expiry_weight: 0.0,
+            },
+        }
+    }
+
+    fn total(&self) -> f64 {
+        self.balance_weight + self.failure_weight + self.rpm_headroom_weight + self.expiry_weight
+    }
+
+    /// 对一组归一化后的因子值（均为 `[0, 1]`）计算加权评分
+    pub fn score(
+        &self,
+        balance_fraction: f64,
+        inverse_failure: f64,
+        rpm_headroom: f64,
+        expiry_fraction: f64,
+    ) -> f64 {
+        let total = self.total();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        (self.balance_weight * balance_fraction
+            + self.failure_weight * inverse_failure
+            + self.rpm_headroom_weight * rpm_headroom
+            + self.expiry_weight * expiry_fraction)
+            / total
+    }
+}
+
 fn default_true() -> bool {
     true
 }
@@ -149,6 +589,27 @@ fn default_thinking_strategy() -> String {
     "discard".to_string()
 }
 
+fn default_history_strategy() -> String {
+    "oldest".to_string()
+}
+
+fn default_redaction_patterns() -> Vec<String> {
+    vec![
+        // Anthropic 风格 API key
+        r"sk-ant-[A-Za-z0-9_-]{20,}".to_string(),
+        // Bearer token
+        r"Bearer\s+[A-Za-z0-9._-]{10,}".to_string(),
+        // PEM 私钥块
+        r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----".to_string(),
+        // 邮箱地址
+        r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}".to_string(),
+    ]
+}
+
+fn default_redaction_marker() -> String {
+    "[redacted]".to_string()
+}
+
 fn default_8000() -> usize {
     8000
 }
@@ -177,6 +638,18 @@ fn default_400k() -> usize {
     400_000
 }
 
+fn default_2000() -> usize {
+    2000
+}
+
+fn default_1500() -> usize {
+    1500
+}
+
+fn default_100k() -> usize {
+    100_000
+}
+
 fn default_image_max_long_edge() -> u32 {
     1568
 }
@@ -193,10 +666,49 @@ fn default_image_multi_threshold() -> usize {
     20
 }
 
+fn default_image_max_pixels() -> u32 {
+    600_000
+}
+
+fn default_image_min_quality() -> u8 {
+    40
+}
+
 fn default_max_request_body_bytes() -> usize {
     400_000
 }
 
+fn default_image_decode_max_pixels() -> u64 {
+    100_000_000 // 约 10000×10000，远超正常图片但能拦住声明巨幅画布的炸弹图片
+}
+
+fn default_tiff_max_pages() -> usize {
+    20
+}
+
+fn default_image_jpeg_quality() -> u8 {
+    85 // 与 image_min_quality 的质量阶梯起点一致
+}
+
+fn default_image_decode_max_bytes() -> usize {
+    64 * 1024 * 1024 // 64 MiB，解码阶段单张图片的内存分配上限
+}
+
+/// 缩放重采样滤波器，对应 `image::imageops::FilterType`
+///
+/// 由快到慢、由模糊到清晰：`Nearest` < `Triangle` < `CatmullRom` < `Lanczos3`。
+/// 默认 `Lanczos3`（与此前硬编码行为一致），请求体大小吃紧时可降级为更快的
+/// 滤波器换取更小的编码体积。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    #[default]
+    Lanczos3,
+}
+
 /// 输入压缩配置
 ///
 /// 控制请求体在协议转换后、发送到上游前的多层压缩策略。
@@ -234,6 +746,23 @@ pub struct CompressionConfig {
     /// 历史最大字符数，默认 400000（0=不限）
     #[serde(default = "default_400k")]
     pub max_history_chars: usize,
+    /// 历史截断策略: "oldest"（从最旧开始移除）| "relevance"（按与当前消息的相关性移除）
+    /// | "summarize"（移除前交给 `Summarizer` 生成摘要插回历史，需调用
+    /// `compress_with_summarizer` 提供 summarizer，否则退化为 "oldest"）
+    #[serde(default = "default_history_strategy")]
+    pub history_strategy: String,
+    /// 压缩预算计量单位：按字符数（默认）或按 BPE token 数
+    #[serde(default)]
+    pub budget: Budget,
+    /// tool_result 截断阈值（token 数），仅 `budget = Tokens` 时生效，默认 2000
+    #[serde(default = "default_2000")]
+    pub tool_result_max_tokens: usize,
+    /// tool_use input 截断阈值（token 数），仅 `budget = Tokens` 时生效，默认 1500
+    #[serde(default = "default_1500")]
+    pub tool_use_input_max_tokens: usize,
+    /// 历史最大 token 数，仅 `budget = Tokens` 时生效，默认 100000（0=不限）
+    #[serde(default = "default_100k")]
+    pub max_history_tokens: usize,
     /// 图片长边最大像素，默认 1568（Anthropic 推荐值，超过会缩放）
     #[serde(default = "default_image_max_long_edge")]
     pub image_max_long_edge: u32,
@@ -249,6 +778,90 @@ pub struct CompressionConfig {
     /// 请求体最大字节数，超过则直接拒绝（0 = 不限制）
     #[serde(default = "default_max_request_body_bytes")]
     pub max_request_body_bytes: usize,
+    /// 敏感信息脱敏正则表达式列表（API key、Bearer token、私钥块、邮箱等）
+    ///
+    /// 在压缩管道最开始执行（空白压缩之前），使脱敏标记自身也会被正常的空白
+    /// 压缩处理。默认内置一组常见模式，可通过配置文件覆盖或清空。
+    #[serde(default = "default_redaction_patterns")]
+    pub redaction_patterns: Vec<String>,
+    /// 即使命中 `redaction_patterns` 也不脱敏的字面值白名单（例如已知的占位符）
+    #[serde(default)]
+    pub redaction_exclude_literals: Vec<String>,
+    /// 脱敏替换标记，默认 "[redacted]"
+    #[serde(default = "default_redaction_marker")]
+    pub redaction_marker: String,
+    /// 固定（pin）保护的消息 id 列表，按 `tool_use_id` 匹配
+    ///
+    /// 命中的轮次（该轮次内任意 tool_use/tool_result 的 id 在此列表中）不会被
+    /// 任何压缩 pass 截断或在历史截断中被移除，用于保证任务说明、必须持续
+    /// 引用的文件等关键上下文始终存活。
+    #[serde(default)]
+    pub pinned_ids: Vec<String>,
+    /// 历史截断触发"最后手段"层（自适应压缩仍超预算）时，是否将被移除的
+    /// 轮次合成为一条摘要轮次插回历史边界，而非直接丢弃，默认 false
+    ///
+    /// 摘要由启发式规则生成（各轮次首尾句、调用的工具名、提及的文件路径），
+    /// 无需额外网络调用；也可通过 `compress_with_summarizer` 注入的
+    /// `Summarizer` 走上游摘要路径。自适应压缩层插回的摘要轮次按该层当前的
+    /// 消息内容截断阈值（`message_content_max_chars`）截断，避免摘要本身重新引入
+    /// 体积膨胀；`compress_with_summarizer` 插回的摘要不做截断。
+    #[serde(default)]
+    pub history_summarize: bool,
+    /// 自适应压缩图片收缩层的紧急像素预算，默认 600000
+    ///
+    /// 仅在 `adaptive_shrink_request_body` 按字节数硬限制降级、且图片字节占比
+    /// 较高时生效，独立于 `image_max_pixels_single`/`image_max_pixels_multi`
+    /// （那两个用于首次转换时的常规缩放）。
+    #[serde(default = "default_image_max_pixels")]
+    pub image_max_pixels: u32,
+    /// 自适应压缩图片收缩层的 JPEG 质量下限，默认 40
+    ///
+    /// 质量阶梯从 85 开始按 85→70→55→... 递减，直到命中目标字节数或降到此下限。
+    #[serde(default = "default_image_min_quality")]
+    pub image_min_quality: u8,
+    /// 质量阶梯降到下限后仍超预算时，是否整张剔除最旧的历史图片（保留
+    /// `current_message` 中的图片），默认 true
+    #[serde(default = "default_true")]
+    pub image_evict_history: bool,
+    /// 解码阶段允许的最大总像素数，默认 100_000_000（约 10000×10000）
+    ///
+    /// 在 `ImageReader::limits` 里按此值换算出等效的单边宽高上限并传给
+    /// `image` crate，防止攻击者上传一张声明巨幅画布（如 100000×100000）
+    /// 但体积很小的图片，在 `into_dimensions()`/完整解码阶段触发数 GB 级
+    /// 内存分配。与 `image_max_pixels_single`/`image_max_pixels_multi`（决定
+    /// 缩放目标尺寸）是两回事：这里只负责"允不允许解码"，不影响缩放结果。
+    #[serde(default = "default_image_decode_max_pixels")]
+    pub image_decode_max_pixels: u64,
+    /// 解码阶段允许的最大内存分配字节数，默认 64 MiB
+    #[serde(default = "default_image_decode_max_bytes")]
+    pub image_decode_max_bytes: usize,
+    /// GIF 抽帧的输出模式：`false`（默认）按原逻辑拆成多张独立 JPEG 静态图；
+    /// `true` 改为把采样帧重新组装成一张共享调色板、降帧率的动图 GIF
+    /// （见 [`crate::image::GifOutput`]），保留动态语义且体积通常远小于
+    /// 多张 JPEG 之和。
+    #[serde(default)]
+    pub gif_animated_output: bool,
+    /// 多页 TIFF 抽取页数上限，默认 20（与 `GIF_MAX_OUTPUT_FRAMES` 取值一致）
+    ///
+    /// 超过此页数的 TIFF 只取前 N 页，避免扫描件场景下的超长多页文档
+    /// 撑爆请求体。
+    #[serde(default = "default_tiff_max_pages")]
+    pub tiff_max_pages: usize,
+    /// 缩放使用的重采样滤波器，默认 `Lanczos3`（与此前硬编码行为一致）
+    #[serde(default)]
+    pub image_resize_filter: ImageResizeFilter,
+    /// 重编码为 JPEG 时使用的质量（1~100），默认 85
+    #[serde(default = "default_image_jpeg_quality")]
+    pub image_jpeg_quality: u8,
+    /// 单张图片编码后字节数预算（不含 base64 膨胀），默认不启用（`None`）
+    ///
+    /// 设置后，`process_image`/`process_image_to_format`/`process_gif_frames`
+    /// 里输出为 JPEG 的路径在初次按 `image_jpeg_quality` 编码超出此预算时，
+    /// 于 [40, `image_jpeg_quality`] 区间二分降质重编码，直到命中预算或降到
+    /// 质量下限 40，返回命中预算的最高质量结果；区间内都未命中时返回质量 40 的
+    /// 编码结果。非 JPEG 输出不受影响。
+    #[serde(default)]
+    pub image_max_final_bytes: Option<usize>,
 }
 
 impl Default for CompressionConfig {
@@ -264,11 +877,31 @@ impl Default for CompressionConfig {
             tool_description_max_chars: default_4000(),
             max_history_turns: default_80_turns(),
             max_history_chars: default_400k(),
+            history_strategy: default_history_strategy(),
+            budget: Budget::default(),
+            tool_result_max_tokens: default_2000(),
+            tool_use_input_max_tokens: default_1500(),
+            max_history_tokens: default_100k(),
             image_max_long_edge: default_image_max_long_edge(),
             image_max_pixels_single: default_image_max_pixels_single(),
             image_max_pixels_multi: default_image_max_pixels_multi(),
             image_multi_threshold: default_image_multi_threshold(),
             max_request_body_bytes: default_max_request_body_bytes(),
+            redaction_patterns: default_redaction_patterns(),
+            redaction_exclude_literals: Vec::new(),
+            redaction_marker: default_redaction_marker(),
+            pinned_ids: Vec::new(),
+            history_summarize: false,
+            image_max_pixels: default_image_max_pixels(),
+            image_min_quality: default_image_min_quality(),
+            image_evict_history: true,
+            image_decode_max_pixels: default_image_decode_max_pixels(),
+            image_decode_max_bytes: default_image_decode_max_bytes(),
+            gif_animated_output: false,
+            tiff_max_pages: default_tiff_max_pages(),
+            image_resize_filter: ImageResizeFilter::default(),
+            image_jpeg_quality: default_image_jpeg_quality(),
+            image_max_final_bytes: None,
         }
     }
 }
@@ -294,14 +927,115 @@ impl Default for Config {
             proxy_username: None,
             proxy_password: None,
             admin_api_key: None,
+            admin_keys: Vec::new(),
             credential_rpm: None,
             load_balancing_mode: default_load_balancing_mode(),
+            rate_limit: None,
+            scheduler_weights: None,
+            notifications: None,
             compression: CompressionConfig::default(),
+            user_quota: None,
+            request_limits: RequestLimitsConfig::default(),
+            cc_streaming: CcStreamingConfig::default(),
+            diagnostics: None,
+            upstream_connection: UpstreamConnectionConfig::default(),
             config_path: None,
+            env_overridden_fields: std::collections::HashSet::new(),
+        }
+    }
+}
+
+/// 将 camelCase 字段名转换为 SCREAMING_SNAKE_CASE（用于生成环境变量名）
+fn camel_to_screaming_snake(key: &str) -> String {
+    let mut out = String::new();
+    for (i, ch) in key.chars().enumerate() {
+        if ch.is_uppercase() && i > 0 {
+            out.push('_');
         }
+        out.extend(ch.to_uppercase());
     }
+    out
 }
 
+/// 递归收集 JSON 值中所有叶子字段的 `(环境变量名, JSON 路径)`
+fn collect_env_paths(value: &serde_json::Value, prefix: Vec<String>, out: &mut Vec<(String, Vec<String>)>) {
+    if let serde_json::Value::Object(map) = value {
+        for (key, v) in map {
+            let mut path = prefix.clone();
+            path.push(key.clone());
+            if v.is_object() {
+                collect_env_paths(v, path, out);
+            } else {
+                let env_suffix = path
+                    .iter()
+                    .map(|s| camel_to_screaming_snake(s))
+                    .collect::<Vec<_>>()
+                    .join("__");
+                out.push((format!("KNA_{}", env_suffix), path));
+            }
+        }
+    }
+}
+
+/// 按 JSON 路径设置嵌套值，沿途缺失的对象节点会被自动创建
+fn set_json_path(value: &mut serde_json::Value, path: &[String], new_value: serde_json::Value) {
+    let Some((head, rest)) = path.split_first() else {
+        return;
+    };
+    if !value.is_object() {
+        *value = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let map = value.as_object_mut().expect("已确保是 Object");
+
+    if rest.is_empty() {
+        map.insert(head.clone(), new_value);
+        return;
+    }
+
+    let entry = map
+        .entry(head.clone())
+        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+    set_json_path(entry, rest, new_value);
+}
+
+/// 运行时热更新结果
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigUpdateResult {
+    /// 已立即生效并持久化的字段
+    pub applied: Vec<String>,
+    /// 需要重启进程才能生效的字段（本次未修改）
+    pub pending_restart: Vec<String>,
+}
+
+/// 启动阶段提前完成的端口保留
+///
+/// 持有已绑定的 `TcpListener`，以及（在 `port: 0` 模式下）由操作系统实际分配
+/// 的端口号，供 Admin 状态接口等回报给运维。
+pub struct PortReservation {
+    pub listener: std::net::TcpListener,
+    port: u16,
+}
+
+impl PortReservation {
+    /// 实际生效的监听端口（`port: 0` 模式下为操作系统分配的端口）
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// 只应存在于环境变量/`.env`、不应被写回磁盘配置文件的敏感字段（JSON 字段名，
+/// camelCase）。`save()` 遇到本次运行里这些字段由 `apply_env_overrides` 覆盖过时，
+/// 会将其在写回前置空。
+const ENV_ONLY_SENSITIVE_FIELDS: &[&str] = &[
+    "adminApiKey",
+    "apiKey",
+    "countTokensApiKey",
+    "proxyUrl",
+    "proxyUsername",
+    "proxyPassword",
+];
+
 impl Config {
     /// 获取默认配置文件路径
     pub fn default_config_path() -> &'static str {
@@ -322,36 +1056,234 @@ impl Config {
         self.api_region.as_deref().unwrap_or(&self.region)
     }
 
+    /// 获取有效的令牌桶限流配置
+    ///
+    /// 优先使用 `rate_limit`；未配置时，若 `credential_rpm > 0` 则由其换算派生，
+    /// 否则返回 `None` 表示不启用限流（凭据始终可用）。
+    pub fn effective_rate_limit(&self) -> Option<RateLimitConfig> {
+        self.rate_limit.or_else(|| {
+            self.credential_rpm
+                .filter(|&rpm| rpm > 0)
+                .map(RateLimitConfig::from_credential_rpm)
+        })
+    }
+
+    /// 获取有效的调度权重
+    ///
+    /// 优先使用 `scheduler_weights`；未配置时由 `load_balancing_mode` 派生等效预设，
+    /// 使旧配置在不修改的情况下保持原有调度行为。
+    pub fn effective_scheduler_weights(&self) -> SchedulerWeights {
+        self.scheduler_weights
+            .unwrap_or_else(|| SchedulerWeights::from_mode(&self.load_balancing_mode))
+    }
+
     /// 从文件加载配置
+    ///
+    /// 加载顺序（优先级从低到高）：内置默认值 < JSON 文件 < `.env` 文件 < 进程环境变量。
+    /// 这使得容器化部署可以把 `admin_api_key`/`api_key`/代理凭据等敏感字段
+    /// 完全放在环境变量或 secret manager 中，而不必写入提交到仓库的 JSON 文件。
     pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::load_dotenv_if_present();
+
         let path = path.as_ref();
-        if !path.exists() {
+        let mut config = if !path.exists() {
             // 配置文件不存在，返回默认配置
-            return Ok(Self {
+            Self {
                 config_path: Some(path.to_path_buf()),
                 ..Default::default()
-            });
-        }
+            }
+        } else {
+            let content = fs::read_to_string(path)?;
+            let mut config: Config = serde_json::from_str(&content)?;
+            config.config_path = Some(path.to_path_buf());
+            config
+        };
 
-        let content = fs::read_to_string(path)?;
-        let mut config: Config = serde_json::from_str(&content)?;
-        config.config_path = Some(path.to_path_buf());
+        config
+            .apply_env_overrides()
+            .context("应用环境变量配置覆盖失败")?;
         Ok(config)
     }
 
+    /// 加载当前目录下的 `.env` 文件（如果存在），写入进程环境变量
+    ///
+    /// 已存在的环境变量优先级更高，不会被 `.env` 覆盖。仅支持
+    /// `KEY=VALUE`（可选引号）的简单格式，忽略空行和 `#` 开头的注释行。
+    fn load_dotenv_if_present() {
+        let Ok(content) = fs::read_to_string(".env") else {
+            return;
+        };
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let key = key.trim();
+            let value = value.trim().trim_matches('"').trim_matches('\'');
+            if key.is_empty() || std::env::var(key).is_ok() {
+                continue;
+            }
+            // SAFETY: 仅在启动阶段、单线程加载配置时设置进程环境变量
+            unsafe {
+                std::env::set_var(key, value);
+            }
+        }
+    }
+
+    /// 用环境变量覆盖已加载的配置字段
+    ///
+    /// 覆盖映射是从当前配置的 JSON 表示自动生成的（而非手工维护的 match 分支）：
+    /// 每个叶子字段的 camelCase 路径被转换为 `KNA_<SCREAMING_SNAKE>` 形式，
+    /// 嵌套字段（如 `compression.toolResultMaxChars`）以 `__` 分隔层级，
+    /// 即 `KNA_COMPRESSION__TOOL_RESULT_MAX_CHARS`。环境变量值优先按 JSON 解析
+    /// （支持数字/布尔/字符串），解析失败则作为原始字符串使用。
+    fn apply_env_overrides(&mut self) -> anyhow::Result<()> {
+        let mut value = serde_json::to_value(&*self).context("序列化配置失败")?;
+
+        let mut paths = Vec::new();
+        collect_env_paths(&value, Vec::new(), &mut paths);
+
+        let mut changed = false;
+        let mut overridden_fields = self.env_overridden_fields.clone();
+        for (env_name, path) in paths {
+            if let Ok(raw) = std::env::var(&env_name) {
+                let parsed = serde_json::from_str::<serde_json::Value>(&raw)
+                    .unwrap_or(serde_json::Value::String(raw));
+                set_json_path(&mut value, &path, parsed);
+                if let Some(top_level) = path.first() {
+                    overridden_fields.insert(top_level.clone());
+                }
+                changed = true;
+            }
+        }
+
+        if changed {
+            let config_path = self.config_path.clone();
+            let mut updated: Config =
+                serde_json::from_value(value).context("环境变量覆盖后的配置无法解析")?;
+            updated.config_path = config_path;
+            updated.env_overridden_fields = overridden_fields;
+            *self = updated;
+        }
+
+        Ok(())
+    }
+
     /// 获取配置文件路径（如果有）
     pub fn config_path(&self) -> Option<&Path> {
         self.config_path.as_deref()
     }
 
+    /// 需要重启进程才能生效的字段（JSON 字段名，camelCase）
+    ///
+    /// 这些字段会影响监听 socket、TLS 后端等已在启动阶段固化的资源，
+    /// 热更新时只会记录为 "pending restart"，不会改写内存中的值。
+    pub fn restart_required_fields() -> &'static [&'static str] {
+        &["host", "port", "tlsBackend", "upstreamConnection"]
+    }
+
+    /// 在启动完成其余运行时组件（Token 管理器、watcher、上游客户端等）之前，
+    /// 提前绑定并保留监听端口
+    ///
+    /// 提前 fail-fast：端口被占用或 host 非法会在此处立即报错，避免"初始化完
+    /// 大半运行时之后才发现监听失败"的半初始化进程。`port` 为 `0` 时由操作系统
+    /// 分配一个空闲端口，实际分配到的端口可通过返回值的 `port()` 获取，便于
+    /// 通过 Admin 状态接口回报给运维。
+    ///
+    /// 返回的 `std::net::TcpListener` 应被调用方保留（例如转换为 HTTP 服务器的
+    /// 底层监听句柄），以保证本次保留的端口不会被其他进程抢占。
+    pub fn reserve_listen_port(&self) -> anyhow::Result<PortReservation> {
+        let addr = format!("{}:{}", self.host, self.port);
+        let listener = std::net::TcpListener::bind(&addr)
+            .with_context(|| format!("绑定监听地址 {addr} 失败，端口可能已被占用"))?;
+        let actual_addr = listener
+            .local_addr()
+            .with_context(|| format!("无法获取监听地址 {addr} 的实际端口"))?;
+
+        Ok(PortReservation {
+            listener,
+            port: actual_addr.port(),
+        })
+    }
+
+    /// 应用一次运行时热更新
+    ///
+    /// `patch` 是一个部分 JSON 对象（与 `Config` 字段同名，camelCase）。
+    /// 对于 `restart_required_fields` 中列出的字段，仅记录到返回值的
+    /// `pending_restart` 中，不修改当前运行中的配置；其余字段立即生效。
+    ///
+    /// 成功应用的字段会写回磁盘（通过 `save`），保证下次启动沿用新配置。
+    pub fn apply_runtime_update(&mut self, patch: &serde_json::Value) -> anyhow::Result<ConfigUpdateResult> {
+        let Some(patch_obj) = patch.as_object() else {
+            anyhow::bail!("配置更新必须是一个 JSON 对象");
+        };
+
+        let mut current = serde_json::to_value(&*self).context("序列化当前配置失败")?;
+        let current_obj = current
+            .as_object_mut()
+            .ok_or_else(|| anyhow::anyhow!("当前配置序列化结果不是对象"))?;
+
+        let restart_fields = Self::restart_required_fields();
+        let mut applied = Vec::new();
+        let mut pending_restart = Vec::new();
+        // 本次更新若显式改写了某个 env-only 敏感字段，说明调用方确实想把它落到
+        // 磁盘上的新值，不再是"应保留在环境变量里"的旧值，取消其覆盖标记
+        let mut env_overridden_fields = self.env_overridden_fields.clone();
+
+        for (key, value) in patch_obj {
+            if restart_fields.contains(&key.as_str()) {
+                pending_restart.push(key.clone());
+                continue;
+            }
+            if ENV_ONLY_SENSITIVE_FIELDS.contains(&key.as_str()) {
+                env_overridden_fields.remove(key.as_str());
+            }
+            current_obj.insert(key.clone(), value.clone());
+            applied.push(key.clone());
+        }
+
+        if !applied.is_empty() {
+            let config_path = self.config_path.clone();
+            let mut updated: Config =
+                serde_json::from_value(current).context("合并后的配置无法解析，已拒绝本次更新")?;
+            updated.config_path = config_path;
+            updated.env_overridden_fields = env_overridden_fields;
+            *self = updated;
+            self.save().context("热更新已应用但写回配置文件失败")?;
+        }
+
+        Ok(ConfigUpdateResult {
+            applied,
+            pending_restart,
+        })
+    }
+
     /// 将当前配置写回原始配置文件
+    ///
+    /// 写回前会把 `ENV_ONLY_SENSITIVE_FIELDS` 中、本次运行由环境变量/`.env`
+    /// 覆盖过的字段置空（见 `env_overridden_fields`/`apply_env_overrides`），
+    /// 防止 Admin API 热更新（`apply_runtime_update`）之类会整份重新序列化
+    /// 配置的流程，把本应只存在于环境变量里的凭据固化进磁盘上的 JSON 文件。
     pub fn save(&self) -> anyhow::Result<()> {
         let path = self
             .config_path
             .as_deref()
             .ok_or_else(|| anyhow::anyhow!("配置文件路径未知，无法保存配置"))?;
 
-        let content = serde_json::to_string_pretty(self).context("序列化配置失败")?;
+        let mut value = serde_json::to_value(self).context("序列化配置失败")?;
+        if let Some(obj) = value.as_object_mut() {
+            for field in ENV_ONLY_SENSITIVE_FIELDS {
+                if self.env_overridden_fields.contains(*field) {
+                    obj.insert((*field).to_string(), serde_json::Value::Null);
+                }
+            }
+        }
+
+        let content = serde_json::to_string_pretty(&value).context("序列化配置失败")?;
         fs::write(path, content)
             .with_context(|| format!("写入配置文件失败: {}", path.display()))?;
         Ok(())
@@ -0,0 +1,178 @@
+//! 配置文件热重载
+//!
+//! 监听 `Config::config_path` 指向的文件，在外部编辑后自动重新解析并校验，
+//! 再原子替换内存中生效的配置。与 `Config::apply_runtime_update`（由 Admin API
+//! 触发的程序化更新）互补，覆盖"运维直接手改 config.json"的场景。
+
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use super::config::Config;
+
+/// 进程内共享的"当前生效配置"
+///
+/// 除配置本身外，还记录文件热重载路径（[`reload_into`]）检测到
+/// [`Config::restart_required_fields`] 发生变化但被搁置未生效的字段，
+/// 与 `Config::apply_runtime_update` 返回的 `pending_restart` 语义一致，
+/// 供 Admin 状态接口等提醒运维这些字段需要重启进程才能生效。
+pub struct SharedConfigState {
+    config: RwLock<Config>,
+    pending_restart: RwLock<std::collections::HashSet<String>>,
+}
+
+pub type SharedConfig = Arc<SharedConfigState>;
+
+impl SharedConfigState {
+    /// 以给定配置构造一个新的共享配置句柄
+    pub fn new(config: Config) -> SharedConfig {
+        Arc::new(Self {
+            config: RwLock::new(config),
+            pending_restart: RwLock::new(std::collections::HashSet::new()),
+        })
+    }
+
+    /// 读取当前生效的配置
+    pub fn read(&self) -> std::sync::RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// 获取当前生效配置的写锁（用于 Admin API 热更新）
+    pub fn write(&self) -> std::sync::RwLockWriteGuard<'_, Config> {
+        self.config.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// 自上次重启以来，被文件热重载搁置、仍等待进程重启生效的字段
+    pub fn pending_restart(&self) -> Vec<String> {
+        let mut fields: Vec<String> = self
+            .pending_restart
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .cloned()
+            .collect();
+        fields.sort();
+        fields
+    }
+}
+
+/// 配置热重载监听器
+///
+/// 内部持有一个后台任务，对文件系统事件做去抖（debounce），避免编辑器保存时
+/// 产生的多次写入触发重复解析。
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    /// 启动对 `config_path` 的监听，更新会原子写入 `shared`
+    ///
+    /// 仅当文件存在且能被成功解析为合法 `Config` 时才会替换 `shared`；
+    /// 解析失败的编辑会被忽略并记录告警日志，保留上一个已知良好的配置。
+    pub fn watch(config_path: PathBuf, shared: SharedConfig) -> anyhow::Result<Self> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
+
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            // 文件系统回调运行在 notify 的独立线程上，仅做轻量转发
+            let _ = tx.send(res);
+        })?;
+        watcher.watch(&config_path, RecursiveMode::NonRecursive)?;
+
+        let watch_path = config_path.clone();
+        tokio::spawn(async move {
+            // 去抖：同一批写入事件合并为一次重载，避免编辑器保存时的多次 write 触发多次解析
+            let debounce = Duration::from_millis(200);
+            loop {
+                let Some(first) = rx.recv().await else {
+                    break;
+                };
+                if !is_modify_event(&first) {
+                    continue;
+                }
+
+                // 吸收去抖窗口内的后续事件
+                loop {
+                    match tokio::time::timeout(debounce, rx.recv()).await {
+                        Ok(Some(_)) => continue,
+                        _ => break,
+                    }
+                }
+
+                reload_into(&watch_path, &shared);
+            }
+        });
+
+        Ok(Self { _watcher: watcher })
+    }
+}
+
+fn is_modify_event(res: &notify::Result<Event>) -> bool {
+    matches!(
+        res,
+        Ok(Event {
+            kind: EventKind::Modify(_) | EventKind::Create(_),
+            ..
+        })
+    )
+}
+
+/// [`Config::restart_required_fields`] 中某个字段的值是否发生了变化
+///
+/// 逐字段手写比较：这些字段影响已在启动阶段固化的监听 socket/上游客户端，
+/// 其余字段一律视为未变化（会跟随本次热重载立即生效）。
+fn restart_field_changed(field: &str, old: &Config, new: &Config) -> bool {
+    match field {
+        "host" => old.host != new.host,
+        "port" => old.port != new.port,
+        "tlsBackend" => old.tls_backend != new.tls_backend,
+        "upstreamConnection" => old.upstream_connection != new.upstream_connection,
+        _ => false,
+    }
+}
+
+fn reload_into(path: &PathBuf, shared: &SharedConfig) {
+    match Config::load(path) {
+        Ok(mut new_config) => {
+            let mut guard = shared.write();
+            let mut pending = shared
+                .pending_restart
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            // 与 `Config::apply_runtime_update` 的语义保持一致：`host`/`port`/
+            // `tlsBackend`/`upstreamConnection` 已在启动阶段固化（绑定的
+            // `TcpListener`、上游连接池等不会随之改变），文件热重载时只记录为
+            // 待重启，不覆盖内存中当前生效的值
+            for &field in Config::restart_required_fields() {
+                if restart_field_changed(field, &guard, &new_config) {
+                    match field {
+                        "host" => new_config.host = guard.host.clone(),
+                        "port" => new_config.port = guard.port,
+                        "tlsBackend" => new_config.tls_backend = guard.tls_backend,
+                        "upstreamConnection" => {
+                            new_config.upstream_connection = guard.upstream_connection
+                        }
+                        _ => {}
+                    }
+                    pending.insert(field.to_string());
+                    tracing::warn!(
+                        path = %path.display(),
+                        field,
+                        "配置文件修改了需要重启才能生效的字段，已搁置，保留当前运行值"
+                    );
+                } else {
+                    pending.remove(field);
+                }
+            }
+
+            *guard = new_config;
+            tracing::info!(path = %path.display(), "配置文件已变更，热重载完成");
+        }
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "配置文件热重载失败，已保留旧配置");
+        }
+    }
+}